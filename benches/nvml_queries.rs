@@ -0,0 +1,58 @@
+//! Compares polling all GPUs sequentially and with `--parallel-nvml-queries`, which splits them into one chunk per
+//! scoped thread.
+//!
+//! Run with `cargo bench`. It is skipped if NVML cannot be loaded, e.g. on a machine without NVIDIA GPUs.
+
+use std::thread;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use nvml_wrapper::{Device, Nvml};
+
+fn poll(device: &Device) -> u32 {
+    device.running_compute_processes_count().unwrap_or_default()
+}
+
+/// Poll the devices like `gpu::get_idle_gpu`, with `threads` threads.
+fn poll_all(devices: &[Device], threads: usize) -> Vec<u32> {
+    if threads <= 1 {
+        return devices.iter().map(poll).collect();
+    }
+    let chunk_size = devices.len().div_ceil(threads);
+    thread::scope(|scope| {
+        devices
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || chunk.iter().map(poll).collect::<Vec<_>>()))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("NVML query thread panicked"))
+            .collect()
+    })
+}
+
+fn parallel_queries(c: &mut Criterion) {
+    let nvml = match Nvml::init() {
+        Ok(nvml) => nvml,
+        Err(e) => {
+            eprintln!("Skipping the NVML benchmarks, NVML cannot be loaded: {}", e);
+            return;
+        }
+    };
+    let devices: Vec<_> = (0..nvml.device_count().unwrap())
+        .map(|i| nvml.device_by_index(i).unwrap())
+        .collect();
+    let mut group = c.benchmark_group("poll_all_gpus");
+    for threads in [1, 2, 4, 8, 16] {
+        if threads > 1 && threads > devices.len() {
+            break;
+        }
+        group.bench_with_input(
+            BenchmarkId::new("threads", threads),
+            &threads,
+            |b, &threads| b.iter(|| poll_all(&devices, threads)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parallel_queries);
+criterion_main!(benches);
@@ -0,0 +1,5 @@
+fn main() {
+    if std::env::var_os("CARGO_FEATURE_CUDA").is_none() {
+        println!("cargo:warning=Building without the `cuda` feature: GPUs will be waited for but never occupied, as if `--no-occupy` is always given.");
+    }
+}
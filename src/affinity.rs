@@ -0,0 +1,95 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::Context;
+use log::info;
+use serde::Deserialize;
+
+/// The entry applying to the users without their own entry.
+const WILDCARD: &str = "*";
+
+/// The GPUs a user may use.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Affinity {
+    Devices(Vec<u32>),
+    /// `"any"`, i.e. no restriction
+    Any(String),
+}
+
+/// Load the GPUs the current user (`$USER`) may use from an affinity file, or `None` if the user may use any GPU.
+///
+/// The file is a TOML table mapping user names to lists of GPU indices, where `*` applies to the users without their
+/// own entry, e.g.
+///
+/// ```toml
+/// alice = [0, 1]
+/// bob = [2, 3]
+/// "*" = "any"
+/// ```
+pub(crate) fn load(path: &Path) -> anyhow::Result<Option<Vec<u32>>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the GPU affinity file {}", path.display()))?;
+    let user = std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_default();
+    resolve(&content, &user)
+        .with_context(|| format!("Invalid GPU affinity file {}", path.display()))
+}
+
+/// The GPUs `user` may use according to the content of an affinity file, see [`load`].
+fn resolve(content: &str, user: &str) -> anyhow::Result<Option<Vec<u32>>> {
+    let mut affinities: HashMap<String, Affinity> = toml::from_str(content)?;
+    let Some(affinity) = affinities
+        .remove(user)
+        .or_else(|| affinities.remove(WILDCARD))
+    else {
+        anyhow::bail!("No GPUs are assigned to user {:?}", user);
+    };
+    match affinity {
+        Affinity::Devices(devices) => {
+            info!("GPUs assigned to user {:?}: {:?}", user, devices);
+            Ok(Some(devices))
+        }
+        Affinity::Any(any) if any == "any" => Ok(None),
+        Affinity::Any(other) => anyhow::bail!(
+            "Invalid GPU affinity {:?} for user {:?}, expected a list of indices or \"any\"",
+            other,
+            user
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = r#"
+alice = [0, 1]
+bob = [2, 3]
+"*" = "any"
+"#;
+
+    #[test]
+    fn own_entry() {
+        assert_eq!(resolve(FILE, "alice").unwrap(), Some(vec![0, 1]));
+        assert_eq!(resolve(FILE, "bob").unwrap(), Some(vec![2, 3]));
+    }
+
+    #[test]
+    fn wildcard() {
+        assert_eq!(resolve(FILE, "carol").unwrap(), None);
+        assert_eq!(resolve("\"*\" = [7]\n", "carol").unwrap(), Some(vec![7]));
+    }
+
+    #[test]
+    fn no_entry() {
+        assert!(resolve("alice = [0]\n", "carol").is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(resolve("alice = \"all\"\n", "alice").is_err());
+        assert!(resolve("alice = [-1]\n", "alice").is_err());
+        assert!(resolve("{\"alice\": [0]}", "alice").is_err());
+    }
+}
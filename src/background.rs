@@ -0,0 +1,95 @@
+use std::{
+    env,
+    io::{self, BufRead, BufReader, Write},
+    process::{Command, Stdio},
+    sync::atomic::Ordering,
+    time::Duration,
+};
+
+use log::info;
+
+use crate::{process, STOPPED};
+
+/// Set in the environment of the background holder, so that it holds the GPUs itself instead of spawning another one.
+const BACKGROUND_ENV: &str = "GPU_WAITER_BACKGROUND";
+
+/// Whether the current process is the background holder spawned by [`spawn`].
+pub(crate) fn is_background() -> bool {
+    env::var_os(BACKGROUND_ENV).is_some()
+}
+
+/// Run the same gpu-waiter invocation as a detached background process, wait until it holds the GPUs,
+/// and print the shell lines exporting them, e.g. for `eval $(gpu-waiter -n 2 hold --export)`.
+///
+/// The reservation can be released later with `kill $GPU_WAITER_PID`.
+pub(crate) fn spawn() -> anyhow::Result<()> {
+    let mut cmd = Command::new(env::current_exe()?);
+    cmd.args(env::args_os().skip(1))
+        .env(BACKGROUND_ENV, "1")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped());
+    // keep Ctrl+C in the shell from reaching the holder
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+    let mut child = cmd.spawn()?;
+    let pid = child.id();
+
+    let stdout = child.stdout.take().expect("stdout of the holder is piped");
+    let (s, r) = crossbeam_channel::bounded(1);
+    std::thread::spawn(move || {
+        let mut line = String::new();
+        let _ = s.send(BufReader::new(stdout).read_line(&mut line).map(|_| line));
+    });
+    let line = loop {
+        if STOPPED.load(Ordering::Relaxed) {
+            process::terminate(pid)?;
+            anyhow::bail!("Interrupted before the GPUs are held");
+        }
+        if let Ok(line) = r.recv_timeout(Duration::from_millis(200)) {
+            break line?;
+        }
+    };
+    let gpu_list = line.trim();
+    if gpu_list.is_empty() {
+        let status = child.wait()?;
+        anyhow::bail!(
+            "The background holder exited before holding the GPUs: {}",
+            status
+        );
+    }
+
+    info!(
+        "GPUs {} are held by process {} in the background",
+        gpu_list, pid
+    );
+    print!("{}", exports(gpu_list, pid));
+    Ok(())
+}
+
+/// The shell lines exporting the GPUs held by the background holder `pid`.
+fn exports(gpu_list: &str, pid: u32) -> String {
+    format!(
+        "export CUDA_VISIBLE_DEVICES={}\nexport GPU_WAITER_PID={}\n",
+        gpu_list, pid
+    )
+}
+
+/// Tell the process waiting in [`spawn`] which GPUs are held.
+pub(crate) fn report_held(gpu_list: &str) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    writeln!(stdout, "{}", gpu_list)?;
+    stdout.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn export_lines() {
+        assert_eq!(
+            exports("1,3", 42),
+            "export CUDA_VISIBLE_DEVICES=1,3\nexport GPU_WAITER_PID=42\n"
+        );
+    }
+}
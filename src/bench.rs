@@ -0,0 +1,156 @@
+use std::time::{Duration, Instant};
+
+use crate::{
+    gpu::{get_idle_gpu, IdleCriteria},
+    lock::FileRWLock,
+};
+
+/// Latencies of a single device's occupation cycle.
+pub struct DeviceBench {
+    pub index: u32,
+    pub bytes: usize,
+    pub init: Duration,
+    pub alloc: Duration,
+    pub free: Duration,
+}
+
+pub struct BenchReport {
+    /// Time taken by each round of [`get_idle_gpu`].
+    pub nvml_queries: Vec<Duration>,
+    pub devices: Vec<DeviceBench>,
+}
+
+impl BenchReport {
+    pub fn nvml_min(&self) -> Duration {
+        self.nvml_queries.iter().copied().min().unwrap_or_default()
+    }
+
+    pub fn nvml_max(&self) -> Duration {
+        self.nvml_queries.iter().copied().max().unwrap_or_default()
+    }
+
+    pub fn nvml_mean(&self) -> Duration {
+        if self.nvml_queries.is_empty() {
+            return Duration::ZERO;
+        }
+        self.nvml_queries.iter().sum::<Duration>() / self.nvml_queries.len() as u32
+    }
+
+    pub fn print(&self) {
+        println!(
+            "NVML idle query ({} rounds): min {:?}, mean {:?}, max {:?}",
+            self.nvml_queries.len(),
+            self.nvml_min(),
+            self.nvml_mean(),
+            self.nvml_max()
+        );
+        for dev in &self.devices {
+            println!(
+                "GPU {}: context init {:?}, alloc_zeros of {} MiB {:?}, free {:?}",
+                dev.index,
+                dev.init,
+                dev.bytes >> 20,
+                dev.alloc,
+                dev.free
+            );
+        }
+    }
+}
+
+/// Time the NVML polling and a sample occupation cycle on every idle GPU.
+///
+/// Busy GPUs are skipped, so that the benchmark never allocates memory next to someone else's job.
+/// The write lock is held during the whole benchmark to keep other instances from grabbing the same GPUs.
+pub(crate) fn run(
+    file_lock: &FileRWLock,
+    criteria: &IdleCriteria,
+    rounds: u32,
+    sample_bytes: usize,
+) -> anyhow::Result<BenchReport> {
+    let _guard = file_lock.write()?;
+    let mut nvml_queries = Vec::with_capacity(rounds as usize);
+    let mut idle_gpus = vec![];
+    for _ in 0..rounds {
+        let start = Instant::now();
+        idle_gpus = get_idle_gpu(criteria, None, None)?.idle;
+        nvml_queries.push(start.elapsed());
+    }
+
+    #[cfg(feature = "cuda")]
+    let devices = idle_gpus
+        .into_iter()
+        .map(|i| bench_device(i, sample_bytes))
+        .collect::<anyhow::Result<_>>()?;
+    #[cfg(not(feature = "cuda"))]
+    let devices = {
+        let _ = (idle_gpus, sample_bytes);
+        log::warn!("Built without the `cuda` feature, so the occupation is not benchmarked");
+        vec![]
+    };
+
+    Ok(BenchReport {
+        nvml_queries,
+        devices,
+    })
+}
+
+#[cfg(feature = "cuda")]
+fn bench_device(i: u32, sample_bytes: usize) -> anyhow::Result<DeviceBench> {
+    let free_mem = crate::NVML.wait().device_by_index(i)?.memory_info()?.free;
+    let bytes = sample_bytes.min(free_mem as usize);
+    log::info!(
+        "Benchmarking occupation of {} MiB on GPU {}",
+        bytes >> 20,
+        i
+    );
+
+    let start = Instant::now();
+    let cuda_dev = cudarc::driver::CudaDevice::new(i as usize)?;
+    let init = start.elapsed();
+
+    let start = Instant::now();
+    let target = crate::occupy::OccupyTarget::Device(cuda_dev.clone());
+    let out = crate::occupy::occupy_gpu(&target, bytes, false)?;
+    cuda_dev.synchronize()?;
+    let alloc = start.elapsed();
+
+    let start = Instant::now();
+    drop(out);
+    cuda_dev.synchronize()?;
+    let free = start.elapsed();
+
+    Ok(DeviceBench {
+        index: i,
+        bytes,
+        init,
+        alloc,
+        free,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nvml_statistics() {
+        let report = BenchReport {
+            nvml_queries: [3, 1, 2].map(Duration::from_millis).to_vec(),
+            devices: vec![],
+        };
+        assert_eq!(report.nvml_min(), Duration::from_millis(1));
+        assert_eq!(report.nvml_max(), Duration::from_millis(3));
+        assert_eq!(report.nvml_mean(), Duration::from_millis(2));
+    }
+
+    #[test]
+    fn nvml_statistics_without_rounds() {
+        let report = BenchReport {
+            nvml_queries: vec![],
+            devices: vec![],
+        };
+        assert_eq!(report.nvml_min(), Duration::ZERO);
+        assert_eq!(report.nvml_max(), Duration::ZERO);
+        assert_eq!(report.nvml_mean(), Duration::ZERO);
+    }
+}
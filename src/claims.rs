@@ -0,0 +1,562 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, Write},
+    path::PathBuf,
+    sync::atomic::Ordering,
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    lock::{
+        guess_global_runtime_dir, open_or_create_file, share_with_group, shared_file_name, LockType,
+    },
+    process, STOPPED,
+};
+
+/// The host name of this machine, to tell the entries of the instances on other machines apart
+/// when the claim file is shared, e.g. on NFS.
+static HOST: Lazy<String> = Lazy::new(process::hostname);
+
+/// How often a waiter or a holder refreshes its heartbeat.
+pub(crate) const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How old a heartbeat can be before the waiter or holder is considered gone, e.g. hung or crashed on another host.
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn is_current(host: &str, pid: u32) -> bool {
+    host == *HOST && pid == std::process::id()
+}
+
+fn is_stale(heartbeat: i64, now: i64) -> bool {
+    now - heartbeat > HEARTBEAT_TIMEOUT.as_secs() as i64
+}
+
+/// A gpu-waiter instance that is still polling for GPUs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Waiter {
+    #[serde(default)]
+    pub host: String,
+    pub pid: u32,
+    /// How many GPUs it asked for.
+    pub num: u32,
+    /// When it started waiting, as a Unix timestamp.
+    pub since: i64,
+    /// When the waiter last showed it is alive, as a Unix timestamp. Missing in the entries of older versions, which
+    /// are thus pruned as stale.
+    #[serde(default)]
+    pub heartbeat: i64,
+}
+
+impl Waiter {
+    fn is_current(&self) -> bool {
+        is_current(&self.host, self.pid)
+    }
+
+    fn is_stale(&self, now: i64) -> bool {
+        is_stale(self.heartbeat, now)
+    }
+}
+
+/// A gpu-waiter instance that has acquired GPUs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Holder {
+    pub host: String,
+    pub pid: u32,
+    pub devices: Vec<u32>,
+    /// When the holder last showed it is alive, as a Unix timestamp.
+    pub heartbeat: i64,
+    /// The name of the reservation, for `gpu-waiter reserve --name`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+impl Holder {
+    fn is_current(&self) -> bool {
+        is_current(&self.host, self.pid)
+    }
+
+    fn is_stale(&self, now: i64) -> bool {
+        is_stale(self.heartbeat, now)
+    }
+}
+
+/// The state shared between all gpu-waiter instances using the same claim file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Claims {
+    /// In the order they started waiting.
+    #[serde(default)]
+    pub waiters: Vec<Waiter>,
+    #[serde(default)]
+    pub holders: Vec<Holder>,
+}
+
+impl Claims {
+    /// Drop the entries of the processes that are gone, e.g. killed without cleaning up,
+    /// and the entries whose heartbeat is stale, e.g. hung or crashed on another host.
+    ///
+    /// Only the processes of this machine can be checked directly, the others are pruned by their heartbeats.
+    pub fn prune_dead(&mut self) {
+        let now = chrono::Utc::now().timestamp();
+        self.waiters.retain(|w| {
+            if w.host == *HOST && !process::is_alive(w.pid) {
+                return false;
+            }
+            if w.is_stale(now) && !w.is_current() {
+                info!(
+                    "The heartbeat of waiting process {} on {} is stale, no longer counting it",
+                    w.pid, w.host
+                );
+                return false;
+            }
+            true
+        });
+        self.holders.retain(|h| {
+            if h.host == *HOST && !process::is_alive(h.pid) {
+                return false;
+            }
+            if h.is_stale(now) && !h.is_current() {
+                warn!(
+                    "The heartbeat of process {} on {} is stale, treating its GPUs {:?} as released",
+                    h.pid, h.host, h.devices
+                );
+                return false;
+            }
+            true
+        });
+    }
+
+    /// Register the current process as holding the given GPUs, or refresh its heartbeat if it already is.
+    pub fn set_holder(&mut self, devices: &[u32]) {
+        let heartbeat = chrono::Utc::now().timestamp();
+        match self.holders.iter_mut().find(|h| h.is_current()) {
+            Some(holder) => {
+                holder.devices = devices.to_vec();
+                holder.heartbeat = heartbeat;
+            }
+            None => self.holders.push(Holder {
+                host: HOST.clone(),
+                pid: std::process::id(),
+                devices: devices.to_vec(),
+                heartbeat,
+                name: None,
+            }),
+        }
+    }
+
+    /// Name the holder entry of the current process, see [`set_holder`](Self::set_holder).
+    pub fn name_holder(&mut self, name: &str) {
+        if let Some(holder) = self.holders.iter_mut().find(|h| h.is_current()) {
+            holder.name = Some(name.to_string());
+        }
+    }
+
+    /// The PID of the process on this machine holding the reservation with the given name.
+    pub fn reserved_by(&self, name: &str) -> Option<u32> {
+        self.holders
+            .iter()
+            .find(|h| h.host == *HOST && h.name.as_deref() == Some(name))
+            .map(|h| h.pid)
+    }
+
+    pub fn remove_holder(&mut self) {
+        self.holders.retain(|h| !h.is_current());
+    }
+
+    /// The GPUs held by other instances on this machine.
+    pub fn claimed_by_others(&self) -> Vec<u32> {
+        self.holders
+            .iter()
+            .filter(|h| h.host == *HOST && !h.is_current())
+            .flat_map(|h| h.devices.iter().copied())
+            .collect()
+    }
+
+    /// Register the current process as waiting, or refresh its heartbeat if it already is.
+    pub fn add_waiter(&mut self, num: u32) {
+        let now = chrono::Utc::now().timestamp();
+        match self.waiters.iter_mut().find(|w| w.is_current()) {
+            Some(waiter) => waiter.heartbeat = now,
+            None => self.waiters.push(Waiter {
+                host: HOST.clone(),
+                pid: std::process::id(),
+                num,
+                since: now,
+                heartbeat: now,
+            }),
+        }
+    }
+
+    pub fn remove_waiter(&mut self) {
+        self.waiters.retain(|w| !w.is_current());
+    }
+
+    /// Whether the given process on this machine is waiting for or holding GPUs.
+    pub fn has_entry(&self, pid: u32) -> bool {
+        self.waiters.iter().any(|w| w.host == *HOST && w.pid == pid)
+            || self.holders.iter().any(|h| h.host == *HOST && h.pid == pid)
+    }
+
+    /// How many other waiters started waiting before the current process, i.e. its position in line.
+    pub fn waiters_ahead(&self) -> usize {
+        self.waiters
+            .iter()
+            .position(Waiter::is_current)
+            .unwrap_or(self.waiters.len())
+    }
+}
+
+/// How often [`wait_for_release`] checks the claim file.
+const RELEASE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Block until the given gpu-waiter process on this machine neither waits for nor holds GPUs,
+/// e.g. because it exited, or `timeout` passes.
+///
+/// The claim file is only read, so that polling it does not keep other instances from updating it.
+pub(crate) fn wait_for_release(
+    claim_file: &ClaimFile,
+    pid: u32,
+    timeout: Option<Duration>,
+) -> anyhow::Result<()> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut logged = false;
+    loop {
+        // the entries of dead processes are only pruned on updates, so check it directly
+        if !(claim_file.read(|claims| claims.has_entry(pid))? && process::is_alive(pid)) {
+            info!("Process {} holds no claim", pid);
+            return Ok(());
+        }
+        if !logged {
+            info!("Waiting for process {} to release its claim", pid);
+            logged = true;
+        }
+        if STOPPED.load(Ordering::Relaxed) {
+            anyhow::bail!("Interrupted before process {} releases its claim", pid);
+        }
+        if let Some(deadline) = deadline {
+            if Instant::now() >= deadline {
+                anyhow::bail!(
+                    "Process {} still holds its claim after {:?}",
+                    pid,
+                    timeout.unwrap_or_default()
+                );
+            }
+        }
+        thread::sleep(RELEASE_POLL_INTERVAL);
+    }
+}
+
+/// The default claim file, in the global runtime directory.
+pub(crate) fn default_path(group: Option<&str>) -> PathBuf {
+    guess_global_runtime_dir().join(shared_file_name(group, "claims.json"))
+}
+
+/// A JSON file holding the [`Claims`].
+///
+/// Each access locks the file itself, so it can be placed apart from the global lock, e.g. on a shared file system.
+pub struct ClaimFile {
+    path: PathBuf,
+    lock_type: LockType,
+}
+
+impl ClaimFile {
+    /// With `unix_group`, the file is only accessible to the members of that group, see [`share_with_group`].
+    pub fn new(path: PathBuf, lock_type: LockType, unix_group: Option<&str>) -> io::Result<Self> {
+        // make sure it exists and is writable by everyone, see `open_or_create_file`
+        let file = open_or_create_file(&path)?;
+        if let Some(group) = unix_group {
+            share_with_group(&file, &path, group)?;
+        }
+        Ok(Self { path, lock_type })
+    }
+
+    fn load(&self, f: &mut File) -> io::Result<Claims> {
+        let mut content = String::new();
+        f.read_to_string(&mut content)?;
+        if content.trim().is_empty() {
+            return Ok(Claims::default());
+        }
+        Ok(serde_json::from_str(&content).unwrap_or_else(|e| {
+            warn!(
+                "The claim file {:?} is corrupted, resetting it: {}",
+                self.path, e
+            );
+            Claims::default()
+        }))
+    }
+
+    fn store(&self, f: &mut File, claims: &Claims) -> io::Result<()> {
+        f.rewind()?;
+        f.set_len(0)?;
+        f.write_all(&serde_json::to_vec(claims)?)?;
+        // flush to the storage before the lock is released, so that the next holder of the lock reads the new claims
+        // even on network file systems that cache writes on the client
+        f.sync_data()
+    }
+
+    /// Load the claims and apply `f`, while holding a shared lock of the file. The dead entries are not pruned.
+    pub fn read<T>(&self, f: impl FnOnce(&Claims) -> T) -> io::Result<T> {
+        let mut file = File::open(&self.path)?;
+        self.lock_type.lock_shared(&file)?;
+        let claims = self.load(&mut file)?;
+        Ok(f(&claims))
+    }
+
+    /// Load the claims, prune the dead entries, apply `f` and store them back, while holding the lock of the file.
+    pub fn update<T>(&self, f: impl FnOnce(&mut Claims) -> T) -> io::Result<T> {
+        // do not create the file here: it may be owned by another user, see `open_or_create_file`
+        let mut file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        self.lock_type.lock_exclusive(&file)?;
+        let mut claims = self.load(&mut file)?;
+        claims.prune_dead();
+        let result = f(&mut claims);
+        self.store(&mut file, &claims)?;
+        // the lock is released when the file is closed
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn waiter(host: &str, pid: u32) -> Waiter {
+        Waiter {
+            host: host.to_string(),
+            pid,
+            num: 1,
+            since: 0,
+            heartbeat: chrono::Utc::now().timestamp(),
+        }
+    }
+
+    /// The PID of a process that has already exited.
+    fn dead_pid() -> u32 {
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        child.id()
+    }
+
+    /// A claim file in the temporary directory, unique to the test.
+    fn temp_claim_file(name: &str, lock_type: LockType) -> (PathBuf, ClaimFile) {
+        let path = std::env::temp_dir().join(format!(
+            "gpu-waiter-test-{}-{}.json",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_file(&path);
+        let claim_file = ClaimFile::new(path.clone(), lock_type, None).unwrap();
+        (path, claim_file)
+    }
+
+    #[test]
+    fn claim_file_at_a_custom_path() {
+        let (path, claim_file) = temp_claim_file("custom", LockType::Flock);
+        // another host sharing the file started waiting first
+        let claims = Claims {
+            waiters: vec![waiter("other-host", 1)],
+            ..Default::default()
+        };
+        std::fs::write(&path, serde_json::to_vec(&claims).unwrap()).unwrap();
+        claim_file.update(|claims| claims.add_waiter(2)).unwrap();
+        assert_eq!(claim_file.read(|claims| claims.waiters.len()).unwrap(), 2);
+        assert_eq!(
+            claim_file.update(|claims| claims.waiters_ahead()).unwrap(),
+            1
+        );
+        claim_file.update(Claims::remove_waiter).unwrap();
+        assert_eq!(claim_file.read(|claims| claims.waiters.len()).unwrap(), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    // lockf is only supported on Unix
+    #[cfg(unix)]
+    #[test]
+    fn updates_are_seen_by_other_instances() {
+        for lock_type in [LockType::Flock, LockType::Lockf] {
+            let (path, writer) = temp_claim_file(&format!("shared-{:?}", lock_type), lock_type);
+            let reader = ClaimFile::new(path.clone(), lock_type, None).unwrap();
+            writer
+                .update(|claims| claims.set_holder(&[0, 1, 2, 3]))
+                .unwrap();
+            assert_eq!(
+                reader
+                    .read(|claims| claims.holders[0].devices.clone())
+                    .unwrap(),
+                [0, 1, 2, 3]
+            );
+            // the shorter claims replace the longer ones entirely
+            writer.update(|claims| claims.set_holder(&[1])).unwrap();
+            assert_eq!(
+                reader
+                    .read(|claims| claims.holders[0].devices.clone())
+                    .unwrap(),
+                [1]
+            );
+            writer.update(Claims::remove_holder).unwrap();
+            let content = std::fs::read_to_string(&path).unwrap();
+            assert!(serde_json::from_str::<Claims>(&content)
+                .unwrap()
+                .holders
+                .is_empty());
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn corrupted_claim_file_is_reset() {
+        let (path, claim_file) = temp_claim_file("corrupted", LockType::Flock);
+        std::fs::write(&path, "{not json").unwrap();
+        assert!(claim_file.read(|claims| claims.waiters.is_empty()).unwrap());
+        claim_file.update(|claims| claims.add_waiter(1)).unwrap();
+        assert_eq!(claim_file.read(|claims| claims.waiters.len()).unwrap(), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn waits_for_the_release_of_live_entries_only() {
+        let (path, claim_file) = temp_claim_file("release", LockType::Flock);
+        let dead = dead_pid();
+        let claims = Claims {
+            waiters: vec![waiter(&HOST, dead), waiter(&HOST, std::process::id())],
+            ..Default::default()
+        };
+        std::fs::write(&path, serde_json::to_vec(&claims).unwrap()).unwrap();
+        // reading does not prune the dead entry
+        assert!(claim_file.read(|claims| claims.has_entry(dead)).unwrap());
+        assert!(!claim_file.read(|claims| claims.has_entry(1)).unwrap());
+        wait_for_release(&claim_file, dead, Some(Duration::ZERO)).unwrap();
+        wait_for_release(&claim_file, 1, Some(Duration::ZERO)).unwrap();
+        assert!(wait_for_release(&claim_file, std::process::id(), Some(Duration::ZERO)).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn holder(host: &str, pid: u32, heartbeat: i64) -> Holder {
+        Holder {
+            host: host.to_string(),
+            pid,
+            devices: vec![0],
+            heartbeat,
+            name: None,
+        }
+    }
+
+    #[test]
+    fn prunes_stale_heartbeats_of_others() {
+        let now = chrono::Utc::now().timestamp();
+        let stale = now - HEARTBEAT_TIMEOUT.as_secs() as i64 - 1;
+        let mut claims = Claims {
+            holders: vec![
+                holder("other-host", 1, stale),
+                holder("other-host", 2, now),
+                holder(&HOST, std::process::id(), stale),
+            ],
+            ..Default::default()
+        };
+        claims.prune_dead();
+        let left: Vec<_> = claims.holders.iter().map(|h| h.pid).collect();
+        assert_eq!(left, [2, std::process::id()]);
+    }
+
+    #[test]
+    fn set_holder_refreshes_the_heartbeat() {
+        let mut claims = Claims {
+            holders: vec![holder(&HOST, std::process::id(), 0)],
+            ..Default::default()
+        };
+        claims.set_holder(&[1, 2]);
+        assert_eq!(claims.holders.len(), 1);
+        assert_eq!(claims.holders[0].devices, [1, 2]);
+        assert!(!claims.holders[0].is_stale(chrono::Utc::now().timestamp()));
+        claims.remove_holder();
+        assert!(claims.holders.is_empty());
+    }
+
+    #[test]
+    fn reservations_by_name() {
+        let mut claims = Claims {
+            holders: vec![holder("other-host", 1, 0)],
+            ..Default::default()
+        };
+        claims.holders[0].name = Some("batch".to_string());
+        // only the reservations on this machine count
+        assert_eq!(claims.reserved_by("batch"), None);
+        // the current process does not hold GPUs yet
+        claims.name_holder("batch");
+        assert_eq!(claims.reserved_by("batch"), None);
+        claims.set_holder(&[0]);
+        claims.name_holder("batch");
+        assert_eq!(claims.reserved_by("batch"), Some(std::process::id()));
+        assert_eq!(claims.reserved_by("other"), None);
+    }
+
+    #[test]
+    fn queue_position() {
+        let mut claims = Claims {
+            waiters: vec![waiter(&HOST, 1), waiter("other-host", 2)],
+            ..Default::default()
+        };
+        claims.add_waiter(2);
+        claims.add_waiter(2);
+        assert_eq!(claims.waiters.len(), 3);
+        assert_eq!(claims.waiters_ahead(), 2);
+        claims.remove_waiter();
+        assert_eq!(claims.waiters.len(), 2);
+        // not waiting at all, i.e. behind everyone
+        assert_eq!(claims.waiters_ahead(), 2);
+    }
+
+    #[test]
+    fn prunes_dead_waiters_of_this_host_only() {
+        let dead = dead_pid();
+        let mut claims = Claims {
+            waiters: vec![
+                waiter(&HOST, dead),
+                waiter(&HOST, std::process::id()),
+                waiter("other-host", dead),
+            ],
+            ..Default::default()
+        };
+        claims.prune_dead();
+        let left: Vec<_> = claims
+            .waiters
+            .iter()
+            .map(|w| (w.host.as_str(), w.pid))
+            .collect();
+        assert_eq!(
+            left,
+            [(HOST.as_str(), std::process::id()), ("other-host", dead)]
+        );
+    }
+
+    #[test]
+    fn prunes_stale_waiters_of_other_hosts() {
+        let now = chrono::Utc::now().timestamp();
+        let stale = now - HEARTBEAT_TIMEOUT.as_secs() as i64 - 1;
+        let mut claims = Claims {
+            waiters: vec![
+                Waiter {
+                    heartbeat: stale,
+                    ..waiter("other-host", 1)
+                },
+                waiter("other-host", 2),
+                Waiter {
+                    heartbeat: stale,
+                    ..waiter(&HOST, std::process::id())
+                },
+            ],
+            ..Default::default()
+        };
+        claims.prune_dead();
+        let left: Vec<_> = claims.waiters.iter().map(|w| w.pid).collect();
+        assert_eq!(left, [2, std::process::id()]);
+        // waiting again refreshes the heartbeat instead of adding another entry
+        claims.add_waiter(1);
+        assert_eq!(claims.waiters.len(), 2);
+        assert!(!claims.waiters[1].is_stale(now));
+    }
+}
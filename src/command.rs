@@ -1,30 +1,62 @@
 use std::{borrow::Cow, usize};
 
+use clap::ValueEnum;
 use itertools::Itertools;
 
+/// How literal braces are escaped in the command.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum EscapeStyle {
+    /// `{{` and `}}`
+    DoubleBrace,
+    /// `\{` and `\}`
+    Backslash,
+}
+
+/// Whether to strip the leading and trailing whitespace of the command, and collapse the whitespace inside into single spaces,
+/// e.g. for commands written across multiple lines.
+#[derive(Clone, Copy)]
+pub struct NormalizeWhitespace(pub bool);
+
 enum SegmentStatus {
     Plain(usize),
     Bracket(usize),
+    Backslash(usize),
+}
+
+/// Progress of spotting `{N}`, which looks like an indexed placeholder but is left as is.
+enum IndexedStatus {
+    None,
+    Open,
+    Digits(String),
 }
 
 enum Segment {
     Plain(usize, usize),
     Bracket(usize, usize),
+    /// A backslash followed by a brace
+    Escaped(usize, usize),
 }
 
 pub struct TemplateResult {
     pub command: String,
     pub template_count: usize,
     pub total_count: usize,
+    /// Non-fatal issues of the command, e.g. `{0}` that looks like a placeholder but is not.
+    pub warnings: Vec<String>,
 }
 
 pub(crate) fn process_command_template(
     command_str: impl Into<Cow<'_, str>>,
     template_str: impl Into<Cow<'_, str>>,
+    style: EscapeStyle,
+    normalize: NormalizeWhitespace,
 ) -> anyhow::Result<TemplateResult> {
     let template: Cow<'_, str> = template_str.into();
     let template = template.into_owned();
-    let command: Cow<'_, str> = command_str.into();
+    let mut command: Cow<'_, str> = command_str.into();
+    if normalize.0 {
+        command = command.split_whitespace().join(" ").into();
+    }
     let mut result = String::with_capacity(command.len());
     
     // scan each substring with only "{" and "}"
@@ -32,6 +64,26 @@ pub(crate) fn process_command_template(
     let mut status = None;
     for (i, c) in command.char_indices() {
         match (&status, c) {
+            (Some(SegmentStatus::Backslash(s)), '{') | (Some(SegmentStatus::Backslash(s)), '}') => {
+                segments.push(Segment::Escaped(*s, i + 1));
+                status = None;
+                continue;
+            }
+            (Some(SegmentStatus::Backslash(s)), _) => {
+                // not followed by a brace, so the backslash is just plain text
+                status = Some(SegmentStatus::Plain(*s));
+            }
+            _ => {}
+        }
+        match (&status, c) {
+            (_, '\\') if style == EscapeStyle::Backslash => {
+                match status {
+                    Some(SegmentStatus::Plain(s)) => segments.push(Segment::Plain(s, i)),
+                    Some(SegmentStatus::Bracket(s)) => segments.push(Segment::Bracket(s, i)),
+                    _ => {}
+                }
+                status = Some(SegmentStatus::Backslash(i));
+            }
             (None, '{') | (None, '}') => {
                 status = Some(SegmentStatus::Bracket(i));
             }
@@ -50,7 +102,7 @@ pub(crate) fn process_command_template(
         }
     }
     match status {
-        Some(SegmentStatus::Plain(s)) => {
+        Some(SegmentStatus::Plain(s)) | Some(SegmentStatus::Backslash(s)) => {
             segments.push(Segment::Plain(s, usize::MAX));
         }
         Some(SegmentStatus::Bracket(s)) => {
@@ -62,14 +114,37 @@ pub(crate) fn process_command_template(
     // process each segment
     let mut template_count = 0;
     let mut total_count = 0;
+    let mut indexed = vec![];
+    let mut indexed_status = IndexedStatus::None;
     let mut command_chrs = command.chars();
     for segment in segments {
         match segment {
             Segment::Plain(start, end) => {
-                command_chrs.by_ref().take(end - start).for_each(|c| result.push(c));
+                let content = command_chrs.by_ref().take(end - start).collect::<String>();
+                indexed_status = match indexed_status {
+                    IndexedStatus::Open if content.chars().all(|c| c.is_ascii_digit()) => IndexedStatus::Digits(content.clone()),
+                    _ => IndexedStatus::None,
+                };
+                result.push_str(&content);
+            }
+            Segment::Escaped(start, end) => {
+                let content = command_chrs.by_ref().take(end - start).collect::<String>();
+                result.extend(content.chars().last());
+                template_count += 1;
+                indexed_status = IndexedStatus::None;
             }
             Segment::Bracket(start, end) => {
                 let content = command_chrs.by_ref().take(end - start).collect::<String>();
+                if let IndexedStatus::Digits(digits) = &indexed_status {
+                    if content.starts_with('}') {
+                        indexed.push(digits.clone());
+                    }
+                }
+                indexed_status = if content == "{" || content == "}{" {
+                    IndexedStatus::Open
+                } else {
+                    IndexedStatus::None
+                };
                 if content == "{" || content == "}" || content == "}{" {
                     result.push_str(&content);
                 } else {
@@ -81,11 +156,11 @@ pub(crate) fn process_command_template(
                                 template_count += 1;
                                 total_count += 1;
                             }
-                            "{{" => {
+                            "{{" if style == EscapeStyle::DoubleBrace => {
                                 result.push('{');
                                 template_count += 1;
                             }
-                            "}}" => {
+                            "}}" if style == EscapeStyle::DoubleBrace => {
                                 result.push('}');
                                 template_count += 1;
                             }
@@ -99,9 +174,132 @@ pub(crate) fn process_command_template(
         }
     }
 
+    let mut warnings = vec![];
+    for digits in &indexed {
+        warnings.push(format!("{{{}}} is not a placeholder and is left as is, use {{}} for the list of GPUs", digits));
+    }
+    if total_count > 0 && !indexed.is_empty() {
+        warnings.push(format!("The command mixes {{}} with {{N}}, but only {{}} is replaced: {}", command));
+    }
+    if total_count > 0 && !template.is_empty() && !template.contains(',') {
+        warnings.push(format!("Only a single GPU is selected, so {{}} is replaced with a single index: {}", template));
+    }
+
     Ok(TemplateResult {
         command: result,
         template_count,
         total_count,
+        warnings,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(command: &str, template: &str, normalize: bool) -> String {
+        process_command_template(
+            command,
+            template,
+            EscapeStyle::DoubleBrace,
+            NormalizeWhitespace(normalize),
+        )
+        .unwrap()
+        .command
+    }
+
+    #[test]
+    fn normalizes_whitespace() {
+        assert_eq!(
+            process("  python train.py \\\n    --gpus {}\n", "0,1", true),
+            "python train.py \\ --gpus 0,1"
+        );
+        assert_eq!(process("echo\t{}\t\tdone", "0", true), "echo 0 done");
+        // kept as is by default
+        assert_eq!(
+            process("  echo \t{}\n  done\n", "0", false),
+            "  echo \t0\n  done\n"
+        );
+        // before the placeholders are replaced, so the replacement is kept as is
+        assert_eq!(process("echo   {}", "a  b", true), "echo a  b");
+    }
+
+    fn warnings(command: &str, template: &str) -> Vec<String> {
+        process_command_template(
+            command,
+            template,
+            EscapeStyle::DoubleBrace,
+            NormalizeWhitespace(false),
+        )
+        .unwrap()
+        .warnings
+    }
+
+    #[test]
+    fn warns_about_indexed_placeholders() {
+        assert_eq!(process("echo {0}", "0,1", false), "echo {0}");
+        assert_eq!(
+            warnings("echo {0}", "0,1"),
+            ["{0} is not a placeholder and is left as is, use {} for the list of GPUs"]
+        );
+        assert_eq!(
+            warnings("echo {} then {1}", "0,1"),
+            [
+                "{1} is not a placeholder and is left as is, use {} for the list of GPUs",
+                "The command mixes {} with {N}, but only {} is replaced: echo {} then {1}",
+            ]
+        );
+        // not braced on both sides
+        assert!(warnings("echo 12 {}", "0,1").is_empty());
+        assert!(warnings("echo {12 {}", "0,1").is_empty());
+        assert!(warnings("echo {} 12}", "0,1").is_empty());
+    }
+
+    #[test]
+    fn warns_about_a_single_gpu() {
+        assert_eq!(
+            warnings("echo {}", "3"),
+            ["Only a single GPU is selected, so {} is replaced with a single index: 3"]
+        );
+        assert!(warnings("echo {}", "1,3").is_empty());
+        // nothing is replaced
+        assert!(warnings("echo", "3").is_empty());
+    }
+
+    fn process_backslash(command: &str, template: &str) -> anyhow::Result<String> {
+        process_command_template(
+            command,
+            template,
+            EscapeStyle::Backslash,
+            NormalizeWhitespace(false),
+        )
+        .map(|result| result.command)
+    }
+
+    #[test]
+    fn escapes_braces_with_backslashes() {
+        assert_eq!(
+            process_backslash(r"echo \{{}\}", "0,1").unwrap(),
+            "echo {0,1}"
+        );
+        assert_eq!(
+            process_backslash(r"echo \{ {} \}", "0,1").unwrap(),
+            "echo { 0,1 }"
+        );
+        assert_eq!(
+            process_backslash(r"echo \}{}\{", "0,1").unwrap(),
+            "echo }0,1{"
+        );
+        // not followed by a brace
+        assert_eq!(
+            process_backslash(r"echo a\b {}", "0,1").unwrap(),
+            r"echo a\b 0,1"
+        );
+        assert_eq!(
+            process_backslash(r"echo {} \", "0,1").unwrap(),
+            r"echo 0,1 \"
+        );
+        assert!(process_backslash("echo {{}}", "0,1").is_err());
+        assert!(process_backslash("echo {{ {}", "0,1").is_err());
+    }
+}
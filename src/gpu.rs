@@ -0,0 +1,888 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use either::Either;
+use itertools::Itertools;
+use log::{info, warn};
+use nvml_wrapper::{
+    bitmasks::device::ThrottleReasons, cuda_driver_version_major, cuda_driver_version_minor,
+    enum_wrappers::device::Clock, enums::device::UsedGpuMemory, error::NvmlError, Device, Nvml,
+};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use crate::NVML;
+
+/// Enforces a minimum interval between NVML calls.
+pub struct RateLimiter {
+    interval: Duration,
+    last: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: Mutex::new(None),
+        }
+    }
+
+    /// Block until at least the interval has passed since the previous call.
+    pub fn wait(&self) {
+        let mut last = self.last.lock();
+        if let Some(elapsed) = last.map(|last| last.elapsed()) {
+            if elapsed < self.interval {
+                thread::sleep(self.interval - elapsed);
+            }
+        }
+        *last = Some(Instant::now());
+    }
+}
+
+/// How the used memory of a GPU is measured for [`IdleCriteria::memory_threshold`].
+#[derive(Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum NvmlScope {
+    /// The sum of the memory used by the compute processes on the GPU
+    #[default]
+    Process,
+    /// The memory used on the GPU as a whole, including what is not attributed to any process
+    System,
+}
+
+impl NvmlScope {
+    fn used_memory(self) -> fn(&Device) -> Result<u64, NvmlError> {
+        match self {
+            NvmlScope::Process => process_memory_used,
+            NvmlScope::System => |d| d.memory_info().map(|m| m.used),
+        }
+    }
+}
+
+/// The memory used by the compute processes on a GPU, or the system-wide figure if the driver
+/// does not report it for some process, e.g. inside some containers.
+fn process_memory_used(device: &Device) -> Result<u64, NvmlError> {
+    let mut used = 0;
+    for process in device.running_compute_processes()? {
+        match process.used_gpu_memory {
+            UsedGpuMemory::Used(bytes) => used += bytes,
+            UsedGpuMemory::Unavailable => return device.memory_info().map(|m| m.used),
+        }
+    }
+    Ok(used)
+}
+
+/// Extra conditions an idle GPU must meet to be selected.
+#[derive(Default)]
+pub struct IdleCriteria {
+    /// Only consider these devices, if given.
+    pub devices: Option<Vec<u32>>,
+    /// Maximum number of compute processes on a GPU that is still considered idle.
+    pub process_count_threshold: u32,
+    /// If given, a GPU is considered idle when its used memory (in bytes) is at most this value,
+    /// instead of checking the number of compute processes.
+    pub memory_threshold: Option<u64>,
+    /// How the used memory is measured for `memory_threshold`.
+    pub nvml_scope: NvmlScope,
+    /// Collect [`Discovery::free`] by the number of compute processes even if `memory_threshold` is given,
+    /// at the cost of an extra query per GPU.
+    pub track_free: bool,
+    /// Minimum current SM clock, in MHz.
+    pub min_sm_clock: Option<u32>,
+    /// Only use GPUs in persistence mode.
+    pub require_persistence_mode: bool,
+    /// Skip GPUs whose clocks are being throttled, e.g. for power or temperature.
+    pub avoid_throttled: bool,
+    /// How long to wait for the query of whether a GPU is busy, before treating it as busy.
+    pub check_timeout: Option<Duration>,
+    /// Treat a GPU as idle if querying whether it is busy fails, instead of returning the error.
+    pub assume_idle_after_error: bool,
+    /// Return an error on a timed-out query, instead of treating the GPU as busy.
+    pub fail_on_timeout: bool,
+    /// Spaces out the NVML calls, if given.
+    pub rate_limiter: Option<RateLimiter>,
+    /// How many threads to poll the GPUs with. At most 1 means sequentially.
+    pub query_threads: usize,
+}
+
+impl IdleCriteria {
+    /// Wait for the rate limiter, if any, before an NVML call.
+    fn throttle(&self) {
+        if let Some(limiter) = &self.rate_limiter {
+            limiter.wait();
+        }
+    }
+
+    fn is_candidate(&self, index: u32) -> bool {
+        self.devices
+            .as_ref()
+            .is_none_or(|devices| devices.contains(&index))
+    }
+
+    /// Check an idle device against the criteria, returning the reason to skip it if any.
+    fn check(&self, device: &impl Readings) -> anyhow::Result<Option<String>> {
+        if let Some(min) = self.min_sm_clock {
+            self.throttle();
+            let clock = device.sm_clock()?;
+            if clock < min {
+                self.throttle();
+                if let Err(reason) = check_sm_clock(clock, min, device.throttle_reasons()?) {
+                    return Ok(Some(reason));
+                }
+            }
+        }
+        if self.require_persistence_mode {
+            self.throttle();
+            if let Err(reason) = check_persistence_mode(device.persistence_mode()?) {
+                return Ok(Some(reason));
+            }
+        }
+        if self.avoid_throttled {
+            self.throttle();
+            if let Err(reason) = check_throttle_reasons(device.throttle_reasons()?) {
+                return Ok(Some(reason));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// The readings of a GPU that [`IdleCriteria::check`] looks at, each queried only if a criterion needs it.
+trait Readings {
+    fn sm_clock(&self) -> Result<u32, NvmlError>;
+    fn persistence_mode(&self) -> Result<bool, NvmlError>;
+    fn throttle_reasons(&self) -> Result<ThrottleReasons, NvmlError>;
+}
+
+impl Readings for Device<'_> {
+    fn sm_clock(&self) -> Result<u32, NvmlError> {
+        self.clock_info(Clock::SM)
+    }
+
+    fn persistence_mode(&self) -> Result<bool, NvmlError> {
+        self.is_in_persistent_mode()
+    }
+
+    fn throttle_reasons(&self) -> Result<ThrottleReasons, NvmlError> {
+        self.current_throttle_reasons()
+    }
+}
+
+/// An idle GPU runs at its idle clock, which says nothing about the clock it reaches under load. So the clock only
+/// counts if it is held down by more than idleness, as given by `reasons`.
+fn check_sm_clock(clock: u32, min: u32, reasons: ThrottleReasons) -> Result<(), String> {
+    if clock < min && !reasons.difference(ThrottleReasons::GPU_IDLE).is_empty() {
+        Err(format!("SM clock {} MHz is below {} MHz", clock, min))
+    } else {
+        Ok(())
+    }
+}
+
+fn check_persistence_mode(enabled: bool) -> Result<(), String> {
+    if enabled {
+        Ok(())
+    } else {
+        Err("persistence mode is disabled".to_string())
+    }
+}
+
+/// Reasons that do not indicate a problem: no work to do, or clocks deliberately set by the administrator.
+const BENIGN_THROTTLE_REASONS: ThrottleReasons =
+    ThrottleReasons::GPU_IDLE.union(ThrottleReasons::APPLICATIONS_CLOCKS_SETTING);
+
+fn check_throttle_reasons(reasons: ThrottleReasons) -> Result<(), String> {
+    let reasons = reasons.difference(BENIGN_THROTTLE_REASONS);
+    if reasons.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "clocks are throttled by {}",
+            reasons.iter_names().map(|(name, _)| name).join(", ")
+        ))
+    }
+}
+
+/// Sets and resets the application clocks of a GPU.
+pub trait ClockControl {
+    fn set(&self, index: u32, mem_clock: u32, sm_clock: u32) -> Result<(), NvmlError>;
+    fn reset(&self, index: u32) -> Result<(), NvmlError>;
+}
+
+/// Controls the clocks through NVML.
+pub struct NvmlClocks;
+
+impl ClockControl for NvmlClocks {
+    fn set(&self, index: u32, mem_clock: u32, sm_clock: u32) -> Result<(), NvmlError> {
+        NVML.wait()
+            .device_by_index(index)?
+            .set_applications_clocks(mem_clock, sm_clock)
+    }
+
+    fn reset(&self, index: u32) -> Result<(), NvmlError> {
+        NVML.wait()
+            .device_by_index(index)?
+            .reset_applications_clocks()
+    }
+}
+
+/// Application clocks set on some GPUs, reset to the defaults when dropped.
+pub struct AppClocks<C: ClockControl = NvmlClocks> {
+    control: C,
+    devices: Vec<u32>,
+}
+
+impl AppClocks {
+    /// Set the application clocks (in MHz) of the given GPUs. The GPUs where it fails, e.g. without root privileges,
+    /// are left as is with a warning.
+    pub fn set(devices: &[u32], mem_clock: u32, sm_clock: u32) -> Self {
+        Self::set_with(NvmlClocks, devices, mem_clock, sm_clock)
+    }
+}
+
+impl<C: ClockControl> AppClocks<C> {
+    fn set_with(control: C, devices: &[u32], mem_clock: u32, sm_clock: u32) -> Self {
+        let devices = devices
+            .iter()
+            .copied()
+            .filter(|i| match control.set(*i, mem_clock, sm_clock) {
+                Ok(()) => {
+                    info!(
+                        "Set the application clocks of GPU {} to {} MHz (memory) and {} MHz (SM)",
+                        i, mem_clock, sm_clock
+                    );
+                    true
+                }
+                Err(NvmlError::NoPermission) => {
+                    warn!(
+                        "Not permitted to set the application clocks of GPU {}, leaving them as is",
+                        i
+                    );
+                    false
+                }
+                Err(e) => {
+                    warn!("Failed to set the application clocks of GPU {}: {}", i, e);
+                    false
+                }
+            })
+            .collect();
+        Self { control, devices }
+    }
+}
+
+impl<C: ClockControl> Drop for AppClocks<C> {
+    fn drop(&mut self) {
+        // only the GPUs whose clocks we changed, so that clocks set by someone else are left alone
+        for i in &self.devices {
+            match self.control.reset(*i) {
+                Ok(()) => info!("Reset the application clocks of GPU {}", i),
+                Err(e) => warn!("Failed to reset the application clocks of GPU {}: {}", i, e),
+            }
+        }
+    }
+}
+
+/// Parse `MEM,SM` application clocks, in MHz.
+pub(crate) fn parse_app_clocks(s: &str) -> Result<(u32, u32), String> {
+    s.split_once(',')
+        .and_then(|(mem, sm)| Some((mem.trim().parse().ok()?, sm.trim().parse().ok()?)))
+        .ok_or_else(|| format!("expected MEM,SM in MHz, e.g. `1215,1410`, got `{}`", s))
+}
+
+/// Result of a round of polling.
+pub struct Discovery {
+    /// Indices of the GPUs that are idle and meet all the criteria.
+    pub idle: Vec<u32>,
+    /// Idle GPUs that are skipped, with the reasons.
+    pub skipped: Vec<(u32, String)>,
+    /// GPUs that are in use but below the given utilization, and meet all the criteria.
+    pub underused: Vec<u32>,
+    /// GPUs with at most [`IdleCriteria::process_count_threshold`] compute processes, regardless of the other criteria.
+    ///
+    /// If `memory_threshold` is given, only collected with [`IdleCriteria::track_free`].
+    pub free: Vec<u32>,
+}
+
+/// Tracks since when each GPU has been continuously free of compute processes.
+#[derive(Default)]
+pub struct FreeStreaks {
+    since: HashMap<u32, Instant>,
+}
+
+impl FreeStreaks {
+    /// Update the streaks with the GPUs found free in the latest poll. The streaks of the others are reset.
+    pub fn update(&mut self, free: &[u32]) {
+        let now = Instant::now();
+        self.since.retain(|i, _| free.contains(i));
+        for i in free {
+            self.since.entry(*i).or_insert(now);
+        }
+    }
+
+    /// Whether the GPU has been free for at least `duration`.
+    pub fn is_free_for(&self, index: u32, duration: Duration) -> bool {
+        self.since
+            .get(&index)
+            .is_some_and(|since| since.elapsed() >= duration)
+    }
+}
+
+/// Versions of the installed NVIDIA driver stack.
+pub struct DriverVersions {
+    /// The NVIDIA driver version, e.g. "550.54.15".
+    pub driver: String,
+    /// The CUDA driver API version as `(major, minor)`, e.g. `(12, 4)`.
+    pub cuda: (i32, i32),
+}
+
+impl DriverVersions {
+    /// With the CUDA driver API version as reported by NVML, e.g. 12040 for 12.4.
+    fn new(driver: String, cuda: i32) -> Self {
+        Self {
+            driver,
+            cuda: (
+                cuda_driver_version_major(cuda),
+                cuda_driver_version_minor(cuda),
+            ),
+        }
+    }
+}
+
+pub(crate) fn driver_versions() -> anyhow::Result<DriverVersions> {
+    driver_versions_of(NVML.wait())
+}
+
+fn driver_versions_of(system: &impl SystemVersions) -> anyhow::Result<DriverVersions> {
+    Ok(DriverVersions::new(
+        system.driver_version()?,
+        system.cuda_driver_version()?,
+    ))
+}
+
+/// The system-wide queries behind [`driver_versions`].
+trait SystemVersions {
+    fn driver_version(&self) -> Result<String, NvmlError>;
+    fn cuda_driver_version(&self) -> Result<i32, NvmlError>;
+}
+
+impl SystemVersions for Nvml {
+    fn driver_version(&self) -> Result<String, NvmlError> {
+        self.sys_driver_version()
+    }
+
+    fn cuda_driver_version(&self) -> Result<i32, NvmlError> {
+        self.sys_cuda_driver_version()
+    }
+}
+
+/// The GPUs whose last query timed out and is still running in its abandoned thread, see [`query_with_timeout`].
+///
+/// They are not queried again until it returns, since NVML does not guarantee that a device can be queried from several
+/// threads at once.
+static HUNG_QUERIES: Lazy<Mutex<HashSet<u32>>> = Lazy::new(Default::default);
+
+fn is_hung(index: u32) -> bool {
+    HUNG_QUERIES.lock().contains(&index)
+}
+
+/// Run `query` on GPU `index` in a separate thread, giving up after `timeout` with `None`.
+///
+/// On timeout, the thread is abandoned and the GPU is marked as hung until the query returns.
+fn run_with_timeout<T: Send + 'static>(
+    index: u32,
+    timeout: Duration,
+    query: impl FnOnce() -> T + Send + 'static,
+) -> Option<T> {
+    let (s, r) = crossbeam_channel::bounded(1);
+    thread::spawn(move || {
+        let _ = s.send(query());
+        // sent before unmarking, so that a result missed on timeout is always followed by this
+        HUNG_QUERIES.lock().remove(&index);
+    });
+    if let Ok(result) = r.recv_timeout(timeout) {
+        return Some(result);
+    }
+    let mut hung = HUNG_QUERIES.lock();
+    // the query may have returned right after the timeout
+    if let Ok(result) = r.try_recv() {
+        return Some(result);
+    }
+    warn!(
+        "Querying GPU {} timed out, not querying it again until the query returns",
+        index
+    );
+    hung.insert(index);
+    None
+}
+
+/// Run an NVML query on a GPU, giving up after `timeout` (if given) with `None`.
+///
+/// On flaky drivers the query may hang, so it is run in a separate thread, which is abandoned on timeout, see
+/// [`run_with_timeout`].
+fn query_with_timeout<T: Send + 'static>(
+    index: u32,
+    device: &Device<'static>,
+    timeout: Option<Duration>,
+    query: fn(&Device) -> Result<T, NvmlError>,
+) -> Result<Option<T>, NvmlError> {
+    let Some(timeout) = timeout else {
+        return query(device).map(Some);
+    };
+    // reuse the handle instead of looking the device up again in the thread, which would bypass the rate limiter
+    // SAFETY: the handle comes from a valid device of the same `Nvml`, which lives as long as the process.
+    let device = unsafe { Device::new(device.handle(), device.nvml()) };
+    run_with_timeout(index, timeout, move || query(&device)).transpose()
+}
+
+/// Check whether a GPU has more than `process_count_threshold` compute processes. `None` if the query timed out.
+fn query_has_processes(
+    index: u32,
+    device: &Device<'static>,
+    criteria: &IdleCriteria,
+) -> Result<Option<bool>, NvmlError> {
+    Ok(
+        query_with_timeout(index, device, criteria.check_timeout, |d| {
+            d.running_compute_processes_count()
+        })?
+        .map(|count| count > criteria.process_count_threshold),
+    )
+}
+
+/// Check whether a GPU is busy by the configured signal, i.e. the memory used if `memory_threshold` is given,
+/// or the number of compute processes otherwise. `None` if the query timed out.
+fn query_busy(
+    index: u32,
+    device: &Device<'static>,
+    criteria: &IdleCriteria,
+) -> Result<Option<bool>, NvmlError> {
+    match criteria.memory_threshold {
+        Some(threshold) => Ok(query_with_timeout(
+            index,
+            device,
+            criteria.check_timeout,
+            criteria.nvml_scope.used_memory(),
+        )?
+        .map(|used| used > threshold)),
+        None => query_has_processes(index, device, criteria),
+    }
+}
+
+/// One lock per GPU, held while it is polled, since NVML does not guarantee that a device can be queried from several
+/// threads at once, e.g. with --parallel-nvml-queries.
+static DEVICE_LOCKS: Lazy<Mutex<HashMap<u32, Arc<Mutex<()>>>>> = Lazy::new(Default::default);
+
+fn device_lock(index: u32) -> Arc<Mutex<()>> {
+    DEVICE_LOCKS.lock().entry(index).or_default().clone()
+}
+
+/// Get handles of all devices, so that they can be reused across polls.
+pub(crate) fn get_all_devices() -> anyhow::Result<Vec<Device<'static>>> {
+    let nvml = NVML.wait();
+    let device_count = nvml.device_count()?;
+    Ok((0..device_count)
+        .map(|i| nvml.device_by_index(i))
+        .collect::<Result<_, _>>()?)
+}
+
+/// The outcome of polling a single GPU.
+enum DeviceState {
+    /// The query of whether it is busy timed out.
+    TimedOut,
+    /// Whether it is below the given utilization and meets all the criteria.
+    Busy { underused: bool, free: bool },
+    /// With the reason to skip it, if any.
+    Idle { skip: Option<String>, free: bool },
+}
+
+fn poll_device(
+    criteria: &IdleCriteria,
+    max_utilization: Option<u32>,
+    cached_devices: Option<&[Device<'static>]>,
+    index: u32,
+) -> anyhow::Result<DeviceState> {
+    // do not touch it while a query that timed out is still running
+    let hung = is_hung(index);
+    if hung && criteria.fail_on_timeout {
+        anyhow::bail!("A query of GPU {} timed out and is still running", index);
+    }
+    if hung {
+        return Ok(DeviceState::TimedOut);
+    }
+    let looked_up;
+    let device = match cached_devices {
+        Some(devices) => &devices[index as usize],
+        None => {
+            criteria.throttle();
+            looked_up = NVML.wait().device_by_index(index)?;
+            &looked_up
+        }
+    };
+    criteria.throttle();
+    let busy = match query_busy(index, device, criteria) {
+        Ok(Some(busy)) => busy,
+        Ok(None) if criteria.fail_on_timeout => {
+            anyhow::bail!("Querying GPU {} timed out", index);
+        }
+        Ok(None) => return Ok(DeviceState::TimedOut),
+        Err(e) if criteria.assume_idle_after_error => {
+            warn!("Failed to query GPU {}, assuming it is idle: {}", index, e);
+            false
+        }
+        Err(e) => return Err(e.into()),
+    };
+    // whether it has no compute processes, see `Discovery::free`
+    let free = match criteria.memory_threshold {
+        None => !busy,
+        Some(_) if criteria.track_free => {
+            criteria.throttle();
+            // a timed-out query breaks the streak, to be on the safe side
+            query_has_processes(index, device, criteria)? == Some(false)
+        }
+        Some(_) => false,
+    };
+    if busy {
+        let underused = match max_utilization {
+            Some(max) => {
+                criteria.throttle();
+                device.utilization_rates()?.gpu < max && criteria.check(device)?.is_none()
+            }
+            None => false,
+        };
+        return Ok(DeviceState::Busy { underused, free });
+    }
+    Ok(DeviceState::Idle {
+        skip: criteria.check(device)?,
+        free,
+    })
+}
+
+/// Get indices of the idle GPUs. If `cached_devices` is given, use it instead of looking up the devices again.
+///
+/// If `max_utilization` (in percent) is given, the GPUs in use but below that utilization are also collected.
+pub(crate) fn get_idle_gpu(
+    criteria: &IdleCriteria,
+    max_utilization: Option<u32>,
+    cached_devices: Option<&[Device<'static>]>,
+) -> anyhow::Result<Discovery> {
+    let device_count = match cached_devices {
+        Some(devices) => devices.len() as u32,
+        None => {
+            criteria.throttle();
+            NVML.wait().device_count()?
+        }
+    };
+    let candidates: Vec<u32> = (0..device_count)
+        .filter(|i| criteria.is_candidate(*i))
+        .collect();
+    let poll = |i: &u32| {
+        let lock = device_lock(*i);
+        let _guard = lock.lock();
+        poll_device(criteria, max_utilization, cached_devices, *i)
+    };
+    // each GPU is polled by a single thread, and the results are kept in the order of the GPUs
+    let states = if criteria.query_threads > 1 && candidates.len() > 1 {
+        let chunk_size = candidates.len().div_ceil(criteria.query_threads);
+        let states: Vec<_> = thread::scope(|scope| {
+            candidates
+                .chunks(chunk_size)
+                .map(|chunk| scope.spawn(move || chunk.iter().map(poll).collect::<Vec<_>>()))
+                .collect::<Vec<_>>()
+                .into_iter()
+                .flat_map(|handle| handle.join().expect("NVML query thread panicked"))
+                .collect()
+        });
+        Either::Left(states.into_iter())
+    } else {
+        Either::Right(candidates.iter().map(poll))
+    };
+
+    let mut idle = Vec::with_capacity(candidates.len());
+    let mut skipped = vec![];
+    let mut underused = vec![];
+    let mut free = vec![];
+    let mut timed_out = 0;
+    for (&i, state) in candidates.iter().zip(states) {
+        match state? {
+            DeviceState::TimedOut => timed_out += 1,
+            DeviceState::Busy {
+                underused: is_underused,
+                free: is_free,
+            } => {
+                if is_underused {
+                    underused.push(i);
+                }
+                if is_free {
+                    free.push(i);
+                }
+            }
+            DeviceState::Idle {
+                skip,
+                free: is_free,
+            } => {
+                if is_free {
+                    free.push(i);
+                }
+                match skip {
+                    Some(reason) => skipped.push((i, reason)),
+                    None => idle.push(i),
+                }
+            }
+        }
+    }
+    if timed_out * 2 > candidates.len() {
+        warn!(
+            "Querying {} out of {} GPUs timed out, there may be something wrong with the driver",
+            timed_out,
+            candidates.len()
+        );
+    }
+    Ok(Discovery {
+        idle,
+        skipped,
+        underused,
+        free,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timed_out_queries_mark_the_gpu_until_they_return() {
+        // an index no real GPU has, so that other tests do not see it as hung
+        let index = u32::MAX;
+        assert_eq!(
+            run_with_timeout(index, Duration::from_secs(1), || 1),
+            Some(1)
+        );
+        assert!(!is_hung(index));
+        let slow = || thread::sleep(Duration::from_millis(200));
+        assert_eq!(run_with_timeout(index, Duration::ZERO, slow), None);
+        assert!(is_hung(index));
+        thread::sleep(Duration::from_millis(500));
+        assert!(!is_hung(index));
+    }
+
+    #[test]
+    fn sm_clock() {
+        let throttled = ThrottleReasons::GPU_IDLE | ThrottleReasons::SW_THERMAL_SLOWDOWN;
+        assert!(check_sm_clock(1410, 1000, throttled).is_ok());
+        assert!(check_sm_clock(1000, 1000, throttled).is_ok());
+        assert_eq!(
+            check_sm_clock(300, 1000, throttled).unwrap_err(),
+            "SM clock 300 MHz is below 1000 MHz"
+        );
+        assert!(check_sm_clock(300, 1000, ThrottleReasons::GPU_IDLE).is_ok());
+    }
+
+    #[test]
+    fn persistence_mode() {
+        assert!(check_persistence_mode(true).is_ok());
+        assert_eq!(
+            check_persistence_mode(false).unwrap_err(),
+            "persistence mode is disabled"
+        );
+    }
+
+    /// Fixed readings; `None` fails the query, to show it is not made.
+    #[derive(Default)]
+    struct FakeReadings {
+        sm_clock: Option<u32>,
+        persistence_mode: Option<bool>,
+        throttle_reasons: Option<ThrottleReasons>,
+    }
+
+    impl Readings for FakeReadings {
+        fn sm_clock(&self) -> Result<u32, NvmlError> {
+            self.sm_clock.ok_or(NvmlError::NotSupported)
+        }
+
+        fn persistence_mode(&self) -> Result<bool, NvmlError> {
+            self.persistence_mode.ok_or(NvmlError::NotSupported)
+        }
+
+        fn throttle_reasons(&self) -> Result<ThrottleReasons, NvmlError> {
+            self.throttle_reasons.ok_or(NvmlError::NotSupported)
+        }
+    }
+
+    #[test]
+    fn skips_gpus_below_the_min_sm_clock() {
+        let criteria = IdleCriteria {
+            min_sm_clock: Some(1000),
+            ..Default::default()
+        };
+        let fast = FakeReadings {
+            sm_clock: Some(1410),
+            ..Default::default()
+        };
+        assert_eq!(criteria.check(&fast).unwrap(), None);
+        let idle = FakeReadings {
+            sm_clock: Some(210),
+            throttle_reasons: Some(ThrottleReasons::GPU_IDLE),
+            ..Default::default()
+        };
+        assert_eq!(criteria.check(&idle).unwrap(), None);
+        let throttled = FakeReadings {
+            sm_clock: Some(600),
+            throttle_reasons: Some(
+                ThrottleReasons::GPU_IDLE | ThrottleReasons::HW_THERMAL_SLOWDOWN,
+            ),
+            ..Default::default()
+        };
+        assert_eq!(
+            criteria.check(&throttled).unwrap().as_deref(),
+            Some("SM clock 600 MHz is below 1000 MHz")
+        );
+    }
+
+    #[test]
+    fn skips_gpus_without_persistence_mode() {
+        let criteria = IdleCriteria {
+            require_persistence_mode: true,
+            ..Default::default()
+        };
+        let enabled = FakeReadings {
+            persistence_mode: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(criteria.check(&enabled).unwrap(), None);
+        let disabled = FakeReadings {
+            persistence_mode: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(
+            criteria.check(&disabled).unwrap().as_deref(),
+            Some("persistence mode is disabled")
+        );
+        let unknown = FakeReadings::default();
+        assert!(criteria.check(&unknown).is_err());
+        // not queried at all unless required
+        assert_eq!(IdleCriteria::default().check(&unknown).unwrap(), None);
+    }
+
+    #[test]
+    fn throttle_reasons() {
+        assert!(check_throttle_reasons(ThrottleReasons::empty()).is_ok());
+        assert!(check_throttle_reasons(BENIGN_THROTTLE_REASONS).is_ok());
+        assert_eq!(
+            check_throttle_reasons(ThrottleReasons::GPU_IDLE | ThrottleReasons::HW_SLOWDOWN)
+                .unwrap_err(),
+            "clocks are throttled by HW_SLOWDOWN"
+        );
+        assert_eq!(
+            check_throttle_reasons(
+                ThrottleReasons::SW_POWER_CAP | ThrottleReasons::SW_THERMAL_SLOWDOWN
+            )
+            .unwrap_err(),
+            "clocks are throttled by SW_POWER_CAP, SW_THERMAL_SLOWDOWN"
+        );
+    }
+
+    #[test]
+    fn parses_app_clocks() {
+        assert_eq!(parse_app_clocks("1215,1410"), Ok((1215, 1410)));
+        assert_eq!(parse_app_clocks(" 877 , 1530 "), Ok((877, 1530)));
+        for s in ["1215", "1215,", "1215,1410,1500", "fast,1410", "-1,1410"] {
+            assert!(parse_app_clocks(s).is_err(), "{}", s);
+        }
+    }
+
+    /// Records the clock changes, failing on the given GPUs.
+    struct FakeClocks {
+        forbidden: Vec<u32>,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl ClockControl for FakeClocks {
+        fn set(&self, index: u32, mem_clock: u32, sm_clock: u32) -> Result<(), NvmlError> {
+            if self.forbidden.contains(&index) {
+                return Err(NvmlError::NoPermission);
+            }
+            self.calls
+                .lock()
+                .push(format!("set {} {},{}", index, mem_clock, sm_clock));
+            Ok(())
+        }
+
+        fn reset(&self, index: u32) -> Result<(), NvmlError> {
+            self.calls.lock().push(format!("reset {}", index));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn app_clocks_are_reset_when_dropped() {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let control = FakeClocks {
+            forbidden: vec![1],
+            calls: calls.clone(),
+        };
+        let clocks = AppClocks::set_with(control, &[0, 1, 2], 1215, 1410);
+        assert_eq!(*calls.lock(), ["set 0 1215,1410", "set 2 1215,1410"]);
+        drop(clocks);
+        // the GPU that could not be set is not reset either
+        assert_eq!(
+            *calls.lock(),
+            ["set 0 1215,1410", "set 2 1215,1410", "reset 0", "reset 2"]
+        );
+    }
+
+    #[test]
+    fn free_streaks() {
+        let mut streaks = FreeStreaks::default();
+        streaks.update(&[0, 1]);
+        assert!(streaks.is_free_for(0, Duration::ZERO));
+        assert!(!streaks.is_free_for(0, Duration::from_secs(60)));
+        assert!(!streaks.is_free_for(2, Duration::ZERO));
+        thread::sleep(Duration::from_millis(20));
+        // a GPU seen busy starts over, the others keep their streaks
+        streaks.update(&[1]);
+        streaks.update(&[0, 1]);
+        assert!(!streaks.is_free_for(0, Duration::from_millis(20)));
+        assert!(streaks.is_free_for(1, Duration::from_millis(20)));
+    }
+
+    /// Fixed versions; `None` fails the query.
+    struct FakeVersions {
+        driver: Option<&'static str>,
+        cuda: i32,
+    }
+
+    impl SystemVersions for FakeVersions {
+        fn driver_version(&self) -> Result<String, NvmlError> {
+            self.driver
+                .map(str::to_string)
+                .ok_or(NvmlError::NotSupported)
+        }
+
+        fn cuda_driver_version(&self) -> Result<i32, NvmlError> {
+            Ok(self.cuda)
+        }
+    }
+
+    #[test]
+    fn gathers_the_driver_versions() {
+        let system = FakeVersions {
+            driver: Some("550.54.15"),
+            cuda: 12040,
+        };
+        let versions = driver_versions_of(&system).unwrap();
+        assert_eq!(versions.driver, "550.54.15");
+        assert_eq!(versions.cuda, (12, 4));
+        let system = FakeVersions {
+            driver: Some(""),
+            cuda: 11080,
+        };
+        assert_eq!(driver_versions_of(&system).unwrap().cuda, (11, 8));
+        let system = FakeVersions {
+            driver: None,
+            cuda: 12040,
+        };
+        assert!(driver_versions_of(&system).is_err());
+    }
+}
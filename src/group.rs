@@ -0,0 +1,110 @@
+use std::ffi::OsString;
+
+use anyhow::Context;
+use either::Either;
+use itertools::Itertools;
+
+/// A command to run once the GPUs are found.
+pub(crate) struct Job {
+    /// How many of the selected GPUs the command gets, in order, or all of them if `None`.
+    pub size: Option<usize>,
+    /// The arguments that are valid UTF-8 may contain the `{}` template.
+    pub args: Vec<Either<OsString, String>>,
+    pub has_template: bool,
+}
+
+/// A command given to `group`, with the number of GPUs it gets.
+pub(crate) struct Group {
+    pub size: usize,
+    pub command: Vec<OsString>,
+}
+
+/// Parse the arguments of `group`, i.e. `--group N COMMAND...` separated by `--`.
+///
+/// Only a `--` followed by `--group` separates the commands, so that a command may contain `--` itself, e.g. to pass
+/// the arguments of a script through a launcher.
+pub(crate) fn parse(args: &[OsString]) -> anyhow::Result<Vec<Group>> {
+    // the `--` separating the commands
+    let separators = args
+        .windows(2)
+        .positions(|pair| pair[0] == "--" && pair[1] == "--group");
+    let mut parts = vec![];
+    let mut start = 0;
+    for end in separators.chain([args.len()]) {
+        parts.push(&args[start..end]);
+        start = end + 1;
+    }
+    parts
+        .into_iter()
+        .map(|part| {
+            let [flag, size, command @ ..] = part else {
+                anyhow::bail!("Expected `--group N COMMAND...`, got {:?}", part);
+            };
+            if flag != "--group" {
+                anyhow::bail!("Expected `--group N COMMAND...`, got {:?}", part);
+            }
+            let size = size
+                .to_str()
+                .and_then(|size| size.parse::<usize>().ok())
+                .filter(|size| *size > 0)
+                .with_context(|| format!("Invalid number of GPUs {:?} for a group", size))?;
+            if command.is_empty() {
+                anyhow::bail!("No command given for the group of {} GPUs", size);
+            }
+            Ok(Group {
+                size,
+                command: command.to_vec(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn parses_groups() {
+        let groups = parse(&args(&[
+            "--group", "2", "python", "a.py", "--", "--group", "1", "b",
+        ]))
+        .unwrap();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].size, 2);
+        assert_eq!(groups[0].command, args(&["python", "a.py"]));
+        assert_eq!(groups[1].size, 1);
+        assert_eq!(groups[1].command, args(&["b"]));
+    }
+
+    #[test]
+    fn rejects_malformed_groups() {
+        for spec in [
+            &["--group", "2"][..],
+            &["--group", "0", "a"],
+            &["--group", "two", "a"],
+            &["--size", "2", "a"],
+            &["--group", "1", "a", "--", "--group"],
+            &["--group", "1", "a", "--", "--group", "x", "b"],
+        ] {
+            assert!(parse(&args(spec)).is_err(), "{:?}", spec);
+        }
+    }
+
+    #[test]
+    fn commands_may_contain_double_dashes() {
+        let groups = parse(&args(&[
+            "--group", "1", "torchrun", "a.py", "--", "--lr", "1", "--", "--group", "1", "b", "--",
+        ]))
+        .unwrap();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(
+            groups[0].command,
+            args(&["torchrun", "a.py", "--", "--lr", "1"])
+        );
+        assert_eq!(groups[1].command, args(&["b", "--"]));
+    }
+}
@@ -1,14 +1,124 @@
 use std::{
-    fs::File,
+    fs::{File, OpenOptions},
     io,
     path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
 };
 
+use clap::ValueEnum;
 use fs4::FileExt;
 use log::warn;
 
+/// How files are locked.
+///
+/// `flock` locks belong to the open file, and are released when it is closed. They are not reliable on some network
+/// file systems, e.g. older NFS. POSIX `fcntl` locks (as used by `lockf`) work over NFS, but they belong to the whole
+/// process: closing any handle of the file in the process releases them, and they never block the same process.
+/// `lockf` is only supported on Unix.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LockType {
+    Flock,
+    Lockf,
+}
+
+impl LockType {
+    /// Open a file to be locked with this lock type. `fcntl` locks need the file to be opened for reading and writing.
+    pub(crate) fn open(self, path: impl AsRef<Path>) -> io::Result<File> {
+        match self {
+            LockType::Flock => open_or_create_file(path),
+            LockType::Lockf => {
+                // make sure it exists and is writable by everyone first
+                open_or_create_file(&path)?;
+                OpenOptions::new().read(true).write(true).open(path)
+            }
+        }
+    }
+
+    pub(crate) fn lock_exclusive(self, file: &File) -> io::Result<()> {
+        match self {
+            LockType::Flock => file.lock_exclusive(),
+            LockType::Lockf => fcntl_lock(file, FcntlLock::Exclusive, true),
+        }
+    }
+
+    /// Take an exclusive lock if nobody else holds the file locked. Returns whether it was taken.
+    pub(crate) fn try_lock_exclusive(self, file: &File) -> io::Result<bool> {
+        let result = match self {
+            LockType::Flock => fs4::FileExt::try_lock_exclusive(file),
+            LockType::Lockf => fcntl_lock(file, FcntlLock::Exclusive, false),
+        };
+        match result {
+            Ok(()) => Ok(true),
+            Err(e) if e.raw_os_error() == fs4::lock_contended_error().raw_os_error() => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Take a shared lock, which can be held by several readers at once. `fcntl` locks only need the file to be opened
+    /// for reading.
+    pub(crate) fn lock_shared(self, file: &File) -> io::Result<()> {
+        match self {
+            LockType::Flock => fs4::FileExt::lock_shared(file),
+            LockType::Lockf => fcntl_lock(file, FcntlLock::Shared, true),
+        }
+    }
+
+    pub(crate) fn unlock(self, file: &File) -> io::Result<()> {
+        match self {
+            LockType::Flock => fs4::FileExt::unlock(file),
+            LockType::Lockf => fcntl_lock(file, FcntlLock::Unlock, true),
+        }
+    }
+}
+
+enum FcntlLock {
+    Shared,
+    Exclusive,
+    Unlock,
+}
+
+/// Lock or unlock the whole file with `fcntl`. If `wait` is set, wait until the lock is granted, otherwise fail with
+/// [`fs4::lock_contended_error`] if it is held by another process.
+#[cfg(unix)]
+fn fcntl_lock(file: &File, lock: FcntlLock, wait: bool) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: `flock` is a plain C struct, for which all zeros is valid.
+    let mut flock: libc::flock = unsafe { std::mem::zeroed() };
+    flock.l_type = match lock {
+        FcntlLock::Shared => libc::F_RDLCK,
+        FcntlLock::Exclusive => libc::F_WRLCK,
+        FcntlLock::Unlock => libc::F_UNLCK,
+    } as _;
+    flock.l_whence = libc::SEEK_SET as _;
+    // `l_start` and `l_len` are zero, i.e. the whole file, however long it grows
+    let cmd = if wait { libc::F_SETLKW } else { libc::F_SETLK };
+    loop {
+        // SAFETY: the file descriptor is valid while `file` is borrowed, and `flock` is initialized.
+        if unsafe { libc::fcntl(file.as_raw_fd(), cmd, &flock) } != -1 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EINTR) => {}
+            // POSIX allows either when the lock is held
+            Some(libc::EACCES | libc::EAGAIN) if !wait => return Err(fs4::lock_contended_error()),
+            _ => return Err(e),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn fcntl_lock(_file: &File, _lock: FcntlLock, _wait: bool) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "lockf is only supported on Unix",
+    ))
+}
+
 /// A heuristic way to decide a global runtime directory.
-fn guess_global_runtime_dir() -> PathBuf {
+pub(crate) fn guess_global_runtime_dir() -> PathBuf {
     if cfg!(windows) {
         PathBuf::from("C:\\ProgramData")
     } else if cfg!(target_os = "android") {
@@ -20,8 +130,29 @@ fn guess_global_runtime_dir() -> PathBuf {
     }
 }
 
+/// The name of a file shared by the gpu-waiter instances, e.g. `gpu-waiter.lock`, or `gpu-waiter.<GROUP>.lock` within a
+/// sharing group.
+pub(crate) fn shared_file_name(group: Option<&str>, extension: &str) -> String {
+    match group {
+        Some(group) => format!("gpu-waiter.{}.{}", group, extension),
+        None => format!("gpu-waiter.{}", extension),
+    }
+}
+
+/// Parse the ID of a sharing group, which becomes part of file names.
+pub(crate) fn parse_group_id(s: &str) -> Result<String, String> {
+    if s.is_empty() || !s.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')) {
+        return Err(format!("expected letters, digits, `-`, `_` or `.`, got `{}`", s));
+    }
+    Ok(s.to_string())
+}
+
+/// How often [`FileRWLock::write_cancellable`] tries to take the lock.
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
 pub struct FileRWLock {
     file: std::fs::File,
+    lock_type: LockType,
 }
 
 pub struct RWLockReadGuard<'a> {
@@ -40,7 +171,7 @@ pub struct RWLockWriteGuard<'a> {
 ///
 /// Unfortunately, this is the case for `/tmp` on many Linux distributions. So we have to try [`File::open`]
 /// first and then `create` if it fails with `NotFound`.
-fn open_or_create_file(path: impl AsRef<Path>) -> io::Result<File> {
+pub(crate) fn open_or_create_file(path: impl AsRef<Path>) -> io::Result<File> {
     let p = path.as_ref();
 
     // 1. try to open the file
@@ -81,8 +212,93 @@ fn open_or_create_file(path: impl AsRef<Path>) -> io::Result<File> {
     }
 }
 
+/// Look up the ID of a Unix group by its name.
+#[cfg(unix)]
+fn group_id(group: &str) -> io::Result<libc::gid_t> {
+    let name = std::ffi::CString::new(group)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "The group name contains a NUL byte"))?;
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        // SAFETY: `group` is a plain C struct, for which all zeros is valid.
+        let mut entry: libc::group = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        // SAFETY: all pointers are valid for the call, and `buf.len()` is the size of `buf`.
+        let err = unsafe { libc::getgrnam_r(name.as_ptr(), &mut entry, buf.as_mut_ptr(), buf.len(), &mut result) };
+        match err {
+            0 if result.is_null() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("There is no Unix group named {}", group),
+                ))
+            }
+            0 => return Ok(entry.gr_gid),
+            // the entry does not fit in the buffer
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            err => return Err(io::Error::from_raw_os_error(err)),
+        }
+    }
+}
+
+/// Whether the current user belongs to the group `gid`, either as its primary group or as a supplementary one.
+#[cfg(unix)]
+fn is_group_member(gid: libc::gid_t) -> bool {
+    // SAFETY: `getegid` is always successful.
+    if unsafe { libc::getegid() } == gid {
+        return true;
+    }
+    // SAFETY: with a size of 0, `getgroups` only returns the number of groups.
+    let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    if count <= 0 {
+        return false;
+    }
+    let mut groups: Vec<libc::gid_t> = vec![0; count as usize];
+    // SAFETY: `groups` holds `count` elements.
+    let count = unsafe { libc::getgroups(count, groups.as_mut_ptr()) };
+    count > 0 && groups[..count as usize].contains(&gid)
+}
+
+/// Make a file shared by the gpu-waiter instances only accessible to the members of a Unix group, instead of everyone.
+///
+/// If the file is ours, e.g. just created, it is handed over to `group` with mode `0o660`. A file created by
+/// someone else cannot be changed, so it is only checked.
+#[cfg(unix)]
+pub(crate) fn share_with_group(file: &File, path: &Path, group: &str) -> io::Result<()> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let gid = group_id(group)?;
+    // SAFETY: `geteuid` is always successful.
+    let euid = unsafe { libc::geteuid() };
+    // only root may hand a file over to a group it is not a member of
+    if euid != 0 && !is_group_member(gid) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("You are not a member of the Unix group {}", group),
+        ));
+    }
+    let metadata = file.metadata()?;
+    if metadata.uid() == euid {
+        std::os::unix::fs::fchown(file, None, Some(gid))?;
+        file.set_permissions(std::fs::Permissions::from_mode(0o660))?;
+    } else if metadata.gid() != gid {
+        warn!(
+            "The file {:?} belongs to another group than {}, and only its owner can change that",
+            path,
+            group
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn share_with_group(_file: &File, _path: &Path, _group: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Files shared by a Unix group are only supported on Unix",
+    ))
+}
+
 impl FileRWLock {
-    pub fn new(name: impl AsRef<Path>) -> io::Result<Self> {
+    pub fn new(name: impl AsRef<Path>, lock_type: LockType) -> io::Result<Self> {
         let base_rt_dir = guess_global_runtime_dir();
         if !base_rt_dir.exists() {
             panic!(
@@ -91,29 +307,167 @@ impl FileRWLock {
             );
         }
         let p = base_rt_dir.join(name);
-        let f = open_or_create_file(&p)?;
-        Ok(Self { file: f })
+        let f = lock_type.open(&p)?;
+        Ok(Self { file: f, lock_type })
+    }
+
+    /// Like [`FileRWLock::new`], but the lock file is only accessible to the members of a Unix group, instead of
+    /// everyone, see [`share_with_group`].
+    pub fn new_shared(name: impl AsRef<Path>, group: &str, lock_type: LockType) -> io::Result<Self> {
+        let lock = Self::new(&name, lock_type)?;
+        share_with_group(&lock.file, name.as_ref(), group)?;
+        Ok(lock)
     }
 
     pub fn read(&self) -> io::Result<RWLockReadGuard<'_>> {
-        fs4::FileExt::lock_shared(&self.file)?;
+        self.lock_type.lock_shared(&self.file)?;
         Ok(RWLockReadGuard { _lock: self })
     }
 
     pub fn write(&self) -> io::Result<RWLockWriteGuard<'_>> {
-        self.file.lock_exclusive()?;
+        self.lock_type.lock_exclusive(&self.file)?;
         Ok(RWLockWriteGuard { _lock: self })
     }
+
+    /// Like [`FileRWLock::write`], but give up after `timeout` or once `cancelled` returns true, which is checked
+    /// between the attempts, returning `None`. Without `timeout`, wait until the lock is taken or cancelled.
+    pub fn write_cancellable(
+        &self,
+        timeout: Option<Duration>,
+        mut cancelled: impl FnMut() -> bool,
+    ) -> io::Result<Option<RWLockWriteGuard<'_>>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            if self.lock_type.try_lock_exclusive(&self.file)? {
+                return Ok(Some(RWLockWriteGuard { _lock: self }));
+            }
+            let remaining = match deadline {
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+                None => LOCK_RETRY_INTERVAL,
+            };
+            if remaining.is_zero() || cancelled() {
+                return Ok(None);
+            }
+            thread::sleep(remaining.min(LOCK_RETRY_INTERVAL));
+        }
+    }
 }
 
 impl Drop for RWLockReadGuard<'_> {
     fn drop(&mut self) {
-        fs4::FileExt::unlock(&self._lock.file).expect("Failed to unlock file");
+        self._lock.lock_type.unlock(&self._lock.file).expect("Failed to unlock file");
     }
 }
 
 impl Drop for RWLockWriteGuard<'_> {
     fn drop(&mut self) {
-        fs4::FileExt::unlock(&self._lock.file).expect("Failed to unlock file");
+        self._lock.lock_type.unlock(&self._lock.file).expect("Failed to unlock file");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The name of a lock file in the runtime directory, unique to the test.
+    fn lock_name(name: &str) -> String {
+        format!("gpu-waiter-test-{}-{}.lock", std::process::id(), name)
+    }
+
+    #[test]
+    fn flock_blocks_other_handles() {
+        let name = lock_name("flock");
+        let first = FileRWLock::new(&name, LockType::Flock).unwrap();
+        let second = FileRWLock::new(&name, LockType::Flock).unwrap();
+        let guard = first.write().unwrap();
+        assert!(second
+            .write_cancellable(Some(Duration::ZERO), || false)
+            .unwrap()
+            .is_none());
+        drop(guard);
+        assert!(second
+            .write_cancellable(Some(Duration::ZERO), || false)
+            .unwrap()
+            .is_some());
+        std::fs::remove_file(guess_global_runtime_dir().join(name)).unwrap();
+    }
+
+    #[test]
+    fn write_cancellable_gives_up_once_cancelled() {
+        let name = lock_name("cancellable");
+        let first = FileRWLock::new(&name, LockType::Flock).unwrap();
+        let second = FileRWLock::new(&name, LockType::Flock).unwrap();
+        let guard = first.write().unwrap();
+        let start = Instant::now();
+        assert!(second.write_cancellable(None, || true).unwrap().is_none());
+        assert!(start.elapsed() < Duration::from_secs(1));
+        let attempts = std::cell::Cell::new(0);
+        let cancelled = || {
+            attempts.set(attempts.get() + 1);
+            attempts.get() > 2
+        };
+        assert!(second.write_cancellable(None, cancelled).unwrap().is_none());
+        assert_eq!(attempts.get(), 3);
+        drop(guard);
+        // not cancelled while the lock is free
+        assert!(second.write_cancellable(None, || true).unwrap().is_some());
+        std::fs::remove_file(guess_global_runtime_dir().join(name)).unwrap();
+    }
+
+    /// Whether another process can take the lock exclusively, tried in a forked child.
+    #[cfg(unix)]
+    fn taken_by_child(lock: &FileRWLock) -> bool {
+        // SAFETY: the child only calls `fcntl` (through `try_lock_exclusive`) and `_exit`, which are async-signal-safe.
+        match unsafe { libc::fork() } {
+            -1 => panic!("Failed to fork: {}", io::Error::last_os_error()),
+            0 => {
+                let code = match lock.lock_type.try_lock_exclusive(&lock.file) {
+                    Ok(true) => 0,
+                    Ok(false) => 1,
+                    Err(_) => 2,
+                };
+                // SAFETY: `_exit` is always safe to call, and skips the destructors and exit handlers of the parent.
+                unsafe { libc::_exit(code) }
+            }
+            pid => {
+                let mut status = 0;
+                // SAFETY: `pid` is our child, and `status` is valid for writes.
+                assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+                assert!(libc::WIFEXITED(status));
+                match libc::WEXITSTATUS(status) {
+                    0 => true,
+                    1 => false,
+                    _ => panic!("The child failed to lock the file"),
+                }
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn lockf_excludes_other_processes() {
+        let name = lock_name("lockf-fork");
+        let lock = FileRWLock::new(&name, LockType::Lockf).unwrap();
+        let guard = lock.write().unwrap();
+        assert!(!taken_by_child(&lock));
+        drop(guard);
+        assert!(taken_by_child(&lock));
+        std::fs::remove_file(guess_global_runtime_dir().join(name)).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn lockf_never_blocks_the_same_process() {
+        let name = lock_name("lockf");
+        let first = FileRWLock::new(&name, LockType::Lockf).unwrap();
+        let second = FileRWLock::new(&name, LockType::Lockf).unwrap();
+        let guard = first.write().unwrap();
+        assert!(second
+            .write_cancellable(Some(Duration::ZERO), || false)
+            .unwrap()
+            .is_some());
+        drop(guard);
+        assert!(first.read().is_ok());
+        std::fs::remove_file(guess_global_runtime_dir().join(name)).unwrap();
     }
 }
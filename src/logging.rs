@@ -0,0 +1,216 @@
+use std::{io::Write, time::Duration};
+
+use clap::ValueEnum;
+
+/// How timestamps are shown in log records.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogTimestamps {
+    /// No timestamp
+    None,
+    /// Local time, e.g. 15:04:05
+    Time,
+    /// Local date and time, e.g. 2024-11-22 15:04:05
+    Datetime,
+    /// RFC 3339 with the local offset, e.g. 2024-11-22T15:04:05+08:00
+    Rfc3339,
+}
+
+impl LogTimestamps {
+    fn now(self) -> Option<String> {
+        let now = chrono::Local::now();
+        match self {
+            LogTimestamps::None => None,
+            LogTimestamps::Time => Some(now.format("%H:%M:%S").to_string()),
+            LogTimestamps::Datetime => Some(now.format("%Y-%m-%d %H:%M:%S").to_string()),
+            LogTimestamps::Rfc3339 => Some(now.to_rfc3339_opts(chrono::SecondsFormat::Secs, false)),
+        }
+    }
+
+    /// The current time for messages that show it, e.g. the spinner, in the same format as the log records.
+    /// The time of day if timestamps are not shown.
+    pub(crate) fn format_now(self) -> String {
+        self.now()
+            .or_else(|| LogTimestamps::Time.now())
+            .unwrap_or_default()
+    }
+}
+
+/// Build the logger, whose records look like `[<timestamp> <LEVEL> <target>] <message>`.
+pub(crate) fn build_logger(timestamps: LogTimestamps) -> env_logger::Logger {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+        .format(move |buf, record| {
+            let style = buf.default_level_style(record.level());
+            write!(buf, "[")?;
+            if let Some(ts) = timestamps.now() {
+                write!(buf, "{} ", ts)?;
+            }
+            writeln!(
+                buf,
+                "{style}{:<5}{style:#} {}] {}",
+                record.level(),
+                record.target(),
+                record.args()
+            )
+        })
+        .build()
+}
+
+/// The animation of the spinner shown while waiting.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SpinnerStyle {
+    /// The default of indicatif
+    Default,
+    /// Braille dots, e.g. ⠋ ⠙ ⠹
+    Dots,
+    /// A rotating line, i.e. | / - \
+    Line,
+    /// A rotating arrow, e.g. ← ↖ ↑
+    Arrow,
+}
+
+impl SpinnerStyle {
+    pub(crate) fn progress_style(self) -> indicatif::ProgressStyle {
+        // the last character is shown when the spinner is finished
+        let tick_chars = match self {
+            SpinnerStyle::Default => return indicatif::ProgressStyle::default_spinner(),
+            SpinnerStyle::Dots => "⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏ ",
+            SpinnerStyle::Line => "|/-\\ ",
+            SpinnerStyle::Arrow => "←↖↑↗→↘↓↙ ",
+        };
+        indicatif::ProgressStyle::default_spinner().tick_chars(tick_chars)
+    }
+
+    /// Style the spinner and keep it ticking every `interval`, until it is finished.
+    pub(crate) fn start(self, spinner: &indicatif::ProgressBar, interval: Duration) {
+        spinner.set_style(self.progress_style());
+        spinner.set_message("Waiting for idle GPUs...");
+        spinner.enable_steady_tick(interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, io, sync::Arc, thread};
+
+    use parking_lot::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn timestamp_formats() {
+        assert_eq!(LogTimestamps::None.now(), None);
+        let time = LogTimestamps::Time.now().unwrap();
+        assert!(
+            chrono::NaiveTime::parse_from_str(&time, "%H:%M:%S").is_ok(),
+            "{}",
+            time
+        );
+        let datetime = LogTimestamps::Datetime.now().unwrap();
+        assert!(
+            chrono::NaiveDateTime::parse_from_str(&datetime, "%Y-%m-%d %H:%M:%S").is_ok(),
+            "{}",
+            datetime
+        );
+        let rfc3339 = LogTimestamps::Rfc3339.now().unwrap();
+        assert!(
+            chrono::DateTime::parse_from_rfc3339(&rfc3339).is_ok(),
+            "{}",
+            rfc3339
+        );
+    }
+
+    #[test]
+    fn spinner_styles() {
+        let line = SpinnerStyle::Line.progress_style();
+        assert_eq!(line.get_tick_str(0), "|");
+        assert_eq!(line.get_tick_str(3), "\\");
+        // wraps around, skipping the final character
+        assert_eq!(line.get_tick_str(4), "|");
+        assert_eq!(line.get_final_tick_str(), " ");
+        assert_eq!(SpinnerStyle::Dots.progress_style().get_tick_str(1), "⠙");
+        assert_eq!(SpinnerStyle::Arrow.progress_style().get_tick_str(2), "↑");
+    }
+
+    /// Records the lines drawn.
+    #[derive(Debug, Default)]
+    struct FakeTerm {
+        lines: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl indicatif::TermLike for FakeTerm {
+        fn width(&self) -> u16 {
+            80
+        }
+
+        fn move_cursor_up(&self, _: usize) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn move_cursor_down(&self, _: usize) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn move_cursor_right(&self, _: usize) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn move_cursor_left(&self, _: usize) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn write_line(&self, s: &str) -> io::Result<()> {
+            self.write_str(s)
+        }
+
+        fn write_str(&self, s: &str) -> io::Result<()> {
+            self.lines.lock().push(s.to_string());
+            Ok(())
+        }
+
+        fn clear_line(&self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn flush(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// The distinct ticks drawn by a spinner ticking every `interval` for a while.
+    fn ticks(interval: Duration) -> HashSet<String> {
+        let term = FakeTerm::default();
+        let lines = term.lines.clone();
+        let target = indicatif::ProgressDrawTarget::term_like(Box::new(term));
+        let spinner = indicatif::ProgressBar::with_draw_target(None, target);
+        SpinnerStyle::Line.start(&spinner, interval);
+        thread::sleep(Duration::from_millis(300));
+        spinner.finish();
+        let ticks = lines
+            .lock()
+            .iter()
+            .filter_map(|line| line.strip_suffix(" Waiting for idle GPUs..."))
+            .map(str::to_string)
+            .collect();
+        ticks
+    }
+
+    #[test]
+    fn spinner_ticks_at_the_interval() {
+        let all = ["|", "/", "-", "\\", " "].map(str::to_string).into();
+        assert_eq!(ticks(Duration::from_millis(10)), all);
+        // only the first tick before it is finished
+        let slow = ["|", "/", " "].map(str::to_string).into();
+        assert_eq!(ticks(Duration::from_secs(60)), slow);
+    }
+
+    #[test]
+    fn format_now_falls_back_to_the_time() {
+        let time = LogTimestamps::None.format_now();
+        assert!(
+            chrono::NaiveTime::parse_from_str(&time, "%H:%M:%S").is_ok(),
+            "{}",
+            time
+        );
+        assert!(LogTimestamps::Datetime.format_now().contains(' '));
+    }
+}
@@ -1,17 +1,19 @@
-#![feature(try_blocks)]
 #![feature(anonymous_lifetime_in_impl_trait)]
 
 use std::{
+    collections::{HashMap, HashSet},
     ffi::OsString,
     num::NonZeroU32,
-    process::Command,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
     sync::{atomic::AtomicBool, Arc},
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+use anyhow::Context;
 use clap::{Parser, Subcommand};
-use crossbeam_channel::{never, select};
+use crossbeam_channel::{after, never, select, tick};
 use either::Either;
 use indicatif::MultiProgress;
 use indicatif_log_bridge::LogWrapper;
@@ -19,10 +21,26 @@ use log::{error, info, warn};
 use mimalloc::MiMalloc;
 use nvml_wrapper::Nvml;
 use once_cell::sync::OnceCell;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 
+mod affinity;
+mod background;
+mod bench;
+mod claims;
 mod command;
+mod gpu;
+mod group;
 mod lock;
+mod logging;
+mod occupy;
+mod output;
+mod process;
+mod remote;
+mod report;
+mod select;
+mod selftest;
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
@@ -39,9 +57,529 @@ struct Cli {
     num: NonZeroU32,
 
     /// Force to run the command with CUDA_VISIBLE_DEVICES set to the selected GPUs, even if {} is present in the command.
+    ///
+    /// Commands without {} get it set anyway, so it only matters for those with {}.
     #[arg(short, long, default_value = "false")]
     force_env: bool,
 
+    /// Never set CUDA_VISIBLE_DEVICES for the command, even if {} is absent, e.g. for monitoring commands that should see all GPUs.
+    ///
+    /// An inherited CUDA_VISIBLE_DEVICES is removed as well.
+    #[arg(long, default_value = "false", conflicts_with = "force_env")]
+    no_cuda_env: bool,
+
+    /// How literal braces are escaped in the command: `{{` and `}}`, or `\{` and `\}`.
+    #[arg(long, value_enum, default_value_t = command::EscapeStyle::DoubleBrace)]
+    escape_style: command::EscapeStyle,
+
+    /// Strip the leading and trailing whitespace of each argument of the command, and collapse the whitespace inside into single spaces.
+    ///
+    /// Useful for commands written across multiple lines, e.g. in config files.
+    #[arg(long, default_value = "false")]
+    normalize_whitespace: bool,
+
+    /// Prepend a timestamp and the stream name, e.g. `[15:04:05.123 STDOUT]`, to each line of the command's output.
+    #[arg(long, default_value = "false")]
+    timestamp_child_output: bool,
+
+    /// Prepend the given string to each line of the command's output, e.g. to tell apart the output of several instances.
+    ///
+    /// Can be combined with --timestamp-child-output, in which case it comes before the timestamp.
+    #[arg(long, value_name = "STRING")]
+    child_log_prefix: Option<String>,
+
+    /// Set an environment variable for the command, e.g. `--env OMP_NUM_THREADS=4`. Can be given multiple times.
+    ///
+    /// These take precedence over the variables set by gpu-waiter, e.g. by --torch-env.
+    #[arg(long, value_name = "KEY=VALUE", value_parser = process::parse_env)]
+    env: Vec<(String, String)>,
+
+    /// Set MASTER_ADDR, MASTER_PORT (a free local port) and WORLD_SIZE (the number of selected GPUs) for PyTorch distributed.
+    #[arg(long, default_value = "false")]
+    torch_env: bool,
+
+    /// Disable all log output (even errors) and the progress spinner, and cache device handles between polls.
+    ///
+    /// Useful for high-frequency automated scheduling where the output overhead matters.
+    #[arg(long, default_value = "false")]
+    perf_mode: bool,
+
+    /// Only with --perf-mode: look up the device handles it caches again after this many seconds, e.g. in case the MIG
+    /// configuration changed. GPU properties such as the name are never cached, they are queried in every poll.
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 3600,
+        requires = "perf_mode",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    gpu_info_cache_ttl: u64,
+
+    /// Set the niceness of gpu-waiter itself at startup, from -20 (highest priority) to 19 (lowest), so that polling on a
+    /// busy login node yields to interactive work. Unix only.
+    ///
+    /// The command still starts at the original niceness. Only root can lower the niceness below the current one.
+    #[arg(long, value_name = "N", allow_negative_numbers = true, value_parser = clap::value_parser!(i32).range(-20..=19))]
+    self_nice: Option<i32>,
+
+    /// How to show timestamps in the log
+    #[arg(long, value_enum, default_value_t = logging::LogTimestamps::Rfc3339)]
+    log_timestamps: logging::LogTimestamps,
+
+    /// How often the spinner shown while waiting is redrawn, in milliseconds. Raise it on slow terminals, e.g. over high-latency SSH.
+    #[arg(long, value_name = "MS", default_value_t = 500, value_parser = clap::value_parser!(u64).range(1..))]
+    spinner_interval: u64,
+
+    /// The animation of the spinner shown while waiting.
+    #[arg(long, value_enum, default_value_t = logging::SpinnerStyle::Default)]
+    spinner_style: logging::SpinnerStyle,
+
+    /// Only consider these GPUs. Accepts a comma-separated list of indices, ranges and GPU UUIDs, like `nvidia-smi -i`. MIG UUIDs are rejected.
+    ///
+    /// For example, `0,2-3,GPU-5f8e...`.
+    #[arg(long, value_name = "SPEC")]
+    select: Option<String>,
+
+    /// Only consider the GPUs assigned to the current user ($USER) in the given affinity file.
+    ///
+    /// The file is a TOML table mapping user names to lists of GPU indices, where `*` applies to the other users,
+    /// e.g. `alice = [0, 1]`, `bob = [2, 3]` and `"*" = "any"` on separate lines. Combined with --select, only the GPUs
+    /// in both are considered.
+    #[arg(long, value_name = "PATH")]
+    gpu_affinity_file: Option<PathBuf>,
+
+    /// Only consider GPUs of these types, e.g. `A100,H100`, matched against the device names reported by NVML, which vary
+    /// between models (`NVIDIA A100-SXM4-80GB`, `NVIDIA A100 80GB PCIe`, ...). Combined with --select, only the GPUs in
+    /// both are considered.
+    ///
+    /// Known types: P100, V100, T4, A10, A30, A40, A100, A800, A6000, L4, L40, L40S, H100, H800, H200, GH200, RTX3090 and
+    /// RTX4090.
+    #[arg(
+        long,
+        value_name = "TYPES",
+        value_delimiter = ',',
+        value_parser = select::parse_gpu_type,
+        conflicts_with = "request_from_server"
+    )]
+    gpu_type_filter: Option<Vec<&'static str>>,
+
+    /// Only consider GPUs with at least the total memory of this tier: `sm` (any), `md` (8 GB), `lg` (24 GB) or `xl`
+    /// (48 GB), e.g. `lg` for any A100-class GPU. Combined with --select, only the GPUs in both are considered.
+    #[arg(long, value_enum, conflicts_with = "request_from_server")]
+    gpu_vram_tier: Option<select::VramTier>,
+
+    /// Ask a centralized allocation service for the GPUs, instead of looking for idle GPUs with NVML.
+    ///
+    /// The GPUs are requested with `POST <URL>/api/allocate`, and released with `POST <URL>/api/release` after the command exits.
+    /// They are not occupied, and NVML is not needed. Only `http://` is supported.
+    #[arg(
+        long,
+        value_name = "URL",
+        conflicts_with_all = ["select", "gpu_affinity_file", "kill_other_users"]
+    )]
+    request_from_server: Option<String>,
+
+    /// The priority sent to the allocation server with --request-from-server.
+    #[arg(long, default_value_t = 0, requires = "request_from_server")]
+    priority: i32,
+
+    /// Pick the GPUs randomly from the idle ones, instead of the ones with the lowest indices.
+    #[arg(long, default_value = "false")]
+    randomize_selection: bool,
+
+    /// Pick the idle GPUs spaced as far apart by index as possible (e.g. 0 and 4 on an 8-GPU node), instead of the ones with the lowest indices.
+    ///
+    /// Useful to spread the heat across the node.
+    #[arg(long, default_value = "false", conflicts_with = "randomize_selection")]
+    spread: bool,
+
+    /// Seed of all random decisions (--randomize-selection and --launch-jitter), for reproducibility. A random one is used and logged if not given.
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Give up querying a GPU after this many milliseconds and treat it as busy, in case the driver hangs.
+    ///
+    /// The GPU is then treated as busy without being queried until the hung query returns.
+    #[arg(long, value_name = "MS")]
+    timeout_per_gpu_check: Option<u64>,
+
+    /// Leave at least this many milliseconds between any two NVML calls while looking for idle GPUs,
+    /// so that NVML is not saturated on nodes with many GPUs.
+    #[arg(long, value_name = "MS")]
+    rate_limit_nvml: Option<u64>,
+
+    /// Poll the GPUs with this many threads instead of one after another, to shorten each poll on nodes with many GPUs.
+    #[arg(long, value_name = "N_THREADS", default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    parallel_nvml_queries: u16,
+
+    /// If querying the processes on a GPU fails, treat it as idle (with a warning) instead of exiting with the error.
+    ///
+    /// Useful when the NVML state of a single GPU is broken, e.g. during a firmware update.
+    #[arg(long, default_value = "false")]
+    assume_idle_after_error: bool,
+
+    /// Exit when a query times out with --timeout-per-gpu-check, instead of treating the GPU as busy.
+    ///
+    /// Failed NVML queries always exit, unless --assume-idle-after-error is given. Useful in pipelines that have their
+    /// own retry logic and want a hung driver to surface immediately.
+    #[arg(long, default_value = "false", requires = "timeout_per_gpu_check")]
+    fail_fast_on_nvml_timeout: bool,
+
+    /// Consider a GPU idle if it has at most this many compute processes, e.g. on platforms where an MPS daemon or a monitor is always running.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    process_count_threshold: u32,
+
+    /// Consider a GPU idle if its used memory is at most this many MiB, instead of checking the number of compute processes.
+    ///
+    /// This handles processes that have freed their memory but not exited yet. Around 100 MiB covers the overhead of a CUDA context.
+    #[arg(long, value_name = "MB")]
+    gpu_memory_threshold: Option<u64>,
+
+    /// How the used memory is measured for --gpu-memory-threshold: summed over the compute processes on the GPU,
+    /// or system-wide as in older versions, which also counts the memory not attributed to any process.
+    #[arg(long, value_enum, default_value_t = gpu::NvmlScope::Process)]
+    nvml_scope: gpu::NvmlScope,
+
+    /// Only select GPUs that have had no compute processes (see --process-count-threshold) continuously for this many seconds.
+    ///
+    /// Unlike the other criteria, which are checked on each poll, this avoids grabbing a GPU in a short gap between two jobs.
+    #[arg(long, value_name = "SECONDS")]
+    require_free_for: Option<u64>,
+
+    /// Poll NVML for this many seconds and discard the results before trusting them, for drivers that report stale
+    /// process counts right after initialization.
+    #[arg(long, value_name = "SECONDS", conflicts_with = "request_from_server")]
+    warm_nvml: Option<u64>,
+
+    /// Connect to this Unix socket and wait for an external coordinator to send a line `go` before looking for GPUs,
+    /// e.g. to stagger the jobs of a pipeline stage instead of letting them all grab GPUs at once.
+    #[arg(long, value_name = "SOCKET")]
+    wait_for_ipc: Option<PathBuf>,
+
+    /// Stop waiting and exit, as on Ctrl+C, once this file exists, for environments where sending signals is awkward,
+    /// e.g. some containers. It is checked on every poll and while waiting for the global lock, and removed once acted on.
+    #[arg(long, value_name = "PATH", conflicts_with = "request_from_server")]
+    cancel_file: Option<PathBuf>,
+
+    /// Do not wait: if there are not enough idle GPUs on the first check, exit immediately with code 75 without running the command.
+    ///
+    /// Useful as a gate in CI.
+    #[arg(long, default_value = "false")]
+    fail_fast_if_busy: bool,
+
+    /// Do not wait if more than this many other gpu-waiter instances sharing the claim file are already waiting:
+    /// exit immediately with code 75 without running the command.
+    #[arg(long, value_name = "N")]
+    fail_if_waiting_more_than: Option<usize>,
+
+    /// Write the result of each poll to stdout as a line of JSON, e.g. for plotting the availability over time.
+    ///
+    /// Not supported by `wait-for-gpus`, whose stdout is the list of GPUs.
+    #[arg(long, default_value = "false")]
+    json_poll_stream: bool,
+
+    /// Write the name, memory, temperature and UUID of the selected GPUs, as measured before occupying them, to the given file as JSON, together with the driver versions.
+    #[arg(long, value_name = "PATH", conflicts_with = "request_from_server")]
+    output_gpu_info_json: Option<PathBuf>,
+
+    /// Write bash lines exporting CUDA_VISIBLE_DEVICES, GPU_COUNT and GPU_WAITER_PID to the given file once the GPUs are
+    /// ready, e.g. `export CUDA_VISIBLE_DEVICES="0,1";`, for sourcing in scripts. It can be `/dev/stdout`.
+    #[arg(long, value_name = "PATH")]
+    output_bash_export: Option<PathBuf>,
+
+    /// Like --output-bash-export, in the syntax of fish, e.g. `set -x CUDA_VISIBLE_DEVICES "0,1";`.
+    #[arg(long, value_name = "PATH")]
+    output_fish_export: Option<PathBuf>,
+
+    /// Like --output-bash-export, in the syntax of csh and tcsh, e.g. `setenv CUDA_VISIBLE_DEVICES "0,1";`.
+    #[arg(long, value_name = "PATH")]
+    output_csh_export: Option<PathBuf>,
+
+    /// Render the template file IN to OUT once the GPUs are ready, replacing `{}` with the selected GPUs like in the
+    /// command, e.g. to generate a hostfile or a config fragment for the command.
+    ///
+    /// Literal braces in IN must be escaped, see --escape-style.
+    #[arg(long, num_args = 2, value_names = ["IN", "OUT"])]
+    render_template: Option<Vec<PathBuf>>,
+
+    /// Only select GPUs whose current SM clock is at least this value (in MHz), e.g. to avoid thermally throttled GPUs.
+    /// An idle GPU running at its idle clock is not skipped, unless its clocks are throttled for another reason too.
+    #[arg(long, value_name = "MHZ")]
+    min_sm_clock: Option<u32>,
+
+    /// Only select GPUs in persistence mode, to avoid the latency of loading the driver on first use.
+    #[arg(long, default_value = "false")]
+    require_persistence_mode: bool,
+
+    /// Skip the idle GPUs whose clocks are being throttled, e.g. by the power cap or for high temperature, logging the reasons.
+    #[arg(long, default_value = "false")]
+    avoid_throttled: bool,
+
+    /// After waiting for this many minutes without enough idle GPUs, also accept GPUs in use whose utilization is below --max-utilization.
+    #[arg(long, value_name = "THRESHOLD_MINUTES")]
+    fallback_to_any_gpu: Option<u64>,
+
+    /// Settle for this many GPUs (at least) if --num GPUs are still not available after --min-viable-wait.
+    #[arg(long, value_name = "M")]
+    minimum_viable_num: Option<NonZeroU32>,
+
+    /// How many minutes to wait for --num GPUs before settling for --minimum-viable-num.
+    #[arg(long, value_name = "MINUTES", default_value_t = 10)]
+    min_viable_wait: u64,
+
+    /// The utilization (in percent) below which a GPU in use is accepted, see --fallback-to-any-gpu.
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(0..=100))]
+    max_utilization: u32,
+
+    /// Keep the global lock held while the command is running, instead of releasing it right after occupying the GPUs.
+    ///
+    /// Note that this blocks all other gpu-waiter instances until the command exits.
+    #[arg(long, default_value = "false")]
+    hold_lock_during_run: bool,
+
+    /// Give up if the global lock cannot be taken within this many seconds, e.g. because another instance holds it with
+    /// --hold-lock-during-run, and exit with code 4. This is separate from waiting for idle GPUs, which is not limited.
+    #[arg(long, value_name = "SECONDS")]
+    lock_retry_budget: Option<u64>,
+
+    /// Replace gpu-waiter with the command via exec(2) once the GPUs are found, instead of running it as a child.
+    ///
+    /// Nothing of gpu-waiter is left while the command runs, so this needs --no-occupy, and the global lock is released
+    /// at once. The GPUs stay claimed against other instances for about a minute. Only supported on Unix.
+    #[arg(
+        long,
+        default_value = "false",
+        conflicts_with_all = [
+            "hold_lock_during_run", "hold_lock_until_ready", "pidfile", "gpu_idle_file", "release_delay",
+            "max_runtime", "app_clocks", "child_log_prefix", "timestamp_child_output",
+            "request_from_server"
+        ]
+    )]
+    exec: bool,
+
+    /// Keep the global lock held after starting the command, until it shows up as a compute process on every occupied GPU,
+    /// or for at most this many seconds, so that other gpu-waiter instances do not see the GPUs in between.
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        conflicts_with_all = ["hold_lock_during_run", "no_occupy", "dynamic_fraction"]
+    )]
+    hold_lock_until_ready: Option<u64>,
+
+    /// How many times to retry creating the CUDA context of a GPU (e.g. when it is transiently busy), before moving to another idle GPU or giving up.
+    #[arg(long, default_value_t = 3)]
+    cuda_init_retries: u32,
+
+    /// If the CUDA context of a GPU cannot be created, e.g. when NVML works but the CUDA driver is missing, size the
+    /// occupation from `nvidia-smi --query-gpu=memory.total,memory.free` and hold a host buffer of that size as a fake
+    /// occupation, instead of moving to another idle GPU or giving up.
+    ///
+    /// The buffer keeps gpu-waiter alive and the GPU recorded in the claim file, but takes no GPU memory, so users of
+    /// other tools may still grab the GPU. --dynamic-fraction and --occupation-fraction-ramp-up do not apply to it.
+    #[arg(long, default_value = "false", conflicts_with = "no_occupy")]
+    nvidia_smi_fallback: bool,
+
+    /// Before occupying each GPU, run a small CUDA kernel and verify its results, skipping the GPU if they are wrong,
+    /// e.g. due to memory corruption.
+    #[arg(long, default_value = "false", conflicts_with = "no_occupy")]
+    check_driver_health: bool,
+
+    /// How often (in milliseconds) to check whether the occupied GPUs are taken by other processes.
+    ///
+    /// If one is taken by a process that is not part of the command (or by any process with `hold`), gpu-waiter exits
+    /// with code 3 at the end. The processes of the command are only told apart in the PID namespace of the host.
+    #[arg(long, value_name = "MS", default_value_t = 100, value_parser = clap::value_parser!(u64).range(10..))]
+    monitor_interval: u64,
+
+    /// Only release an occupied GPU once another process is seen on it in this many consecutive checks, e.g. to ignore
+    /// processes that only touch the GPU briefly.
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    monitor_threshold: u32,
+
+    /// Do not occupy the selected GPUs with memory allocation. Only the file lock and CUDA_VISIBLE_DEVICES are managed.
+    ///
+    /// Useful when exclusive access is already enforced (e.g. by the compute mode), or when CUDA is not installed.
+    #[arg(long, default_value = "false")]
+    no_occupy: bool,
+
+    /// Before taking the selected GPUs, terminate the compute processes of other users on them, and kill the ones still running after --kill-grace-period.
+    ///
+    /// Only the processes allowed by --process-count-threshold and --gpu-memory-threshold can be on a selected GPU.
+    /// Requires root and --yes, and the PID namespace of the host, i.e. not in a container with its own.
+    /// Conflicts with --fallback-to-any-gpu, which would select GPUs that are in use.
+    #[arg(
+        long,
+        default_value = "false",
+        requires = "yes",
+        conflicts_with = "fallback_to_any_gpu"
+    )]
+    kill_other_users: bool,
+
+    /// How long to wait for the processes terminated by --kill-other-users to exit, before killing them.
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    kill_grace_period: u64,
+
+    /// Confirm a dangerous operation, e.g. --kill-other-users.
+    #[arg(long, default_value = "false")]
+    yes: bool,
+
+    /// The fraction of the free memory of each GPU to occupy.
+    #[arg(long, value_name = "FRACTION", default_value_t = 0.25, value_parser = occupy::parse_fraction)]
+    memory_fraction: f64,
+
+    /// Occupy this fraction of the total memory of each GPU instead, failing if there is not enough free memory.
+    ///
+    /// Useful for consistent reservations across GPUs with different loads.
+    #[arg(
+        long,
+        value_name = "FRACTION",
+        value_parser = occupy::parse_fraction,
+        conflicts_with_all = ["no_occupy", "memory_fraction", "occupation_fraction_ramp_up"]
+    )]
+    occupy_total_fraction: Option<f64>,
+
+    /// Start by occupying 5% of the free memory, and raise it by 5 percentage points every SECONDS/19 seconds until --memory-fraction is reached.
+    ///
+    /// This gives the command time to claim its own memory before the occupation is maximized.
+    #[arg(long, value_name = "SECONDS", conflicts_with = "no_occupy")]
+    occupation_fraction_ramp_up: Option<u64>,
+
+    /// Instead of releasing a GPU when it is used by others (e.g. the command), shrink the occupation to leave room for their memory usage plus 10%, and grow it back when they free memory.
+    ///
+    /// The occupation never exceeds the initial one.
+    #[arg(
+        long,
+        default_value = "false",
+        conflicts_with_all = ["no_occupy", "occupation_fraction_ramp_up"]
+    )]
+    dynamic_fraction: bool,
+
+    /// Scale the occupation of each selected GPU by a weight, in the order they are selected, e.g. `1,2` to occupy
+    /// half as much on the first GPU as on the second. The GPU with the largest weight gets the full --memory-fraction
+    /// (or --occupy-total-fraction).
+    ///
+    /// A spare GPU replacing one that could not be occupied takes over its weight. There must be one weight per GPU
+    /// of --num.
+    #[arg(
+        long,
+        value_name = "W1,W2,...",
+        value_delimiter = ',',
+        value_parser = occupy::parse_weight,
+        conflicts_with_all = ["no_occupy", "minimum_viable_num", "occupation_fraction_ramp_up"]
+    )]
+    occupy_weights: Option<Vec<f64>>,
+
+    /// With `hold`, when a held GPU is used by others, keep looking for another idle GPU and occupy it instead, so that the same number of GPUs stays held.
+    ///
+    /// With `hold --export`, the exported CUDA_VISIBLE_DEVICES is not updated.
+    #[arg(
+        long,
+        default_value = "false",
+        conflicts_with_all = ["no_occupy", "dynamic_fraction"]
+    )]
+    retry_on_preempt: bool,
+
+    /// Allocate the occupying memory on a dedicated CUDA stream with the lowest priority, instead of the default stream, so that it does not interfere with the command's work.
+    #[arg(long, default_value = "false", conflicts_with = "no_occupy")]
+    cudarc_stream: bool,
+
+    /// The order in which the selected GPUs are occupied.
+    #[arg(long, value_enum, default_value_t = occupy::OccupyOrder::Index, conflicts_with = "no_occupy")]
+    occupy_order: occupy::OccupyOrder,
+
+    /// The order of the GPU indices given to the command, in CUDA_VISIBLE_DEVICES and the `{}` template.
+    ///
+    /// CUDA numbers the visible devices in this order, e.g. with `CUDA_VISIBLE_DEVICES=3,1`, `cuda:0` is GPU 3. With
+    /// `group`, each command gets the next GPUs in this order.
+    #[arg(long, value_enum, default_value_t = select::IdOrder::AsSelected)]
+    id_order: select::IdOrder,
+
+    /// Make the first allocation on the given GPU fail, to test the error handling. Only in debug builds.
+    #[cfg(all(feature = "cuda", debug_assertions))]
+    #[arg(long, value_name = "INDEX", hide = true)]
+    inject_alloc_failure: Option<u32>,
+
+    /// Before occupying each GPU, reset its CUDA context like `cudaDeviceReset` and clear its NVML process accounting (which needs root),
+    /// for a clean starting state at the cost of some latency.
+    #[arg(long, default_value = "false", conflicts_with = "no_occupy")]
+    clear_cuda_cache: bool,
+
+    /// Occupy the GPUs without zeroing the allocated memory, so that the command starts sooner on large GPUs.
+    #[arg(long, default_value = "false", conflicts_with = "no_occupy")]
+    lazy_occupy: bool,
+
+    /// After occupying each GPU, make sure the occupation is visible in NVML before releasing the global lock, so that other instances do not see the GPU as idle.
+    #[arg(long, default_value = "false", conflicts_with = "no_occupy")]
+    occupancy_check: bool,
+
+    /// Set the application clocks of the selected GPUs to MEM,SM (in MHz) before running the command, e.g. `1215,1410`,
+    /// and reset them when gpu-waiter exits. Useful for consistent benchmarks.
+    ///
+    /// Setting them usually needs root privileges; the GPUs where it fails are left as is with a warning.
+    #[arg(long, value_name = "MEM,SM", value_parser = gpu::parse_app_clocks, conflicts_with = "request_from_server")]
+    app_clocks: Option<(u32, u32)>,
+
+    /// Keep the GPUs occupied for this many seconds after the command exits, so that they are not grabbed by others before the command's memory is returned.
+    #[arg(long, value_name = "SECONDS", conflicts_with = "no_occupy")]
+    release_delay: Option<u64>,
+
+    /// Lower the CPU priority of the command and its child processes (to niceness 10) once the free memory of any
+    /// selected GPU drops below this many MiB. It is not raised again.
+    ///
+    /// Checked every --monitor-interval. Unix only.
+    #[arg(
+        long,
+        value_name = "THRESHOLD_MB",
+        conflicts_with = "request_from_server"
+    )]
+    renice_on_memory_pressure: Option<u64>,
+
+    /// After occupying the GPUs, wait a random time of up to this many seconds before launching the command, so that
+    /// jobs started together on many nodes do not hit the shared storage at once. Ignored by `hold`.
+    #[arg(long, value_name = "MAX_SECONDS")]
+    launch_jitter: Option<u64>,
+
+    /// Terminate the command once it has been running for this long (e.g. "12h", "2days"), and release the GPUs.
+    ///
+    /// With `hold`, this is how long the GPUs are held.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    max_runtime: Option<Duration>,
+
+    /// Where to keep the state shared between the gpu-waiter instances, e.g. the waiting ones, defaulting to a file next to the global lock.
+    ///
+    /// It can be put on a shared file system, e.g. NFS, to see the instances on other machines.
+    #[arg(long, value_name = "PATH")]
+    claim_file: Option<PathBuf>,
+
+    /// Coordinate only with the gpu-waiter instances using the same group ID, e.g. to share some GPUs within a lab.
+    ///
+    /// The global lock and the default claim file are named after it, e.g. `gpu-waiter.<GROUP_ID>.lock`, so other groups
+    /// and the instances without a group neither wait for it nor see its claims.
+    #[arg(long, value_name = "GROUP_ID", value_parser = lock::parse_group_id)]
+    share_group: Option<String>,
+
+    /// How the global lock and the claim file are locked. `lockf` uses POSIX `fcntl` locks, which work on network file systems
+    /// where `flock` is unreliable, e.g. NFS. Only supported on Unix.
+    #[arg(long, value_enum, default_value_t = lock::LockType::Flock)]
+    lock_type: lock::LockType,
+
+    /// Make the global lock file and the claim file only accessible to the members of this Unix group (mode 0660),
+    /// instead of everyone.
+    ///
+    /// It only takes effect on a file of our own, e.g. created by this run; a file created by someone else is left as is.
+    #[arg(long, value_name = "GROUP")]
+    lock_unix_group: Option<String>,
+
+    /// Write the PID of the command to this file once it is started, and remove the file when it exits.
+    #[arg(long, value_name = "PATH")]
+    pidfile: Option<PathBuf>,
+
+    /// Create this file, holding the comma-separated indices of the selected GPUs, once they are occupied, and remove it when gpu-waiter exits.
+    ///
+    /// Useful for scripts waiting for `hold` in the background, e.g. `while [ ! -f /tmp/gpus_ready ]; do sleep 1; done`.
+    #[arg(long, value_name = "PATH")]
+    gpu_idle_file: Option<PathBuf>,
+
     /// An external command to run. If {} is present in the command, it will be replaced with the ids of the GPUs and CUDA_VISIBLE_DEVICES will NOT be set.
     ///
     /// For example, `gpu-waiter --num 2 deepspeed --include localhost:{}` could run `deepspeed --include localhost:1,3`.
@@ -55,159 +593,1240 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
+    /// Measure the latency of NVML idle queries and of occupying each idle GPU, then exit.
+    Bench {
+        /// How many rounds of NVML idle queries to time
+        #[arg(long, default_value_t = 10)]
+        rounds: u32,
+
+        /// Size of the sample allocation on each GPU, in MiB
+        #[arg(long, default_value_t = 256)]
+        sample_mb: usize,
+    },
+    /// Wait for idle GPUs and occupy them without running any command, until Ctrl+C is pressed.
+    ///
+    /// Exits with code 3 if any of the held GPUs was used by another process in the meantime.
+    Hold {
+        /// Keep holding the GPUs in the background, and print the lines exporting CUDA_VISIBLE_DEVICES and GPU_WAITER_PID to stdout.
+        ///
+        /// For example, `eval $(gpu-waiter -n 2 hold --export)`. Release the GPUs later with `kill $GPU_WAITER_PID`.
+        #[arg(long, default_value = "false")]
+        export: bool,
+    },
+    /// Wait for idle GPUs, occupy them, run a stress kernel on them and report the utilization achieved, then release them.
+    ///
+    /// Useful to validate a node and gpu-waiter together, e.g. `gpu-waiter -n 2 selftest --duration 30`.
+    Selftest {
+        /// How long to run the stress kernel, in seconds
+        #[arg(long, default_value_t = 30)]
+        duration: u64,
+    },
+    /// Wait until the gpu-waiter process with the given PID on this machine releases its claim, i.e. neither waits for nor holds GPUs, then exit.
+    ///
+    /// Useful for chaining jobs, e.g. `gpu-waiter wait-for-pid 1234 && gpu-waiter python next.py`.
+    WaitForPid {
+        pid: u32,
+
+        /// Give up and exit with an error after this long.
+        #[arg(long, value_name = "SECONDS")]
+        timeout: Option<u64>,
+    },
+    /// Wait for idle GPUs, print their indices to stdout, then exit without occupying them or running any command.
+    ///
+    /// For example, `CUDA_VISIBLE_DEVICES=$(gpu-waiter -n 2 wait-for-gpus) python train.py`. Nothing stops another
+    /// process from taking the GPUs in the meantime, use `hold --export` or `reserve` for that.
+    WaitForGpus {
+        /// How to print the indices: `csv` (1,3), `space` (1 3) or `json` ([1,3])
+        #[arg(long, value_enum, default_value_t = report::IndexFormat::Csv)]
+        format: report::IndexFormat,
+    },
+    /// Reserve GPUs under a name, held by a background process until `gpu-waiter release --name` is run.
+    ///
+    /// Like `hold --export`, the lines exporting CUDA_VISIBLE_DEVICES and GPU_WAITER_PID are printed once the GPUs are held.
+    Reserve {
+        /// The name of the reservation, unique on this machine
+        #[arg(long)]
+        name: String,
+    },
+    /// Release the GPUs reserved by `gpu-waiter reserve --name`, then exit.
+    Release {
+        /// The name of the reservation
+        #[arg(long)]
+        name: String,
+    },
+    /// Run several commands at once, each on its own share of the selected GPUs, and wait for all of them to exit.
+    ///
+    /// Each command is given as `--group N COMMAND...`, separated by `--`, and gets the next N of the selected GPUs.
+    /// Only a `--` followed by `--group` starts the next command, so the commands may contain `--` themselves.
+    /// For example, `gpu-waiter -n 4 group --group 2 python a.py -- --group 2 python b.py`. The sizes must add up to --num.
+    Group {
+        #[arg(
+            value_name = "--group N COMMAND",
+            required = true,
+            trailing_var_arg = true,
+            allow_hyphen_values = true
+        )]
+        groups: Vec<OsString>,
+    },
     #[command(external_subcommand)]
     External(Vec<OsString>),
 }
 
 static NVML: OnceCell<Nvml> = OnceCell::new();
 
-fn get_idle_gpu() -> anyhow::Result<Vec<u32>> {
-    let nvml = NVML.wait();
-    let device_count = nvml.device_count()?;
-    let mut result = Vec::with_capacity(device_count as usize);
-    for i in 0..device_count {
-        let device = nvml.device_by_index(i)?;
-        if device.running_compute_processes_count()? == 0 {
-            result.push(i);
+/// Take the global lock, giving up after `budget` seconds if given, see --lock-retry-budget, or once `cancel_file`
+/// exists, see --cancel-file.
+///
+/// `heartbeat` is called every [`claims::HEARTBEAT_INTERVAL`] meanwhile, so that our entry in the claim file does not go
+/// stale while another instance holds the lock for long, e.g. with --hold-lock-during-run.
+fn lock_global<'a>(
+    file_lock: &'a lock::FileRWLock,
+    budget: Option<u64>,
+    cancel_file: Option<&Path>,
+    mut heartbeat: impl FnMut(),
+) -> anyhow::Result<Option<lock::RWLockWriteGuard<'a>>> {
+    let mut next_heartbeat = Instant::now() + claims::HEARTBEAT_INTERVAL;
+    Ok(
+        file_lock.write_cancellable(budget.map(Duration::from_secs), || {
+            if Instant::now() >= next_heartbeat {
+                heartbeat();
+                next_heartbeat += claims::HEARTBEAT_INTERVAL;
+            }
+            cancel_file.is_some_and(Path::exists)
+        })?,
+    )
+}
+
+/// Stop waiting because the --cancel-file exists, and remove it, so that it does not cancel the next run too.
+fn cancel_by_file(path: &Path) {
+    info!("{} exists, exiting...", path.display());
+    STOPPED.store(true, std::sync::atomic::Ordering::Relaxed);
+    if let Err(err) = std::fs::remove_file(path) {
+        warn!(
+            "Failed to remove the cancel file {}: {}",
+            path.display(),
+            err
+        );
+    }
+}
+
+/// How CUDA_VISIBLE_DEVICES is passed to a command.
+#[derive(Debug, PartialEq, Eq)]
+enum CudaEnv {
+    /// Set to the selected GPUs
+    Set,
+    /// Set to the selected GPUs despite the template, see --force-env
+    Forced,
+    /// Removed, even if inherited, see --no-cuda-env
+    Removed,
+    /// Left as inherited, since the template passes the GPUs instead
+    Inherited,
+}
+
+impl CudaEnv {
+    fn of(has_template: bool, force_env: bool, no_cuda_env: bool) -> Self {
+        // --no-cuda-env (which conflicts with --force-env) comes first, then --force-env, then the template
+        match (has_template, force_env, no_cuda_env) {
+            (_, _, true) => CudaEnv::Removed,
+            (false, _, _) => CudaEnv::Set,
+            (true, true, _) => CudaEnv::Forced,
+            (true, false, _) => CudaEnv::Inherited,
         }
     }
-    Ok(result)
+}
+
+/// Why --force-env changes nothing for these commands, if it does not.
+fn force_env_without_effect(jobs: &[group::Job]) -> Option<&'static str> {
+    if jobs.is_empty() {
+        Some("since there is no command")
+    } else if !jobs.iter().any(|job| job.has_template) {
+        Some("since CUDA_VISIBLE_DEVICES is set anyway for a command without template \"{}\"")
+    } else {
+        None
+    }
+}
+
+/// How the occupied GPUs are watched for other processes.
+#[derive(Clone, Copy)]
+struct MonitorConfig {
+    /// See --monitor-interval
+    interval: Duration,
+    /// How many consecutive checks must see another process before the GPU is released, see --monitor-threshold
+    threshold: u32,
+}
+
+/// The outcome of occupying a single GPU.
+#[cfg(feature = "cuda")]
+enum Occupation {
+    Occupied,
+    /// Its CUDA context could not be created, see --nvidia-smi-fallback.
+    NoContext(anyhow::Error),
+    /// The memory occupying it could not be allocated.
+    NoMemory(anyhow::Error),
+    /// It should not be used, for the reason already logged.
+    GivenUp,
+}
+
+/// Check whether another process has started using an occupied GPU.
+///
+/// `expected` is the number of compute processes expected while we occupy the GPU, including our own if any.
+fn is_used_by_others(index: u32, expected: u32) -> anyhow::Result<bool> {
+    let nvml_dev = NVML.wait().device_by_index(index)?;
+    Ok(nvml_dev.running_compute_processes_count()? > expected)
+}
+
+/// The idle GPUs that may replace a preempted one, i.e. neither claimed by others nor already held, see
+/// --retry-on-preempt.
+#[cfg(feature = "cuda")]
+fn replacement_candidates(idle: Vec<u32>, claimed: &[u32], held: &[u32]) -> Vec<u32> {
+    idle.into_iter()
+        .filter(|i| !claimed.contains(i) && !held.contains(i))
+        .collect()
+}
+
+/// The exit code with --fail-fast-if-busy when there are not enough idle GPUs, i.e. `EX_TEMPFAIL` of sysexits.
+const BUSY_EXIT_CODE: i32 = 75;
+
+/// The exit code when any of the occupied GPUs was used by another process during the run, even if it was replaced
+/// (see --retry-on-preempt), see [`is_preempted`].
+const PREEMPTED_EXIT_CODE: i32 = 3;
+
+/// Whether a GPU that the monitor found used by another process was preempted.
+///
+/// When holding, any other process is. With a command, the command itself showing up is what the monitor waits for,
+/// so only the processes that are neither gpu-waiter nor the command count, as given by `foreign`.
+fn is_preempted(
+    index: u32,
+    hold: bool,
+    foreign: impl FnOnce() -> anyhow::Result<Vec<u32>>,
+) -> bool {
+    if hold {
+        return true;
+    }
+    match foreign() {
+        Ok(pids) if pids.is_empty() => false,
+        Ok(pids) => {
+            warn!(
+                "GPU {} is also used by processes {:?}, which are not part of the command",
+                index, pids
+            );
+            true
+        }
+        Err(err) => {
+            warn!(
+                "Failed to check the processes on GPU {} for preemption: {}",
+                index, err
+            );
+            false
+        }
+    }
+}
+
+/// The niceness of the command with --renice-on-memory-pressure, the default increment of nice(1).
+const MEMORY_PRESSURE_NICENESS: i32 = 10;
+
+/// The exit code when the global lock cannot be taken within --lock-retry-budget, to tell it apart from the GPUs being busy.
+const LOCK_TIMEOUT_EXIT_CODE: i32 = 4;
+
+/// How long to wait for the command to exit after asking it to terminate, before killing it.
+const TERMINATE_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Whether `hold` is over after another process took one of the held GPUs, i.e. once none is held any more.
+///
+/// With --no-occupy nothing is held in the first place, so it only ends with Ctrl+C or --max-runtime.
+fn hold_is_over(no_occupy: bool, nothing_held: bool) -> bool {
+    !no_occupy && nothing_held
 }
 
 static STOPPED: AtomicBool = AtomicBool::new(false);
 
+/// How many bytes to occupy on GPU `index`, i.e. `fraction` of its free memory, or `total_fraction` of its total memory
+/// with --occupy-total-fraction.
+///
+/// `residual` is the memory still held there by our own processes, see [`process::own_residual_memory`]. It is not
+/// free already, but the command will need it again while they exit, so at least that much is left free.
+#[cfg(feature = "cuda")]
+fn occupation_bytes(
+    index: u32,
+    total: u64,
+    free: u64,
+    residual: u64,
+    fraction: f64,
+    total_fraction: Option<f64>,
+) -> anyhow::Result<usize> {
+    let bytes = match total_fraction {
+        Some(total_fraction) => {
+            let bytes = (total as f64 * total_fraction) as u64;
+            if bytes > free {
+                anyhow::bail!(
+                    "Cannot occupy {} MiB ({}% of the total memory) on GPU {}, only {} MiB is free",
+                    bytes >> 20,
+                    total_fraction * 100.0,
+                    index,
+                    free >> 20
+                );
+            }
+            bytes
+        }
+        None => (free as f64 * fraction) as u64,
+    };
+    occupy::checked_bytes(bytes.min(free.saturating_sub(residual)))
+}
+
+/// Sleep for `duration`, or until Ctrl+C. Returns whether the whole duration has passed.
+///
+/// `heartbeat` is called every [`claims::HEARTBEAT_INTERVAL`] meanwhile, so that a long sleep does not make the held
+/// GPUs look released to the other instances.
+fn sleep_unless_stopped(duration: Duration, mut heartbeat: impl FnMut()) -> bool {
+    let deadline = Instant::now() + duration;
+    let mut next_heartbeat = Instant::now() + claims::HEARTBEAT_INTERVAL;
+    while !STOPPED.load(std::sync::atomic::Ordering::Relaxed) {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return true;
+        }
+        if Instant::now() >= next_heartbeat {
+            heartbeat();
+            next_heartbeat += claims::HEARTBEAT_INTERVAL;
+        }
+        thread::sleep(remaining.min(Duration::from_millis(200)));
+    }
+    false
+}
+
+/// A uniformly random delay of up to `max_secs` seconds, see --launch-jitter.
+fn launch_jitter(rng: &mut impl Rng, max_secs: u64) -> Duration {
+    Duration::from_secs_f64(rng.gen_range(0.0..=max_secs as f64))
+}
+
+/// Write `contents` of the file `template` to `out`, with `{}` replaced by the selected GPUs, see --render-template.
+///
+/// Unlike in the command, the whitespace is kept as is.
+fn render_template_file(
+    template: &Path,
+    contents: &str,
+    out: &Path,
+    gpu_list: &str,
+    style: command::EscapeStyle,
+) -> anyhow::Result<()> {
+    let result = command::process_command_template(
+        contents,
+        gpu_list,
+        style,
+        command::NormalizeWhitespace(false),
+    )?;
+    for warning in result.warnings {
+        warn!("{:?}: {}", template, warning);
+    }
+    std::fs::write(out, result.command)
+        .with_context(|| format!("Failed to write the rendered template to {:?}", out))
+}
+
+/// Call `poll` about once a second for `duration`, or until Ctrl+C, and at least once. Returns how many times it was
+/// called. See --warm-nvml.
+fn warm_up(
+    duration: Duration,
+    mut poll: impl FnMut() -> anyhow::Result<()>,
+) -> anyhow::Result<usize> {
+    let deadline = Instant::now() + duration;
+    let mut polls = 0;
+    loop {
+        poll()?;
+        polls += 1;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || STOPPED.load(std::sync::atomic::Ordering::Relaxed) {
+            return Ok(polls);
+        }
+        thread::sleep(remaining.min(Duration::from_secs(1)));
+    }
+}
+
 fn main() -> anyhow::Result<()> {
-    let logger =
-        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).build();
+    let args = Cli::parse();
+
+    let logger = logging::build_logger(args.log_timestamps);
     let multi = MultiProgress::new();
     LogWrapper::new(multi.clone(), logger).try_init()?;
+    if args.perf_mode {
+        log::set_max_level(log::LevelFilter::Off);
+    }
 
     if let Err(err) = ctrlc::set_handler(move || {
-        info!("Ctrl+C received, exiting...");
+        info!("Ctrl+C or termination signal received, exiting...");
         STOPPED.store(true, std::sync::atomic::Ordering::Relaxed);
     }) {
         warn!("Failed to set Ctrl+C handler: {}", err)
     }
+    let launcher = match args.self_nice {
+        Some(nice) => {
+            // created before the main thread is reniced, see `Launcher`
+            let launcher = process::Launcher::start();
+            if let Err(e) = process::set_thread_niceness(nice) {
+                anyhow::bail!(
+                    "Failed to set the niceness of gpu-waiter to {}: {}",
+                    nice,
+                    e
+                );
+            }
+            launcher
+        }
+        None => process::Launcher::direct(),
+    };
+
+    let reservation = match &args.command {
+        Commands::Reserve { name } => Some(name.clone()),
+        _ => None,
+    };
+    let export = matches!(args.command, Commands::Hold { export: true }) || reservation.is_some();
+    if export && !background::is_background() {
+        if let Some(name) = &reservation {
+            let claim_file = claims::ClaimFile::new(
+                args.claim_file
+                    .unwrap_or_else(|| claims::default_path(args.share_group.as_deref())),
+                args.lock_type,
+                args.lock_unix_group.as_deref(),
+            )?;
+            if let Some(pid) = claim_file.update(|claims| claims.reserved_by(name))? {
+                anyhow::bail!(
+                    "The reservation {} is already held by process {}",
+                    name,
+                    pid
+                );
+            }
+        }
+        return background::spawn();
+    }
+    if let Commands::Release { name } = &args.command {
+        let claim_file = claims::ClaimFile::new(
+            args.claim_file
+                .unwrap_or_else(|| claims::default_path(args.share_group.as_deref())),
+            args.lock_type,
+            args.lock_unix_group.as_deref(),
+        )?;
+        let Some(pid) = claim_file.update(|claims| claims.reserved_by(name))? else {
+            anyhow::bail!("No reservation named {} on this machine", name);
+        };
+        info!("Releasing the reservation {} held by process {}", name, pid);
+        process::terminate(pid)?;
+        if claims::wait_for_release(&claim_file, pid, Some(TERMINATE_GRACE_PERIOD)).is_err() {
+            warn!(
+                "Process {} is still running {:?} after being asked to terminate, killing it",
+                pid, TERMINATE_GRACE_PERIOD
+            );
+            process::kill(pid)?;
+        }
+        return Ok(());
+    }
+    if let Commands::WaitForPid { pid, timeout } = args.command {
+        // only the claim file is needed, not NVML
+        let claim_file = claims::ClaimFile::new(
+            args.claim_file
+                .unwrap_or_else(|| claims::default_path(args.share_group.as_deref())),
+            args.lock_type,
+            args.lock_unix_group.as_deref(),
+        )?;
+        return claims::wait_for_release(&claim_file, pid, timeout.map(Duration::from_secs));
+    }
 
     if std::env::var("CUDA_VISIBLE_DEVICES").is_ok() {
         warn!("CUDA_VISIBLE_DEVICES is already set, which will be ignored");
         std::env::remove_var("CUDA_VISIBLE_DEVICES");
     }
-    NVML.get_or_try_init(|| {
-        Nvml::builder()
-            .lib_path("libnvidia-ml.so.1".as_ref())
-            .init()
-    })?;
+    let server = args
+        .request_from_server
+        .as_deref()
+        .map(remote::Server::new)
+        .transpose()?;
+    // with an allocation server, the GPUs may not even be visible to NVML here
+    let mut driver_versions = None;
+    let device_count = if server.is_some() {
+        args.num.get()
+    } else {
+        NVML.get_or_try_init(|| {
+            Nvml::builder()
+                .lib_path("libnvidia-ml.so.1".as_ref())
+                .init()
+        })?;
+        match gpu::driver_versions() {
+            Ok(versions) => {
+                info!(
+                    "NVIDIA driver version: {}, CUDA driver version: {}.{}",
+                    versions.driver, versions.cuda.0, versions.cuda.1
+                );
+                driver_versions = Some(versions);
+            }
+            Err(err) => warn!("Failed to get the driver versions: {}", err),
+        }
 
-    let args = Cli::parse();
-    let device_count = NVML.wait().device_count()?;
-    if args.num.get() > device_count {
-        return Err(anyhow::anyhow!(
-            "Requested {} devices, but there are only {} devices in total",
-            args.num,
-            device_count
-        ));
+        let device_count = NVML.wait().device_count()?;
+        if args.num.get() > device_count {
+            return Err(anyhow::anyhow!(
+                "Requested {} devices, but there are only {} devices in total",
+                args.num,
+                device_count
+            ));
+        }
+        device_count
+    };
+
+    if let Some(m) = args.minimum_viable_num {
+        if m > args.num {
+            anyhow::bail!(
+                "--minimum-viable-num ({}) must not exceed --num ({})",
+                m,
+                args.num
+            );
+        }
     }
 
-    // prevalidate the command
-    let Commands::External(cmds) = args.command;
-    let mut preprocess_cmd: Vec<Either<OsString, String>> = Vec::with_capacity(cmds.len());
-    let mut has_template = false;
-    for arg in cmds {
-        if let Some(arg) = arg.to_str() {
-            let result = command::process_command_template(arg, "")?;
-            if result.template_count > 0 {
-                if !has_template {
-                    info!("The command contains template \"{{}}\", so CUDA_VISIBLE_DEVICES will NOT be set");
+    let mut devices = args
+        .select
+        .as_deref()
+        .map(|spec| select::resolve(&select::parse_spec(spec)?))
+        .transpose()?;
+    if let Some(path) = &args.gpu_affinity_file {
+        if let Some(assigned) = affinity::load(path)? {
+            if let Some(i) = assigned.iter().find(|i| **i >= device_count) {
+                anyhow::bail!(
+                    "Device index {} in the GPU affinity file is out of range, there are only {} devices",
+                    i,
+                    device_count
+                );
+            }
+            devices = select::restrict(devices, assigned);
+        }
+    }
+    if let Some(types) = &args.gpu_type_filter {
+        devices = select::restrict(devices, select::devices_of_types(types)?);
+    }
+    if let Some(tier) = args.gpu_vram_tier {
+        devices = select::restrict(devices, select::devices_with_memory(tier.min_bytes())?);
+    }
+    if let Some(devices) = &devices {
+        if (devices.len() as u32) < args.num.get() {
+            anyhow::bail!(
+                "Requested {} devices, but only {} devices are selected",
+                args.num,
+                devices.len()
+            );
+        }
+    }
+    let criteria = gpu::IdleCriteria {
+        devices,
+        process_count_threshold: args.process_count_threshold,
+        memory_threshold: args.gpu_memory_threshold.map(|mb| mb << 20),
+        nvml_scope: args.nvml_scope,
+        track_free: args.require_free_for.is_some(),
+        min_sm_clock: args.min_sm_clock,
+        require_persistence_mode: args.require_persistence_mode,
+        avoid_throttled: args.avoid_throttled,
+        check_timeout: args.timeout_per_gpu_check.map(Duration::from_millis),
+        assume_idle_after_error: args.assume_idle_after_error,
+        fail_on_timeout: args.fail_fast_on_nvml_timeout,
+        rate_limiter: args
+            .rate_limit_nvml
+            .map(|ms| gpu::RateLimiter::new(Duration::from_millis(ms))),
+        query_threads: args.parallel_nvml_queries as usize,
+    };
+
+    let no_occupy = args.no_occupy || server.is_some() || cfg!(not(feature = "cuda"));
+    if cfg!(not(feature = "cuda")) && !args.no_occupy {
+        warn!("Built without the `cuda` feature, so the GPUs will NOT be occupied (as if --no-occupy is given)");
+    }
+    if args.kill_other_users && !process::is_root() {
+        anyhow::bail!("--kill-other-users requires root");
+    }
+    #[cfg(all(feature = "cuda", debug_assertions))]
+    if let Some(index) = args.inject_alloc_failure {
+        warn!("The first allocation on GPU {} will fail on purpose", index);
+        *occupy::INJECT_ALLOC_FAILURE.lock() = Some(index);
+    }
+    if args.hold_lock_during_run {
+        warn!("The global lock will be held while the command is running, so all other gpu-waiter instances will be blocked until it exits");
+    }
+
+    // init global file lock
+    let lock_name = lock::shared_file_name(args.share_group.as_deref(), "lock");
+    let file_lock = match &args.lock_unix_group {
+        Some(group) => lock::FileRWLock::new_shared(lock_name, group, args.lock_type)?,
+        None => lock::FileRWLock::new(lock_name, args.lock_type)?,
+    };
+    let claim_file = claims::ClaimFile::new(
+        args.claim_file
+            .clone()
+            .unwrap_or_else(|| claims::default_path(args.share_group.as_deref())),
+        args.lock_type,
+        args.lock_unix_group.as_deref(),
+    )?;
+
+    let selftest = match args.command {
+        Commands::Selftest { .. } if cfg!(not(feature = "cuda")) || server.is_some() => {
+            anyhow::bail!(
+                "The selftest needs CUDA, i.e. the `cuda` feature and no --request-from-server"
+            );
+        }
+        Commands::Selftest { duration } => Some(Duration::from_secs(duration)),
+        _ => None,
+    };
+    // the GPUs are only printed, see `wait-for-gpus`
+    let index_format = match args.command {
+        Commands::WaitForGpus { .. } if args.json_poll_stream => {
+            anyhow::bail!("--json-poll-stream cannot be used with `wait-for-gpus`, which prints the GPUs to stdout");
+        }
+        Commands::WaitForGpus { format } => Some(format),
+        _ => None,
+    };
+    let cmds = match args.command {
+        Commands::Bench { .. } if server.is_some() => {
+            anyhow::bail!("Cannot benchmark the GPUs assigned by an allocation server");
+        }
+        Commands::Bench { rounds, sample_mb } => {
+            let report = bench::run(&file_lock, &criteria, rounds, sample_mb << 20)?;
+            report.print();
+            return Ok(());
+        }
+        Commands::Hold { .. }
+        | Commands::Reserve { .. }
+        | Commands::Selftest { .. }
+        | Commands::WaitForGpus { .. } => None,
+        Commands::WaitForPid { .. } | Commands::Release { .. } => {
+            unreachable!("handled before initializing NVML")
+        }
+        Commands::External(cmds) => Some(vec![(None, cmds)]),
+        Commands::Group { groups } => {
+            let groups = group::parse(&groups)?;
+            let total: usize = groups.iter().map(|group| group.size).sum();
+            if total != args.num.get() as usize {
+                anyhow::bail!(
+                    "The groups need {} GPUs in total, but --num is {}",
+                    total,
+                    args.num
+                );
+            }
+            if args.minimum_viable_num.is_some() || args.pidfile.is_some() {
+                anyhow::bail!("--minimum-viable-num and --pidfile cannot be used with `group`");
+            }
+            Some(
+                groups
+                    .into_iter()
+                    .map(|group| (Some(group.size), group.command))
+                    .collect(),
+            )
+        }
+    };
+    let hold = cmds.is_none();
+    if args.exec {
+        if cfg!(not(unix)) {
+            anyhow::bail!("--exec is only supported on Unix");
+        }
+        if !no_occupy {
+            anyhow::bail!("--exec needs --no-occupy, since the occupation is gone once gpu-waiter is replaced");
+        }
+        if cmds.as_ref().is_none_or(|cmds| cmds.len() > 1) {
+            anyhow::bail!("--exec needs a single command, not `hold` or `group`");
+        }
+    }
+    if args.retry_on_preempt && !hold {
+        warn!("--retry-on-preempt only applies to `hold`, ignoring it");
+    }
+
+    if let Some(weights) = &args.occupy_weights {
+        if weights.len() != args.num.get() as usize {
+            anyhow::bail!(
+                "--occupy-weights has {} weights, but {} GPUs are requested",
+                weights.len(),
+                args.num
+            );
+        }
+    }
+
+    // read before waiting, so that a missing template is reported right away
+    let render_template = match args.render_template.as_deref() {
+        Some([template, out]) => Some((
+            template,
+            out,
+            std::fs::read_to_string(template)
+                .with_context(|| format!("Failed to read the template {:?}", template))?,
+        )),
+        _ => None,
+    };
+
+    // prevalidate the commands
+    let mut jobs = vec![];
+    // the same warning may come up again when the command is processed for real
+    let mut template_warnings = HashSet::new();
+    for (size, cmds) in cmds.unwrap_or_default() {
+        let mut preprocess_cmd: Vec<Either<OsString, String>> = Vec::with_capacity(cmds.len());
+        let mut has_template = false;
+        for arg in cmds {
+            if let Some(arg) = arg.to_str() {
+                let result = command::process_command_template(
+                    arg,
+                    "",
+                    args.escape_style,
+                    command::NormalizeWhitespace(args.normalize_whitespace),
+                )?;
+                for warning in result.warnings {
+                    if template_warnings.insert(warning.clone()) {
+                        warn!("{}", warning);
+                    }
+                }
+                if result.template_count > 0 {
+                    if !has_template && !args.force_env && !args.no_cuda_env {
+                        info!("The command contains template \"{{}}\", so CUDA_VISIBLE_DEVICES will NOT be set");
+                    }
+                    has_template = true;
                 }
-                has_template = true;
+                preprocess_cmd.push(Either::Right(arg.to_string()));
+            } else {
+                warn!("Failed to parse the argument you passed in: \"{:?}\", most likely it contains invalid UTF-8 characters. This argument will be ignored for inserting GPU ids.", arg);
+                preprocess_cmd.push(Either::Left(arg));
             }
-            preprocess_cmd.push(Either::Right(arg.to_string()));
-        } else {
-            warn!("Failed to parse the argument you passed in: \"{:?}\", most likely it contains invalid UTF-8 characters. This argument will be ignored for inserting GPU ids.", arg);
-            preprocess_cmd.push(Either::Left(arg));
+        }
+        jobs.push(group::Job {
+            size,
+            args: preprocess_cmd,
+            has_template,
+        });
+    }
+    if args.force_env {
+        if let Some(reason) = force_env_without_effect(&jobs) {
+            warn!("--force-env has no effect, {}", reason);
         }
     }
 
     // start waiting
-    info!(
-        "Start waiting at {}",
-        chrono::Local::now().format("%H:%M:%S")
-    );
+    info!("Start waiting at {}", args.log_timestamps.format_now());
     // show a spinner for polling
-    let spinner = multi.add(indicatif::ProgressBar::new_spinner());
-    spinner.set_message("Waiting for idle GPUs...");
-    spinner.enable_steady_tick(Duration::from_millis(500));
+    let spinner = if args.perf_mode {
+        indicatif::ProgressBar::hidden()
+    } else {
+        let spinner = multi.add(indicatif::ProgressBar::new_spinner());
+        args.spinner_style
+            .start(&spinner, Duration::from_millis(args.spinner_interval));
+        spinner
+    };
+    let mut cached_devices = if args.perf_mode && server.is_none() {
+        Some(gpu::get_all_devices()?)
+    } else {
+        None
+    };
+    let mut devices_cached_at = Instant::now();
+    let cache_ttl = Duration::from_secs(args.gpu_info_cache_ttl);
     let mut idle_gpu = None;
-    // init global file lock
-    let file_lock = lock::FileRWLock::new("gpu-waiter.lock")?;
     let mut lock_guard = None;
+    let mut last_skipped = vec![];
+    let mut free_streaks = gpu::FreeStreaks::default();
+    let mut last_waiters = None;
+    let mut busy = false;
+    let mut lock_timed_out = false;
+    let require_free_for = args.require_free_for.map(Duration::from_secs);
+    let seed = args.seed.unwrap_or_else(|| rand::thread_rng().gen());
+    if args.randomize_selection || args.launch_jitter.is_some() {
+        info!("Random seed: {} (pass --seed {} to reproduce)", seed, seed);
+    }
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    // some drivers return stale process counts right after NVML is initialized, so discard the first polls
+    if let Some(secs) = args.warm_nvml {
+        spinner.set_message("Warming up NVML...");
+        warm_up(Duration::from_secs(secs), || {
+            gpu::get_idle_gpu(&criteria, None, cached_devices.as_deref()).map(drop)
+        })?;
+        spinner.set_message("Waiting for idle GPUs...");
+    }
+    if let Some(socket) = &args.wait_for_ipc {
+        spinner.set_message("Waiting for the coordinator...");
+        if !remote::wait_for_go(socket)? {
+            return Ok(());
+        }
+        spinner.set_message("Waiting for idle GPUs...");
+    }
+    if let Some(max) = args.fail_if_waiting_more_than {
+        let waiters = claim_file.update(|claims| claims.waiters.len())?;
+        if waiters > max {
+            error!(
+                "{} other gpu-waiter instances are already waiting, more than {}, exiting",
+                waiters, max
+            );
+            std::process::exit(BUSY_EXIT_CODE);
+        }
+    }
+    let wait_start = Instant::now();
+    // how many GPUs to select; it may be lowered to --minimum-viable-num
+    let mut num = args.num.get() as usize;
+    // released when dropped, after the command exits; process::exit skips that, so drop it before exiting
+    let mut allocation = None;
+    if let Some(server) = &server {
+        let granted = server.allocate(num as u32, args.priority)?;
+        idle_gpu = Some(granted.gpus.clone());
+        allocation = Some(granted);
+    }
     // poll for idle GPUs
-    while !STOPPED.load(std::sync::atomic::Ordering::Relaxed) {
-        let guard_in_loop = file_lock.write()?;
-        let mut idle_gpus = get_idle_gpu()?;
-        if idle_gpus.len() >= args.num.get() as usize {
-            info!("Found {} idle GPUs!: {:?}", args.num, idle_gpus);
-            idle_gpus.splice(args.num.get() as usize.., std::iter::empty());
-            idle_gpu = Some(idle_gpus);
+    while idle_gpu.is_none() && !STOPPED.load(std::sync::atomic::Ordering::Relaxed) {
+        if let Some(path) = args.cancel_file.as_ref().filter(|path| path.exists()) {
+            cancel_by_file(path);
+            break;
+        }
+        let fallback = args
+            .fallback_to_any_gpu
+            .is_some_and(|minutes| wait_start.elapsed() >= Duration::from_secs(minutes * 60));
+        if cached_devices.is_some() && devices_cached_at.elapsed() >= cache_ttl {
+            cached_devices = Some(gpu::get_all_devices()?);
+            devices_cached_at = Instant::now();
+        }
+        let Some(guard_in_loop) = lock_global(
+            &file_lock,
+            args.lock_retry_budget,
+            args.cancel_file.as_deref(),
+            || {
+                if let Err(err) = claim_file.update(|claims| claims.add_waiter(num as u32)) {
+                    warn!("Failed to update the heartbeat in the claim file: {}", err);
+                }
+            },
+        )?
+        else {
+            if let Some(path) = args.cancel_file.as_ref().filter(|path| path.exists()) {
+                cancel_by_file(path);
+                break;
+            }
+            error!(
+                "Could not take the global lock within {} seconds, other gpu-waiter instances are holding it, exiting",
+                args.lock_retry_budget.unwrap_or_default()
+            );
+            lock_timed_out = true;
+            break;
+        };
+        let (waiters, claimed) = claim_file.update(|claims| {
+            claims.add_waiter(num as u32);
+            (
+                (claims.waiters.len() - 1, claims.waiters_ahead()),
+                claims.claimed_by_others(),
+            )
+        })?;
+        if last_waiters != Some(waiters) {
+            let (others, ahead) = waiters;
+            if others > 0 {
+                info!(
+                    "~{} other gpu-waiter instances are also waiting, {} of them since before us",
+                    others, ahead
+                );
+            }
+            last_waiters = Some(waiters);
+        }
+        let discovery = gpu::get_idle_gpu(
+            &criteria,
+            fallback.then_some(args.max_utilization),
+            cached_devices.as_deref(),
+        )?;
+        // only report the skipped GPUs when they change, to avoid flooding the log
+        if discovery.skipped != last_skipped {
+            for (i, reason) in &discovery.skipped {
+                info!("Skipping idle GPU {}: {}", i, reason);
+            }
+            last_skipped = discovery.skipped;
+        }
+        free_streaks.update(&discovery.free);
+        let idle_gpus: Vec<u32> = discovery
+            .idle
+            .into_iter()
+            .filter(|i| require_free_for.is_none_or(|d| free_streaks.is_free_for(*i, d)))
+            // e.g. held by an instance with --no-occupy
+            .filter(|i| !claimed.contains(i))
+            .collect();
+        if args.json_poll_stream {
+            report::PollRecord::new(device_count, &idle_gpus, num, seed).emit()?;
+        }
+        if idle_gpus.len() >= num {
+            info!("Found {} idle GPUs!: {:?}", num, idle_gpus);
+            idle_gpu = Some(if args.randomize_selection {
+                select::randomize(idle_gpus, num, &mut rng)
+            } else if args.spread {
+                select::spread(idle_gpus, num)
+            } else {
+                idle_gpus
+            });
+            lock_guard = Some(guard_in_loop);
+            break;
+        }
+        let settle = args
+            .minimum_viable_num
+            .filter(|_| wait_start.elapsed() >= Duration::from_secs(args.min_viable_wait * 60));
+        if let Some(m) = settle {
+            if idle_gpus.len() >= m.get() as usize {
+                num = idle_gpus.len();
+                warn!(
+                    "Only {} idle GPUs after waiting for {} minutes, settling for them instead of the {} requested: {:?}",
+                    num,
+                    args.min_viable_wait,
+                    args.num,
+                    idle_gpus
+                );
+                idle_gpu = Some(idle_gpus);
+                lock_guard = Some(guard_in_loop);
+                break;
+            }
+        }
+        if fallback && idle_gpus.len() + discovery.underused.len() >= num {
+            warn!(
+                "Not enough idle GPUs after waiting for {} minutes, falling back to a DEGRADED set, including GPUs in use but below {}% utilization: {:?}",
+                wait_start.elapsed().as_secs() / 60,
+                args.max_utilization,
+                discovery.underused
+            );
+            // prefer the idle GPUs
+            let mut candidates = idle_gpus;
+            candidates.extend(discovery.underused);
+            idle_gpu = Some(candidates);
             lock_guard = Some(guard_in_loop);
             break;
         }
         drop(guard_in_loop);
-        spinner.set_message(format!(
-            "Waiting for idle GPUs... ({} available, {} requested) [Last check: {}]",
-            idle_gpus.len(),
-            args.num,
-            chrono::Local::now().format("%H:%M:%S")
-        ));
+        if args.fail_fast_if_busy {
+            error!(
+                "Only {} idle GPUs, but {} requested, exiting",
+                idle_gpus.len(),
+                args.num
+            );
+            busy = true;
+            break;
+        }
+        if !args.perf_mode {
+            let others = match waiters.0 {
+                0 => String::new(),
+                others => format!(", ~{} others waiting", others),
+            };
+            spinner.set_message(format!(
+                "Waiting for idle GPUs... ({} available, {} requested{}) [Last check: {}]",
+                idle_gpus.len(),
+                args.num,
+                others,
+                args.log_timestamps.format_now()
+            ));
+        }
         thread::sleep(Duration::from_secs(1));
     }
 
+    // we are no longer waiting, either with the GPUs found or stopped
+    claim_file.update(|claims| claims.remove_waiter())?;
+
     // remove the spinner
     spinner.finish_and_clear();
     multi.remove(&spinner);
 
-    if let Some(idle_gpu) = idle_gpu {
-        info!("Occupying GPUs: {:?}", idle_gpu);
+    if busy {
+        drop(allocation);
+        std::process::exit(BUSY_EXIT_CODE);
+    }
+    if lock_timed_out {
+        drop(allocation);
+        std::process::exit(LOCK_TIMEOUT_EXIT_CODE);
+    }
+
+    if let (Some(format), Some(candidates)) = (index_format, &idle_gpu) {
+        let mut selected = candidates[..num].to_vec();
+        args.id_order.sort(&mut selected);
+        println!("{}", format.format(&selected));
+        return Ok(());
+    }
+
+    // whether any held GPU was used by another process, see PREEMPTED_EXIT_CODE
+    let mut preempted = false;
+    if let Some(mut candidates) = idle_gpu {
+        if args.kill_other_users {
+            process::kill_other_users(
+                &candidates[..num],
+                Duration::from_secs(args.kill_grace_period),
+            )?;
+        }
+        let selection_order = candidates.clone();
+        let selection_time = chrono::Local::now().to_rfc3339();
+        // taken before occupying, including the spare GPUs in case they replace a selected one
+        let gpu_info = match &args.output_gpu_info_json {
+            Some(_) => candidates
+                .iter()
+                .map(|i| Ok((*i, report::GpuInfo::query(*i)?)))
+                .collect::<Result<HashMap<_, _>, nvml_wrapper::error::NvmlError>>()?,
+            None => HashMap::new(),
+        };
+        if !no_occupy {
+            // only the selected GPUs are reordered, the spare ones stay as replacements
+            args.occupy_order.sort(&mut candidates[..num])?;
+        }
+        if no_occupy {
+            info!(
+                "Selected GPUs (without occupying): {:?}",
+                &candidates[..num]
+            );
+        } else {
+            info!("Occupying GPUs: {:?}", &candidates[..num]);
+        }
 
         let (device_used_s, device_used_r) = crossbeam_channel::unbounded();
         let (proc_exit_s, proc_exit_r) = crossbeam_channel::unbounded();
-        let occupantions = Arc::new(RwLock::new(Vec::with_capacity(idle_gpu.len())));
-        for i in &idle_gpu {
-            let cuda_dev = cudarc::driver::CudaDevice::new(*i as usize)?;
-            let nvml_dev = NVML.wait().device_by_index(*i)?;
-            let free_mem = nvml_dev.memory_info()?.free;
+        let occupantions = Arc::new(RwLock::new(occupy::GpuAllocationGuard::default()));
+        // how many compute processes are expected on each GPU while we occupy it, i.e. the ones already running before
+        // and our own, shared with the monitor since GPUs are added with --retry-on-preempt
+        let baselines: Arc<RwLock<HashMap<u32, u32>>> =
+            Arc::new(RwLock::new(HashMap::with_capacity(num)));
+        #[cfg(feature = "cuda")]
+        let mut dynamic = args
+            .dynamic_fraction
+            .then(|| occupy::DynamicOccupation::new(args.lazy_occupy));
+        #[cfg(feature = "cuda")]
+        let mut ramp_up = args
+            .occupation_fraction_ramp_up
+            .map(|_| occupy::RampUp::new(args.memory_fraction, args.lazy_occupy));
+        // the share of the occupation of GPU `i`, see --occupy-weights
+        #[cfg(feature = "cuda")]
+        let occupation_scale = |i: u32, occupied: &[u32]| -> f64 {
+            match &args.occupy_weights {
+                Some(weights) => {
+                    occupy::weight_scale(weights, &selection_order[..num], i, occupied)
+                }
+                None => 1.0,
+            }
+        };
+        // how many bytes to occupy on GPU `i` with the given memory, with the share `scale` of the occupation
+        #[cfg(feature = "cuda")]
+        let occupation_bytes = |i: u32,
+                                total: u64,
+                                free: u64,
+                                residual: u64,
+                                fraction: f64,
+                                scale: f64|
+         -> anyhow::Result<usize> {
+            occupation_bytes(
+                i,
+                total,
+                free,
+                residual,
+                fraction * scale,
+                args.occupy_total_fraction.map(|f| f * scale),
+            )
+        };
+        // occupy GPU `i` with the share `scale` of the occupation, for both the selected GPUs and the replacements
+        #[cfg(feature = "cuda")]
+        let occupy_one = |i: u32,
+                          scale: f64,
+                          dynamic: Option<&mut occupy::DynamicOccupation>,
+                          ramp_up: Option<&mut occupy::RampUp>|
+         -> anyhow::Result<Occupation> {
+            let nvml_dev = NVML.wait().device_by_index(i)?;
+            // before creating our own context, which is a compute process too
+            let baseline = nvml_dev.running_compute_processes_count()?;
+            if args.clear_cuda_cache {
+                if let Err(err) = occupy::reset_cuda_device(i) {
+                    warn!("Failed to reset the CUDA context of GPU {}: {}", i, err);
+                }
+            }
+            let cuda_dev = match occupy::init_cuda_device(i, args.cuda_init_retries) {
+                Ok(cuda_dev) => cuda_dev,
+                Err(err) if args.nvidia_smi_fallback => {
+                    warn!(
+                        "Failed to initialize CUDA on GPU {}, holding a host buffer instead: {}",
+                        i, err
+                    );
+                    warn!(
+                        "GPU {} is not really occupied, the buffer takes no GPU memory: like with --no-occupy, other processes may grab it",
+                        i
+                    );
+                    let (total, free) = occupy::nvidia_smi_memory(i)?;
+                    let bytes = occupation_bytes(i, total, free, 0, args.memory_fraction, scale)?;
+                    let out = occupy::occupy_host(bytes)?;
+                    // without a CUDA context, we do not show up as a compute process
+                    baselines.write().insert(i, baseline);
+                    occupantions.write().push(i, out);
+                    return Ok(Occupation::Occupied);
+                }
+                Err(err) => return Ok(Occupation::NoContext(err.into())),
+            };
+            if args.check_driver_health {
+                if let Err(err) = occupy::check_driver_health(&cuda_dev) {
+                    warn!(
+                        "GPU {} failed the driver health check, giving it up: {}",
+                        i, err
+                    );
+                    return Ok(Occupation::GivenUp);
+                }
+            }
+            let mem = nvml_dev.memory_info()?;
+            let fraction = ramp_up
+                .as_ref()
+                .map_or(args.memory_fraction, |ramp_up| ramp_up.fraction());
+            // e.g. our previous run is still exiting, and the command will need that memory again
+            let residual = process::own_residual_memory(i);
+            let bytes = occupation_bytes(i, mem.total, mem.free, residual, fraction, scale)?;
+            if bytes == 0 && residual > 0 {
+                warn!(
+                    "Giving up GPU {}: our own processes still hold {} MiB on it",
+                    i,
+                    residual >> 20
+                );
+                return Ok(Occupation::GivenUp);
+            }
+            if residual > 0 {
+                info!(
+                    "Our own processes still hold {} MiB on GPU {}, leaving that much free for the command",
+                    residual >> 20,
+                    i
+                );
+            }
+            let target = if args.cudarc_stream {
+                occupy::OccupyTarget::Stream(Arc::new(occupy::LowPriorityStream::new(cuda_dev)?))
+            } else {
+                occupy::OccupyTarget::Device(cuda_dev)
+            };
+            // our own context is a compute process too
+            baselines.write().insert(i, baseline + 1);
+            if let Some(dynamic) = dynamic {
+                if let Err(err) =
+                    dynamic.occupy(i, target, (mem.free, mem.used), bytes, &occupantions)
+                {
+                    // the chunks allocated before the failure
+                    occupantions.write().release(i);
+                    return Ok(Occupation::NoMemory(err));
+                }
+            } else {
+                match occupy::occupy_gpu(&target, bytes, args.lazy_occupy) {
+                    Ok(out) => occupantions.write().push(i, out),
+                    Err(err) => return Ok(Occupation::NoMemory(err)),
+                }
+                if let Some(ramp_up) = ramp_up {
+                    ramp_up.add(i, target, mem.free);
+                }
+            }
+            if args.occupancy_check {
+                occupy::check_occupancy(i, baseline)?;
+            }
+            Ok(Occupation::Occupied)
+        };
+        // the spare idle GPUs are used as replacements, in case we fail to create a CUDA context or to allocate on some
+        // of them
+        let mut idle_gpu = occupy::occupy_enough(candidates, num, &occupantions, |i, occupied| {
+            if no_occupy {
+                return Ok(true);
+            }
+            #[cfg(feature = "cuda")]
+            {
+                let scale = occupation_scale(i, occupied);
+                match occupy_one(i, scale, dynamic.as_mut(), ramp_up.as_mut())? {
+                    Occupation::Occupied => Ok(true),
+                    Occupation::NoContext(err) | Occupation::NoMemory(err) => {
+                        warn!("Giving up GPU {}: {}", i, err);
+                        Ok(false)
+                    }
+                    Occupation::GivenUp => Ok(false),
+                }
+            }
+            #[cfg(not(feature = "cuda"))]
+            {
+                let _ = (i, occupied);
+                unreachable!("GPUs are never occupied without the `cuda` feature")
+            }
+        })?;
 
-            let out = cuda_dev.alloc_zeros::<u8>((free_mem / 4) as usize)?;
-            occupantions.write().push((*i, out));
+        // the occupation order should not affect the order the command sees
+        idle_gpu.sort_by_key(|i| selection_order.iter().position(|j| j == i));
+        args.id_order.sort(&mut idle_gpu);
+        if !no_occupy {
+            info!("GPUs occupied: {:?}", occupantions.read().indices());
+        }
+        if let Some(path) = &args.output_gpu_info_json {
+            let mut gpu_info = gpu_info;
+            report::SelectionRecord {
+                selected_gpus: idle_gpu.iter().filter_map(|i| gpu_info.remove(i)).collect(),
+                selection_time,
+                driver_version: driver_versions.as_ref().map(|v| v.driver.clone()),
+                cuda_version: driver_versions
+                    .as_ref()
+                    .map(|v| format!("{}.{}", v.cuda.0, v.cuda.1)),
+                seed,
+            }
+            .write(path)?;
         }
+        let held_devices = || {
+            if no_occupy {
+                idle_gpu.clone()
+            } else {
+                occupantions.read().indices()
+            }
+        };
+        let refresh_heartbeat = || {
+            if let Err(err) = claim_file.update(|claims| claims.set_holder(&held_devices())) {
+                warn!("Failed to update the heartbeat in the claim file: {}", err);
+            }
+        };
+        claim_file.update(|claims| {
+            claims.set_holder(&held_devices());
+            if let Some(name) = &reservation {
+                claims.name_holder(name);
+            }
+        })?;
 
-        info!("GPUs occupied: {:?}", idle_gpu);
-        // after occupying, drop the lock guard
-        if let Some(guard) = lock_guard {
-            drop(guard);
+        // the occupied GPUs the command has not shown up on yet, see --hold-lock-until-ready
+        let mut not_ready: HashSet<u32> = match args.hold_lock_until_ready {
+            Some(_) if !hold => occupantions.read().indices().into_iter().collect(),
+            _ => HashSet::new(),
+        };
+        // after occupying, drop the lock guard, unless we are asked to hold it until the command exits or is ready
+        if !args.hold_lock_during_run && not_ready.is_empty() {
+            drop(lock_guard.take());
         }
 
-        let occp = occupantions.clone();
+        // only keep a weak reference in the monitor, so that the memory is released as soon as main drops the guard
+        let occp = Arc::downgrade(&occupantions);
+        let monitor = MonitorConfig {
+            interval: Duration::from_millis(args.monitor_interval),
+            threshold: args.monitor_threshold,
+        };
+        // keep monitoring while waiting for replacements, since they will need it too
+        let retry_on_preempt = hold && args.retry_on_preempt;
+        let monitor_baselines = baselines.clone();
         thread::spawn(move || {
-            'outer: while occp.read().len() > 0 {
-                for (i, _) in occp.read().iter() {
-                    let result: anyhow::Result<()> = try {
-                        let nvml_dev = NVML.wait().device_by_index(*i)?;
-                        if nvml_dev.running_compute_processes_count()? > 1 {
-                            if let Err(e) = device_used_s.send(Ok(*i)) {
+            // how many consecutive checks have seen another process on each GPU
+            let mut streaks: HashMap<u32, u32> = HashMap::new();
+            'outer: while let Some(occp) = occp.upgrade() {
+                let indices = occp.read().indices();
+                if indices.is_empty() && !retry_on_preempt {
+                    break;
+                }
+                // with --dynamic-fraction, the occupation is resized instead of released when the GPU is used
+                #[cfg(feature = "cuda")]
+                if let Some(dynamic) = &dynamic {
+                    if let Err(err) = dynamic.adjust(&occp) {
+                        let _ = device_used_s.send(Err(err));
+                        break;
+                    }
+                    drop(occp);
+                    thread::sleep(monitor.interval);
+                    continue;
+                }
+                drop(occp);
+                for i in indices {
+                    let baseline = monitor_baselines.read()[&i];
+                    match is_used_by_others(i, baseline) {
+                        Ok(true) => {
+                            let streak = streaks.entry(i).or_default();
+                            *streak += 1;
+                            if *streak < monitor.threshold {
+                                continue;
+                            }
+                            streaks.remove(&i);
+                            if let Err(e) = device_used_s.send(Ok(i)) {
                                 error!("Failed to send used device: {}", e);
                                 break 'outer;
                             }
                         }
-                    };
-
-                    if let Err(err) = result {
-                        let _ = device_used_s.send(Err(err));
-                        break 'outer;
+                        Ok(false) => {
+                            streaks.remove(&i);
+                        }
+                        Err(err) => {
+                            let _ = device_used_s.send(Err(err));
+                            break 'outer;
+                        }
                     }
                 }
-                thread::sleep(Duration::from_millis(100));
+                thread::sleep(monitor.interval);
             }
         });
 
@@ -217,54 +1836,803 @@ fn main() -> anyhow::Result<()> {
             .collect::<Vec<_>>()
             .join(",");
 
-        let mut final_cmd = Vec::with_capacity(preprocess_cmd.len());
-        for arg in preprocess_cmd {
-            match arg {
-                Either::Left(arg) => {
-                    final_cmd.push(arg);
-                }
-                Either::Right(arg) => {
-                    let result = command::process_command_template(&arg, &gpu_list_str)?;
-                    final_cmd.push(OsString::from(result.command));
-                }
+        // reset when dropped, on any way out of this block
+        let _app_clocks = args
+            .app_clocks
+            .map(|(mem, sm)| gpu::AppClocks::set(&idle_gpu, mem, sm));
+        // the GPUs are ready to be used from here on
+        let _idle_file = args
+            .gpu_idle_file
+            .as_deref()
+            .map(|path| process::ReadyFile::create(path, &gpu_list_str))
+            .transpose()?;
+        for (shell, path) in [
+            (report::Shell::Bash, &args.output_bash_export),
+            (report::Shell::Fish, &args.output_fish_export),
+            (report::Shell::Csh, &args.output_csh_export),
+        ] {
+            if let Some(path) = path {
+                shell.write_exports(path, &gpu_list_str, idle_gpu.len())?;
             }
         }
-        let mut cmd = Command::new(&final_cmd[0]);
-        if !has_template || args.force_env {
-            cmd.env("CUDA_VISIBLE_DEVICES", &gpu_list_str);
-        } else {
-            info!("CUDA_VISIBLE_DEVICES is NOT set because the command contains template");
+        if let Some((template, out, contents)) = &render_template {
+            render_template_file(template, contents, out, &gpu_list_str, args.escape_style)?;
         }
-        if has_template {
-            info!(
-                "The command will be run as: {:?}",
-                final_cmd.join(&OsString::from(" "))
-            );
+        let mut _pidfile = None;
+        if hold {
+            if export {
+                info!("Holding GPUs {:?} in the background", idle_gpu);
+                background::report_held(&gpu_list_str)?;
+            } else if let Some(duration) = selftest {
+                info!("Stressing GPUs {:?} for {:?}", idle_gpu, duration);
+            } else {
+                info!("Holding GPUs {:?}, press Ctrl+C to release them", idle_gpu);
+            }
+        }
+        // spread the launches of jobs started at the same time, e.g. the ranks of a multi-node training
+        if let Some(max) = args.launch_jitter.filter(|_| !hold) {
+            let delay = launch_jitter(&mut rng, max);
+            info!("Waiting {:?} before launching the command", delay);
+            if !sleep_unless_stopped(delay, refresh_heartbeat) {
+                jobs.clear();
+            }
         }
-        let mut cmd = cmd.args(&final_cmd[1..]).spawn()?;
+        // the commands still running, shared with the threads reaping them
+        let pids = Arc::new(Mutex::new(HashSet::new()));
+        let grouped = jobs.len() > 1;
+        let mut rest = &idle_gpu[..];
+        for job in jobs {
+            let (gpus, others) = rest.split_at(job.size.unwrap_or(rest.len()));
+            rest = others;
+            let gpu_list_str = gpus
+                .iter()
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            let mut final_cmd = Vec::with_capacity(job.args.len());
+            for arg in job.args {
+                match arg {
+                    Either::Left(arg) => {
+                        final_cmd.push(arg);
+                    }
+                    Either::Right(arg) => {
+                        let result = command::process_command_template(
+                            &arg,
+                            &gpu_list_str,
+                            args.escape_style,
+                            command::NormalizeWhitespace(args.normalize_whitespace),
+                        )?;
+                        for warning in result.warnings {
+                            if template_warnings.insert(warning.clone()) {
+                                warn!("{}", warning);
+                            }
+                        }
+                        final_cmd.push(OsString::from(result.command));
+                    }
+                }
+            }
+            let mut cmd = Command::new(&final_cmd[0]);
+            match CudaEnv::of(job.has_template, args.force_env, args.no_cuda_env) {
+                CudaEnv::Removed => {
+                    cmd.env_remove("CUDA_VISIBLE_DEVICES");
+                    info!("CUDA_VISIBLE_DEVICES is NOT set because of --no-cuda-env");
+                }
+                CudaEnv::Set => {
+                    cmd.env("CUDA_VISIBLE_DEVICES", &gpu_list_str);
+                }
+                CudaEnv::Forced => {
+                    cmd.env("CUDA_VISIBLE_DEVICES", &gpu_list_str);
+                    info!(
+                        "CUDA_VISIBLE_DEVICES is set despite the template because of --force-env"
+                    );
+                }
+                CudaEnv::Inherited => {
+                    info!("CUDA_VISIBLE_DEVICES is NOT set because the command contains template");
+                }
+            }
+            if args.torch_env {
+                cmd.envs(process::torch_env(gpus.len())?);
+            }
+            cmd.envs(args.env.iter().map(|(k, v)| (k, v)));
+            if job.has_template {
+                info!(
+                    "The command will be run as: {:?}",
+                    final_cmd.join(&OsString::from(" "))
+                );
+            }
+            let child_output = output::ChildOutput {
+                prefix: args.child_log_prefix.clone(),
+                timestamps: args.timestamp_child_output,
+            };
+            if child_output.is_piped() {
+                cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+            }
+            cmd.args(&final_cmd[1..]);
+            #[cfg(unix)]
+            if args.exec {
+                info!("Replacing gpu-waiter with the command");
+                let err = launcher.exec(cmd);
+                return Err(anyhow::Error::new(err).context("Failed to exec the command"));
+            }
+            let mut cmd = launcher.spawn(cmd)?;
+            let pid = cmd.id();
+            if grouped {
+                info!(
+                    "Started process {} on GPUs {}: {:?}",
+                    pid, gpu_list_str, final_cmd
+                );
+            }
+            if let Some(path) = &args.pidfile {
+                _pidfile = Some(process::PidFile::create(path, pid)?);
+            }
+            let mut forwarders = vec![];
+            if let Some(stdout) = cmd.stdout.take() {
+                forwarders.push(child_output.forward(stdout, std::io::stdout(), "STDOUT"));
+            }
+            if let Some(stderr) = cmd.stderr.take() {
+                forwarders.push(child_output.forward(stderr, std::io::stderr(), "STDERR"));
+            }
 
-        thread::spawn(move || {
-            let _ = proc_exit_s.send(cmd.wait());
-        });
+            let proc_exit_s = proc_exit_s.clone();
+            let running = pids.clone();
+            thread::spawn(move || {
+                // forget the PID before reaping the process, so that a reused PID is never signalled
+                if let Err(err) = process::wait_exited(pid) {
+                    warn!("Failed to wait for process {}: {}", pid, err);
+                }
+                running.lock().remove(&pid);
+                // report right away: the output may stay open after the exit, e.g. held by a background grandchild
+                let _ = proc_exit_s.send((pid, cmd.wait(), forwarders));
+            });
+            pids.lock().insert(pid);
+        }
+        let mut running = pids.lock().len();
+        drop(proc_exit_s);
 
         let mut device_used_r = Some(&device_used_r);
+        let mut proc_exit_r = Some(&proc_exit_r);
+        let mut runtime_limit = args.max_runtime.map(after).unwrap_or_else(never);
+        let mut kill_timer = never();
+        // stops once the commands are reniced, see --renice-on-memory-pressure
+        let mut memory_pressure_tick = match args.renice_on_memory_pressure {
+            Some(_) if running > 0 => tick(monitor.interval),
+            _ => never(),
+        };
+        let mut ready_timeout = match args.hold_lock_until_ready {
+            Some(secs) if !not_ready.is_empty() => after(Duration::from_secs(secs)),
+            _ => never(),
+        };
+        let heartbeat = tick(claims::HEARTBEAT_INTERVAL);
+        // one step for each 5 percentage points from 5% to 100%
+        #[cfg_attr(not(feature = "cuda"), allow(unused_mut))]
+        let mut ramp_up_tick = match args.occupation_fraction_ramp_up {
+            Some(secs) if !no_occupy => tick(Duration::from_secs_f64(secs as f64 / 19.0)),
+            _ => never(),
+        };
+        // ticks while fewer GPUs are held than initially, see --retry-on-preempt
+        #[cfg_attr(not(feature = "cuda"), allow(unused_mut))]
+        let mut replacement_tick = never();
+        // occupy an idle GPU in place of a preempted one, returning it if any is found
+        #[cfg(feature = "cuda")]
+        let occupy_replacement = |locked: bool,
+                                  mut ramp_up: Option<&mut occupy::RampUp>|
+         -> anyhow::Result<Option<u32>> {
+            // the lock may be already held with --hold-lock-during-run
+            let _guard = if locked {
+                None
+            } else {
+                let guard =
+                    lock_global(&file_lock, args.lock_retry_budget, None, refresh_heartbeat)?;
+                if guard.is_none() {
+                    anyhow::bail!("Could not take the global lock within --lock-retry-budget");
+                }
+                guard
+            };
+            let claimed = claim_file.update(|claims| claims.claimed_by_others())?;
+            let held = occupantions.read().indices();
+            let discovery = gpu::get_idle_gpu(&criteria, None, cached_devices.as_deref())?;
+            for i in replacement_candidates(discovery.idle, &claimed, &held) {
+                // a replacement takes over the share of the preempted GPU, see --occupy-weights
+                let scale = occupation_scale(i, &held);
+                match occupy_one(i, scale, None, ramp_up.as_deref_mut())? {
+                    Occupation::Occupied => {}
+                    // a GPU we cannot occupy does not replace one we could
+                    Occupation::NoContext(err) | Occupation::NoMemory(err) => {
+                        warn!("Giving up GPU {}: {}", i, err);
+                        continue;
+                    }
+                    Occupation::GivenUp => continue,
+                }
+                return Ok(Some(i));
+            }
+            Ok(None)
+        };
+        let mut runtime_exceeded = false;
+        let mut exited = false;
+        // the stress runs in the background, so that the heartbeat is kept and Ctrl+C is noticed meanwhile
+        #[cfg(feature = "cuda")]
+        let selftest_r = selftest.map(|duration| {
+            let load = selftest::CudaLoad {
+                init_retries: args.cuda_init_retries,
+            };
+            selftest::spawn(idle_gpu.clone(), duration, load)
+        });
+        #[cfg(not(feature = "cuda"))]
+        let selftest_r: Option<
+            crossbeam_channel::Receiver<anyhow::Result<selftest::SelftestReport>>,
+        > = None;
         'select: while !STOPPED.load(std::sync::atomic::Ordering::Relaxed) {
             select! {
+                recv(selftest_r.as_ref().unwrap_or(&never())) -> res => {
+                    res.context("The selftest thread panicked")??.print();
+                    break 'select;
+                }
                 recv(device_used_r.unwrap_or(&never())) -> res => {
                     if matches!(res, Err(_)) {
                         device_used_r = None;
                     } else {
                         let used_index = res??;
-                        occupantions.write().retain(|(j, _)| *j != used_index);
+                        info!("GPU {} is now used by another process, releasing it", used_index);
+                        occupantions.write().release(used_index);
+                        preempted |= is_preempted(used_index, hold, || {
+                            process::foreign_processes(used_index, &pids.lock())
+                        });
+                        if not_ready.remove(&used_index) && not_ready.is_empty() {
+                            info!("The command is using all the occupied GPUs, releasing the global lock");
+                            drop(lock_guard.take());
+                            ready_timeout = never();
+                        }
+                        if hold && args.retry_on_preempt {
+                            info!("Looking for another idle GPU to hold instead of GPU {}", used_index);
+                            replacement_tick = tick(Duration::from_secs(1));
+                        } else if hold && hold_is_over(no_occupy, occupantions.read().is_empty()) {
+                            info!("All the held GPUs are now used by other processes, exiting");
+                            break 'select;
+                        }
                     }
                 }
-                recv(proc_exit_r) -> res => {
-                    let status = res??;
-                    info!("Process exited with status: {}", status);
-                    break 'select;
+                recv(ready_timeout) -> _ => {
+                    warn!(
+                        "The command is still not using GPUs {:?} after {}s, releasing the global lock anyway",
+                        not_ready,
+                        args.hold_lock_until_ready.unwrap_or_default()
+                    );
+                    not_ready.clear();
+                    drop(lock_guard.take());
+                    ready_timeout = never();
+                }
+                recv(runtime_limit) -> _ => {
+                    let max_runtime = humantime::format_duration(args.max_runtime.unwrap_or_default());
+                    if hold {
+                        info!("The GPUs have been held for {}, releasing them", max_runtime);
+                        break 'select;
+                    }
+                    warn!("The command has been running for more than {}, terminating it", max_runtime);
+                    runtime_exceeded = true;
+                    runtime_limit = never();
+                    for pid in pids.lock().iter() {
+                        process::terminate(*pid)?;
+                    }
+                    kill_timer = after(TERMINATE_GRACE_PERIOD);
+                }
+                recv(ramp_up_tick) -> _ => {
+                    #[cfg(feature = "cuda")]
+                    if let Some(ramp_up) = &mut ramp_up {
+                        if ramp_up.step(&occupantions) {
+                            ramp_up_tick = never();
+                        }
+                    }
+                }
+                recv(replacement_tick) -> _ => {
+                    #[cfg(feature = "cuda")]
+                    match occupy_replacement(lock_guard.is_some(), ramp_up.as_mut()) {
+                        Ok(Some(i)) => {
+                            info!("Holding GPU {} in place of a preempted one", i);
+                            if occupantions.read().indices().len() >= idle_gpu.len() {
+                                replacement_tick = never();
+                            }
+                            if let Err(err) = claim_file.update(|claims| claims.set_holder(&held_devices())) {
+                                warn!("Failed to update the held GPUs in the claim file: {}", err);
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(err) => warn!("Failed to occupy a replacement GPU: {}", err),
+                    }
+                }
+                recv(heartbeat) -> _ => refresh_heartbeat(),
+                recv(memory_pressure_tick) -> _ => {
+                    let threshold = args.renice_on_memory_pressure.unwrap_or_default() << 20;
+                    for &i in &idle_gpu {
+                        let free = match NVML.wait().device_by_index(i).and_then(|dev| dev.memory_info()) {
+                            Ok(mem) => mem.free,
+                            Err(err) => {
+                                warn!("Failed to query the memory of GPU {}: {}", i, err);
+                                continue;
+                            }
+                        };
+                        if free < threshold {
+                            warn!("Only {} MiB is free on GPU {}, lowering the priority of the command", free >> 20, i);
+                            memory_pressure_tick = never();
+                            for pid in pids.lock().iter() {
+                                if let Err(err) = process::set_niceness(*pid, MEMORY_PRESSURE_NICENESS) {
+                                    warn!("Failed to renice process {}: {}", pid, err);
+                                }
+                            }
+                            break;
+                        }
+                    }
+                }
+                recv(kill_timer) -> _ => {
+                    warn!("The command is still running {:?} after being asked to terminate, killing it", TERMINATE_GRACE_PERIOD);
+                    kill_timer = never();
+                    for pid in pids.lock().iter() {
+                        process::kill(*pid)?;
+                    }
                 }
+                recv(proc_exit_r.unwrap_or(&never())) -> res => {
+                    let Ok((pid, status, forwarders)) = res else {
+                        // when holding, there is no process to wait for
+                        proc_exit_r = None;
+                        continue;
+                    };
+                    let status = status?;
+                    running -= 1;
+                    // let the last lines of the output through before reporting the exit
+                    if !output::drain(forwarders, output::DRAIN_TIMEOUT) {
+                        warn!("The output of process {} is still open, e.g. by the processes it started, not waiting for it", pid);
+                    }
+                    let process = if grouped { format!("Process {}", pid) } else { "Process".to_string() };
+                    if runtime_exceeded {
+                        info!("{} was terminated for exceeding the maximum runtime, exited with status: {}", process, status);
+                    } else {
+                        info!("{} exited with status: {}", process, status);
+                    }
+                    if running == 0 {
+                        exited = true;
+                        break 'select;
+                    }
+                }
+                // wake up regularly to notice Ctrl+C
+                default(Duration::from_millis(200)) => {}
             }
         }
+
+        if let Some(delay) = args.release_delay.filter(|_| exited && !no_occupy) {
+            let delay = Duration::from_secs(delay);
+            info!(
+                "Keeping the GPUs occupied for {:?} before releasing them",
+                delay
+            );
+            sleep_unless_stopped(delay, refresh_heartbeat);
+        }
+
+        if let Err(err) = claim_file.update(|claims| claims.remove_holder()) {
+            warn!("Failed to remove our entry from the claim file: {}", err);
+        }
+    }
+    if preempted {
+        drop(allocation);
+        std::process::exit(PREEMPTED_EXIT_CODE);
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(std::iter::once("gpu-waiter").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn cli_is_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn lazy_occupy_needs_occupying() {
+        assert!(parse(&["--lazy-occupy", "echo"]).unwrap().lazy_occupy);
+        assert!(parse(&["--lazy-occupy", "--no-occupy", "echo"]).is_err());
+    }
+
+    #[test]
+    fn max_runtime_is_a_human_duration() {
+        let cli = parse(&["--max-runtime", "2h 30min", "echo"]).unwrap();
+        assert_eq!(cli.max_runtime, Some(Duration::from_secs(9000)));
+        assert!(parse(&["--max-runtime", "12", "echo"]).is_err());
+    }
+
+    #[test]
+    fn monitor_interval_has_a_floor() {
+        assert_eq!(parse(&["echo"]).unwrap().monitor_interval, 100);
+        assert_eq!(
+            parse(&["--monitor-interval", "10", "echo"])
+                .unwrap()
+                .monitor_interval,
+            10
+        );
+        assert!(parse(&["--monitor-interval", "9", "echo"]).is_err());
+    }
+
+    #[test]
+    fn hold_ends_once_every_gpu_is_taken() {
+        assert!(!hold_is_over(false, false));
+        assert!(hold_is_over(false, true));
+        // nothing was occupied, so nothing can be taken
+        assert!(!hold_is_over(true, true));
+        // or it ends after a given time
+        let cli = parse(&["--max-runtime", "1h", "hold"]).unwrap();
+        assert_eq!(cli.max_runtime, Some(Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn occupy_total_fraction_replaces_memory_fraction() {
+        let cli = parse(&["--occupy-total-fraction", "0.9", "echo"]).unwrap();
+        assert_eq!(cli.occupy_total_fraction, Some(0.9));
+        assert!(parse(&[
+            "--occupy-total-fraction",
+            "0.9",
+            "--memory-fraction",
+            "0.5",
+            "echo"
+        ])
+        .is_err());
+        assert!(parse(&["--occupy-total-fraction", "0", "echo"]).is_err());
+    }
+
+    #[cfg(feature = "cuda")]
+    #[test]
+    fn occupation_leaves_room_for_own_residual_memory() {
+        const GIB: u64 = 1 << 30;
+        let bytes = |free, residual, fraction, total_fraction| {
+            occupation_bytes(0, 80 * GIB, free, residual, fraction, total_fraction)
+                .map(|b| b as u64)
+        };
+        assert_eq!(bytes(60 * GIB, 0, 0.25, None).unwrap(), 15 * GIB);
+        // the residual is not free already, so it is not taken off again
+        assert_eq!(bytes(60 * GIB, 20 * GIB, 0.25, None).unwrap(), 15 * GIB);
+        assert_eq!(bytes(30 * GIB, 20 * GIB, 0.5, None).unwrap(), 10 * GIB);
+        assert_eq!(bytes(18 * GIB, 20 * GIB, 0.25, None).unwrap(), 0);
+        assert_eq!(
+            bytes(60 * GIB, 20 * GIB, 0.25, Some(0.25)).unwrap(),
+            20 * GIB
+        );
+        assert_eq!(
+            bytes(30 * GIB, 20 * GIB, 0.25, Some(0.25)).unwrap(),
+            10 * GIB
+        );
+        assert!(bytes(30 * GIB, 0, 0.25, Some(0.5)).is_err());
+    }
+
+    #[test]
+    fn cuda_env_outcomes() {
+        assert_eq!(CudaEnv::of(false, false, false), CudaEnv::Set);
+        assert_eq!(CudaEnv::of(false, true, false), CudaEnv::Set);
+        assert_eq!(CudaEnv::of(true, false, false), CudaEnv::Inherited);
+        assert_eq!(CudaEnv::of(true, true, false), CudaEnv::Forced);
+        assert_eq!(CudaEnv::of(false, false, true), CudaEnv::Removed);
+        assert_eq!(CudaEnv::of(true, false, true), CudaEnv::Removed);
+        assert!(parse(&["--no-cuda-env", "--force-env", "echo"]).is_err());
+    }
+
+    #[test]
+    fn spinner_interval_is_positive() {
+        assert_eq!(parse(&["echo"]).unwrap().spinner_interval, 500);
+        assert_eq!(
+            parse(&["--spinner-interval", "2000", "echo"])
+                .unwrap()
+                .spinner_interval,
+            2000
+        );
+        assert!(parse(&["--spinner-interval", "0", "echo"]).is_err());
+        assert!(
+            parse(&["--spinner-style", "line", "echo"])
+                .unwrap()
+                .spinner_style
+                == logging::SpinnerStyle::Line
+        );
+    }
+
+    #[test]
+    fn hold_lock_until_ready_conflicts() {
+        let cli = parse(&["--hold-lock-until-ready", "30", "echo"]).unwrap();
+        assert_eq!(cli.hold_lock_until_ready, Some(30));
+        for other in [
+            "--hold-lock-during-run",
+            "--no-occupy",
+            "--dynamic-fraction",
+            "--exec",
+        ] {
+            assert!(parse(&["--hold-lock-until-ready", "30", other, "echo"]).is_err());
+        }
+    }
+
+    #[test]
+    fn lock_type_defaults_to_flock() {
+        assert_eq!(parse(&["echo"]).unwrap().lock_type, lock::LockType::Flock);
+        assert_eq!(
+            parse(&["--lock-type", "lockf", "echo"]).unwrap().lock_type,
+            lock::LockType::Lockf
+        );
+        assert!(parse(&["--lock-type", "fcntl", "echo"]).is_err());
+    }
+
+    #[test]
+    fn retry_on_preempt_needs_occupying() {
+        assert!(
+            parse(&["--retry-on-preempt", "hold"])
+                .unwrap()
+                .retry_on_preempt
+        );
+        assert!(parse(&["--retry-on-preempt", "--no-occupy", "hold"]).is_err());
+        assert!(parse(&["--retry-on-preempt", "--dynamic-fraction", "hold"]).is_err());
+    }
+
+    #[cfg(feature = "cuda")]
+    #[test]
+    fn replacements_skip_claimed_and_held_gpus() {
+        assert_eq!(replacement_candidates(vec![0, 1, 2, 3], &[1], &[0, 2]), [3]);
+        assert!(replacement_candidates(vec![0, 1], &[0], &[1]).is_empty());
+    }
+
+    #[test]
+    fn exit_codes_are_distinct() {
+        // 1 is any error, and 2 a usage error reported by clap
+        let codes = [BUSY_EXIT_CODE, PREEMPTED_EXIT_CODE, LOCK_TIMEOUT_EXIT_CODE];
+        assert_eq!(codes.iter().collect::<HashSet<_>>().len(), codes.len());
+        assert!(codes.iter().all(|code| *code > 2));
+        let hold = Cli::command()
+            .find_subcommand("hold")
+            .unwrap()
+            .get_long_about()
+            .unwrap()
+            .to_string();
+        assert!(
+            hold.contains(&format!("code {}", PREEMPTED_EXIT_CODE)),
+            "{}",
+            hold
+        );
+    }
+
+    #[test]
+    fn kill_other_users_only_kills_on_idle_gpus() {
+        assert!(
+            parse(&["--kill-other-users", "--yes", "echo"])
+                .unwrap()
+                .kill_other_users
+        );
+        assert!(parse(&["--kill-other-users", "echo"]).is_err());
+        assert!(parse(&[
+            "--kill-other-users",
+            "--yes",
+            "--fallback-to-any-gpu",
+            "10",
+            "echo"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn preemption_sets_the_exit_code() {
+        // holding, any other process is a preemption, without looking at the processes
+        assert!(is_preempted(0, true, || unreachable!()));
+        // with a command, only the processes that are not part of it are
+        assert!(!is_preempted(0, false, || Ok(vec![])));
+        assert!(is_preempted(0, false, || Ok(vec![4242])));
+        // failing to tell is not a preemption
+        assert!(!is_preempted(0, false, || Err(anyhow::anyhow!("failed"))));
+    }
+
+    #[test]
+    fn fail_fast_on_nvml_timeout_needs_a_timeout() {
+        assert!(
+            parse(&[
+                "--fail-fast-on-nvml-timeout",
+                "--timeout-per-gpu-check",
+                "500",
+                "echo"
+            ])
+            .unwrap()
+            .fail_fast_on_nvml_timeout
+        );
+        assert!(parse(&["--fail-fast-on-nvml-timeout", "echo"]).is_err());
+    }
+
+    #[test]
+    fn app_clocks_conflicts() {
+        let cli = parse(&["--app-clocks", "1215,1410", "echo"]).unwrap();
+        assert_eq!(cli.app_clocks, Some((1215, 1410)));
+        assert!(parse(&["--app-clocks", "1215,1410", "--exec", "--no-occupy", "echo"]).is_err());
+        assert!(parse(&[
+            "--app-clocks",
+            "1215,1410",
+            "--request-from-server",
+            "http://localhost:8080",
+            "echo"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn warm_up_polls_at_least_once() {
+        assert_eq!(warm_up(Duration::ZERO, || Ok(())).unwrap(), 1);
+        assert_eq!(warm_up(Duration::from_millis(50), || Ok(())).unwrap(), 2);
+        assert!(warm_up(Duration::from_secs(60), || anyhow::bail!("NVML failed")).is_err());
+        assert_eq!(
+            parse(&["--warm-nvml", "5", "echo"]).unwrap().warm_nvml,
+            Some(5)
+        );
+    }
+
+    #[test]
+    fn group_takes_the_separators_verbatim() {
+        let cli = parse(&[
+            "-n", "2", "group", "--group", "1", "a", "--", "--group", "1", "b",
+        ])
+        .unwrap();
+        let Commands::Group { groups } = cli.command else {
+            panic!("expected `group`");
+        };
+        assert_eq!(group::parse(&groups).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn self_nice_is_a_niceness() {
+        assert_eq!(
+            parse(&["--self-nice", "-20", "echo"]).unwrap().self_nice,
+            Some(-20)
+        );
+        assert_eq!(
+            parse(&["--self-nice", "19", "echo"]).unwrap().self_nice,
+            Some(19)
+        );
+        assert!(parse(&["--self-nice", "20", "echo"]).is_err());
+        assert!(parse(&["--self-nice", "-21", "echo"]).is_err());
+    }
+
+    #[test]
+    fn launch_jitter_is_reproducible() {
+        let jitters = |seed| {
+            let mut rng = ChaCha8Rng::seed_from_u64(seed);
+            (0..100)
+                .map(|_| launch_jitter(&mut rng, 30))
+                .collect::<Vec<_>>()
+        };
+        let first = jitters(42);
+        assert!(first.iter().all(|delay| *delay <= Duration::from_secs(30)));
+        assert_eq!(first, jitters(42));
+        assert_ne!(first, jitters(43));
+        assert_eq!(
+            launch_jitter(&mut ChaCha8Rng::seed_from_u64(42), 0),
+            Duration::ZERO
+        );
+        assert!(sleep_unless_stopped(Duration::from_millis(10), || panic!(
+            "no heartbeat is due"
+        )));
+    }
+
+    #[test]
+    fn id_order_defaults_to_the_selection() {
+        assert!(parse(&["echo"]).unwrap().id_order == select::IdOrder::AsSelected);
+        assert!(
+            parse(&["--id-order", "descending", "echo"])
+                .unwrap()
+                .id_order
+                == select::IdOrder::Descending
+        );
+    }
+
+    #[test]
+    fn renders_a_template_file() {
+        let out = std::env::temp_dir().join(format!("gpu-waiter-test-{}.yaml", std::process::id()));
+        let contents = "gpus: [{}]\nname: {{x}}\n  indented:\t{0}\n";
+        render_template_file(
+            Path::new("in.yaml"),
+            contents,
+            &out,
+            "1,3",
+            command::EscapeStyle::DoubleBrace,
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&out).unwrap(),
+            "gpus: [1,3]\nname: {x}\n  indented:\t{0}\n"
+        );
+        render_template_file(
+            Path::new("in.yaml"),
+            "gpus: \\{{}\\}",
+            &out,
+            "1,3",
+            command::EscapeStyle::Backslash,
+        )
+        .unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "gpus: {1,3}");
+        std::fs::remove_file(&out).unwrap();
+        let cli = parse(&["--render-template", "in.yaml", "out.yaml", "echo"]).unwrap();
+        assert_eq!(
+            cli.render_template,
+            Some(vec![PathBuf::from("in.yaml"), PathBuf::from("out.yaml")])
+        );
+        assert!(parse(&["--render-template", "in.yaml", "echo"]).is_err());
+    }
+
+    #[test]
+    fn force_env_only_matters_with_a_template() {
+        let job = |has_template| group::Job {
+            size: None,
+            args: vec![],
+            has_template,
+        };
+        assert!(force_env_without_effect(&[]).is_some());
+        assert!(force_env_without_effect(&[job(false), job(false)]).is_some());
+        assert_eq!(force_env_without_effect(&[job(false), job(true)]), None);
+        assert_eq!(CudaEnv::of(true, true, false), CudaEnv::Forced);
+    }
+
+    #[test]
+    fn cancel_file_stops_waiting_for_the_lock() {
+        let name = format!("gpu-waiter-test-{}-cancel.lock", std::process::id());
+        let cancel_file =
+            std::env::temp_dir().join(format!("gpu-waiter-test-{}.cancel", std::process::id()));
+        let holder = lock::FileRWLock::new(&name, lock::LockType::Flock).unwrap();
+        let waiter = lock::FileRWLock::new(&name, lock::LockType::Flock).unwrap();
+        let guard = holder.write().unwrap();
+        // the budget still applies before the cancel file shows up
+        assert!(lock_global(&waiter, Some(0), Some(&cancel_file), || {})
+            .unwrap()
+            .is_none());
+        std::fs::write(&cancel_file, "").unwrap();
+        assert!(lock_global(&waiter, None, Some(&cancel_file), || {})
+            .unwrap()
+            .is_none());
+        drop(guard);
+        assert!(lock_global(&waiter, None, Some(&cancel_file), || {})
+            .unwrap()
+            .is_some());
+        std::fs::remove_file(&cancel_file).unwrap();
+        std::fs::remove_file(lock::guess_global_runtime_dir().join(name)).unwrap();
+        assert!(parse(&["--cancel-file", "stop", "echo"])
+            .unwrap()
+            .cancel_file
+            .is_some());
+    }
+
+    #[test]
+    fn hold_is_not_an_external_command() {
+        assert!(matches!(
+            parse(&["-n", "2", "hold"]).unwrap().command,
+            Commands::Hold { export: false }
+        ));
+        assert!(matches!(
+            parse(&["hold", "--export"]).unwrap().command,
+            Commands::Hold { export: true }
+        ));
+        assert!(matches!(
+            parse(&["holder", "--export"]).unwrap().command,
+            Commands::External(_)
+        ));
+    }
+
+    #[test]
+    fn subcommand_names_run_as_commands_after_double_dash() {
+        for name in [
+            "bench",
+            "group",
+            "hold",
+            "release",
+            "reserve",
+            "selftest",
+            "wait-for-gpus",
+            "wait-for-pid",
+        ] {
+            assert!(Cli::command().find_subcommand(name).is_some(), "{}", name);
+            let Commands::External(command) = parse(&["--", name, "x"]).unwrap().command else {
+                panic!("expected `{}` to run as a command", name);
+            };
+            assert_eq!(command, [name, "x"]);
+        }
+    }
+}
@@ -0,0 +1,1121 @@
+use std::{cmp::Reverse, collections::HashMap};
+#[cfg(feature = "cuda")]
+use std::{sync::Arc, thread, time::Duration};
+
+use clap::ValueEnum;
+use nvml_wrapper::error::NvmlError;
+
+use parking_lot::RwLock;
+
+#[cfg(feature = "cuda")]
+use anyhow::Context;
+#[cfg(feature = "cuda")]
+use cudarc::{
+    driver::{
+        result, sys, CudaDevice, CudaSlice, DeviceSlice, DriverError, LaunchAsync, LaunchConfig,
+    },
+    nvrtc::Ptx,
+};
+#[cfg(feature = "cuda")]
+use log::{debug, info, warn};
+
+/// Memory held on a GPU to occupy it.
+#[cfg(feature = "cuda")]
+pub(crate) enum Allocation {
+    /// Allocated on the default stream of the device
+    Default(CudaSlice<u8>),
+    /// Allocated on a dedicated stream, see [`LowPriorityStream`]
+    Stream(StreamAllocation),
+    /// Host memory standing in for the GPU memory when CUDA cannot be initialized, see `--nvidia-smi-fallback`
+    Host(Vec<u8>),
+}
+
+#[cfg(feature = "cuda")]
+impl Allocation {
+    pub fn len(&self) -> usize {
+        match self {
+            Allocation::Default(slice) => slice.len(),
+            Allocation::Stream(allocation) => allocation.len,
+            Allocation::Host(buffer) => buffer.capacity(),
+        }
+    }
+}
+
+/// A CUDA stream with the lowest priority, so that the occupation does not compete with the command's work.
+#[cfg(feature = "cuda")]
+pub(crate) struct LowPriorityStream {
+    cuda_dev: Arc<CudaDevice>,
+    stream: sys::CUstream,
+}
+
+// SAFETY: a CUDA stream can be used from any thread, as long as its context is bound to the thread.
+#[cfg(feature = "cuda")]
+unsafe impl Send for LowPriorityStream {}
+#[cfg(feature = "cuda")]
+unsafe impl Sync for LowPriorityStream {}
+
+#[cfg(feature = "cuda")]
+impl LowPriorityStream {
+    pub fn new(cuda_dev: Arc<CudaDevice>) -> Result<Self, DriverError> {
+        cuda_dev.bind_to_thread()?;
+        let (mut least, mut greatest) = (0, 0);
+        let mut stream = std::mem::MaybeUninit::uninit();
+        // SAFETY: the context of the device is bound to the current thread, and the pointers are valid.
+        let stream = unsafe {
+            let lib = sys::lib();
+            lib.cuCtxGetStreamPriorityRange(&mut least, &mut greatest)
+                .result()?;
+            // numerically greater values mean lower priorities
+            lib.cuStreamCreateWithPriority(
+                stream.as_mut_ptr(),
+                sys::CUstream_flags::CU_STREAM_NON_BLOCKING as u32,
+                least,
+            )
+            .result()?;
+            stream.assume_init()
+        };
+        Ok(Self { cuda_dev, stream })
+    }
+}
+
+#[cfg(feature = "cuda")]
+impl Drop for LowPriorityStream {
+    fn drop(&mut self) {
+        let _ = self.cuda_dev.bind_to_thread();
+        // SAFETY: the stream is created in `new`, and all the allocations on it are freed, as they hold an `Arc` to it.
+        if let Err(err) = unsafe { result::stream::destroy(self.stream) } {
+            warn!("Failed to destroy the CUDA stream: {}", err);
+        }
+    }
+}
+
+/// Memory allocated on a [`LowPriorityStream`], freed when dropped.
+#[cfg(feature = "cuda")]
+pub(crate) struct StreamAllocation {
+    stream: Arc<LowPriorityStream>,
+    ptr: sys::CUdeviceptr,
+    len: usize,
+}
+
+#[cfg(feature = "cuda")]
+impl Drop for StreamAllocation {
+    fn drop(&mut self) {
+        let _ = self.stream.cuda_dev.bind_to_thread();
+        // SAFETY: the memory is allocated on this stream in `occupy_gpu`, and is never used by anything else.
+        let result = unsafe {
+            result::free_async(self.ptr, self.stream.stream)
+                .and_then(|_| result::stream::synchronize(self.stream.stream))
+        };
+        if let Err(err) = result {
+            warn!("Failed to free the occupied memory: {}", err);
+        }
+    }
+}
+
+/// Where to allocate the memory occupying a GPU.
+#[cfg(feature = "cuda")]
+#[derive(Clone)]
+pub(crate) enum OccupyTarget {
+    /// The default stream of the device
+    Device(Arc<CudaDevice>),
+    /// A dedicated low-priority stream, see `--cudarc-stream`
+    Stream(Arc<LowPriorityStream>),
+}
+
+/// Memory held on a GPU to occupy it. Without CUDA support, no allocation can ever be made.
+#[cfg(not(feature = "cuda"))]
+pub(crate) enum Allocation {}
+
+/// Holds the memory occupying the GPUs, and releases all of it when dropped, even when unwinding from a panic.
+#[derive(Default)]
+pub(crate) struct GpuAllocationGuard {
+    devices: Vec<(u32, Vec<Allocation>)>,
+}
+
+impl GpuAllocationGuard {
+    /// Add an allocation occupying the given GPU, in addition to the existing ones.
+    #[cfg_attr(not(feature = "cuda"), allow(dead_code))]
+    pub fn push(&mut self, index: u32, allocation: Allocation) {
+        match self.devices.iter_mut().find(|(i, _)| *i == index) {
+            Some((_, allocations)) => allocations.push(allocation),
+            None => self.devices.push((index, vec![allocation])),
+        }
+    }
+
+    #[cfg_attr(not(feature = "cuda"), allow(dead_code))]
+    pub fn contains(&self, index: u32) -> bool {
+        self.devices.iter().any(|(i, _)| *i == index)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// The number of bytes occupying the given GPU.
+    #[cfg(feature = "cuda")]
+    pub fn allocated(&self, index: u32) -> usize {
+        self.devices
+            .iter()
+            .filter(|(i, _)| *i == index)
+            .flat_map(|(_, allocations)| allocations)
+            .map(|allocation| allocation.len())
+            .sum()
+    }
+
+    /// Release allocations on the given GPU until at least `bytes` are freed, or nothing is left.
+    /// The GPU is still considered occupied afterwards.
+    #[cfg(feature = "cuda")]
+    pub fn shrink(&mut self, index: u32, bytes: usize) {
+        if let Some((_, allocations)) = self.devices.iter_mut().find(|(i, _)| *i == index) {
+            let mut freed = 0;
+            while freed < bytes {
+                let Some(allocation) = allocations.pop() else {
+                    break;
+                };
+                freed += allocation.len();
+            }
+        }
+    }
+
+    /// Indices of the GPUs still occupied.
+    pub fn indices(&self) -> Vec<u32> {
+        self.devices.iter().map(|(i, _)| *i).collect()
+    }
+
+    /// Release the memory occupying the given GPU.
+    pub fn release(&mut self, index: u32) {
+        self.devices.retain(|(i, _)| *i != index);
+    }
+}
+
+impl Drop for GpuAllocationGuard {
+    fn drop(&mut self) {
+        if !self.devices.is_empty() {
+            log::info!("Releasing occupied GPUs: {:?}", self.indices());
+            self.devices.clear();
+        }
+    }
+}
+
+/// The delay before the first retry of CUDA context creation. It doubles after each failed attempt.
+#[cfg(feature = "cuda")]
+const CUDA_INIT_BACKOFF: Duration = Duration::from_millis(200);
+
+/// A kernel writing `i * 0x9E3779B1 ^ 0x5A5A5A5A` to `out[i]`, for [`check_driver_health`].
+///
+/// It is plain PTX rather than CUDA C, so that it is JIT-compiled by the driver without NVRTC.
+#[cfg(feature = "cuda")]
+const HEALTH_CHECK_PTX: &str = r"
+.version 6.0
+.target sm_50
+.address_size 64
+
+.visible .entry fill_pattern(
+    .param .u64 out,
+    .param .u32 n
+)
+{
+    .reg .pred %p<2>;
+    .reg .b32 %r<8>;
+    .reg .b64 %rd<4>;
+
+    ld.param.u64 %rd1, [out];
+    ld.param.u32 %r1, [n];
+    mov.u32 %r2, %ctaid.x;
+    mov.u32 %r3, %ntid.x;
+    mov.u32 %r4, %tid.x;
+    mad.lo.s32 %r5, %r2, %r3, %r4;
+    setp.ge.u32 %p1, %r5, %r1;
+    @%p1 bra DONE;
+    cvta.to.global.u64 %rd2, %rd1;
+    mul.wide.u32 %rd3, %r5, 4;
+    add.s64 %rd2, %rd2, %rd3;
+    mul.lo.s32 %r6, %r5, 0x9E3779B1;
+    xor.b32 %r7, %r6, 0x5A5A5A5A;
+    st.global.u32 [%rd2], %r7;
+DONE:
+    ret;
+}
+";
+
+/// How many `u32`s the health check writes and reads back, i.e. 4 MiB.
+#[cfg(feature = "cuda")]
+const HEALTH_CHECK_LEN: u32 = 1 << 20;
+
+/// Run a small kernel filling a buffer with a known pattern, and verify it after reading it back,
+/// to catch GPUs that compute silently wrong results, e.g. due to memory corruption.
+#[cfg(feature = "cuda")]
+pub(crate) fn check_driver_health(cuda_dev: &Arc<CudaDevice>) -> anyhow::Result<()> {
+    cuda_dev.load_ptx(
+        Ptx::from_src(HEALTH_CHECK_PTX),
+        "gpu_waiter_health",
+        &["fill_pattern"],
+    )?;
+    let kernel = cuda_dev
+        .get_func("gpu_waiter_health", "fill_pattern")
+        .context("The health check kernel is not loaded")?;
+    let mut buf = cuda_dev.alloc_zeros::<u32>(HEALTH_CHECK_LEN as usize)?;
+    // SAFETY: the kernel takes a pointer to `n` u32s and `n`, and only writes within them.
+    unsafe {
+        kernel.launch(
+            LaunchConfig::for_num_elems(HEALTH_CHECK_LEN),
+            (&mut buf, HEALTH_CHECK_LEN),
+        )
+    }?;
+    let result = cuda_dev.dtoh_sync_copy(&buf)?;
+    let wrong = (0..HEALTH_CHECK_LEN)
+        .zip(&result)
+        .filter(|(i, v)| i.wrapping_mul(0x9E3779B1) ^ 0x5A5A5A5A != **v)
+        .count();
+    if wrong > 0 {
+        anyhow::bail!(
+            "{} of {} values computed by the health check kernel are wrong",
+            wrong,
+            HEALTH_CHECK_LEN
+        );
+    }
+    Ok(())
+}
+
+/// A kernel keeping the GPU busy: each thread runs `iters` dependent FMAs and stores the result, so that nothing is optimized away.
+#[cfg(feature = "cuda")]
+const STRESS_PTX: &str = r"
+.version 6.0
+.target sm_50
+.address_size 64
+
+.visible .entry spin(
+    .param .u64 out,
+    .param .u32 iters
+)
+{
+    .reg .pred %p<2>;
+    .reg .b32 %r<7>;
+    .reg .f32 %f<2>;
+    .reg .b64 %rd<4>;
+
+    ld.param.u64 %rd1, [out];
+    ld.param.u32 %r1, [iters];
+    mov.u32 %r2, %ctaid.x;
+    mov.u32 %r3, %ntid.x;
+    mov.u32 %r4, %tid.x;
+    mad.lo.s32 %r5, %r2, %r3, %r4;
+    cvt.rn.f32.u32 %f1, %r5;
+    mov.u32 %r6, 0;
+LOOP:
+    fma.rn.f32 %f1, %f1, 0f3F7FFFEF, 0f3F800000;
+    add.u32 %r6, %r6, 1;
+    setp.lt.u32 %p1, %r6, %r1;
+    @%p1 bra LOOP;
+    cvta.to.global.u64 %rd2, %rd1;
+    mul.wide.u32 %rd3, %r5, 4;
+    add.s64 %rd2, %rd2, %rd3;
+    st.global.f32 [%rd2], %f1;
+    ret;
+}
+";
+
+/// Threads per launch of the stress kernel.
+#[cfg(feature = "cuda")]
+const STRESS_THREADS: u32 = 1 << 20;
+/// FMAs per thread in each launch of the stress kernel, short enough for the deadline to be checked often.
+#[cfg(feature = "cuda")]
+const STRESS_ITERS: u32 = 1 << 12;
+
+/// Keep the GPU busy with the stress kernel until `deadline` or Ctrl+C, returning the number of launches.
+#[cfg(feature = "cuda")]
+pub(crate) fn stress(
+    cuda_dev: &Arc<CudaDevice>,
+    deadline: std::time::Instant,
+) -> anyhow::Result<u64> {
+    cuda_dev.load_ptx(Ptx::from_src(STRESS_PTX), "gpu_waiter_stress", &["spin"])?;
+    let kernel = cuda_dev
+        .get_func("gpu_waiter_stress", "spin")
+        .context("The stress kernel is not loaded")?;
+    let mut buf = cuda_dev.alloc_zeros::<f32>(STRESS_THREADS as usize)?;
+    let mut launches = 0;
+    while std::time::Instant::now() < deadline
+        && !crate::STOPPED.load(std::sync::atomic::Ordering::Relaxed)
+    {
+        // SAFETY: the kernel takes a pointer to one f32 per thread, and only writes its own.
+        unsafe {
+            kernel.clone().launch(
+                LaunchConfig::for_num_elems(STRESS_THREADS),
+                (&mut buf, STRESS_ITERS),
+            )
+        }?;
+        cuda_dev.synchronize()?;
+        launches += 1;
+    }
+    Ok(launches)
+}
+
+/// Reset the primary CUDA context of a GPU, like `cudaDeviceReset`, so that the occupation starts from a clean state,
+/// and clear the NVML accounting of the processes that used it, which needs root privileges.
+///
+/// Only the context of this process is reset: the ones of other processes are torn down by the driver when they exit.
+#[cfg(feature = "cuda")]
+pub(crate) fn reset_cuda_device(index: u32) -> anyhow::Result<()> {
+    // let the pending work finish before the context is destroyed
+    CudaDevice::new(index as usize)?.synchronize()?;
+    let dev = result::device::get(index as i32)?;
+    // SAFETY: the only handle to the primary context of the device was dropped above.
+    unsafe { sys::lib().cuDevicePrimaryCtxReset_v2(dev).result()? };
+    if let Err(e) = crate::NVML
+        .wait()
+        .device_by_index(index)
+        .and_then(|mut device| device.clear_accounting_pids())
+    {
+        warn!(
+            "Failed to clear the accounting of the processes on GPU {}: {}",
+            index, e
+        );
+    }
+    Ok(())
+}
+
+/// Create a CUDA context on the given device, retrying up to `retries` times with an exponential backoff.
+///
+/// Context creation may transiently fail with "all CUDA-capable devices are busy" when processes are
+/// relaunched rapidly, or under MPS.
+#[cfg(feature = "cuda")]
+pub(crate) fn init_cuda_device(index: u32, retries: u32) -> Result<Arc<CudaDevice>, DriverError> {
+    retry_with_backoff(retries, CUDA_INIT_BACKOFF, |attempt| {
+        let result = CudaDevice::new(index as usize);
+        if let Err(err) = &result {
+            if attempt < retries {
+                warn!(
+                    "Failed to initialize CUDA on GPU {} (attempt {}/{}): {}",
+                    index,
+                    attempt + 1,
+                    retries + 1,
+                    err
+                );
+            }
+        }
+        result
+    })
+}
+
+/// Call `f` until it succeeds or has been retried `retries` times. `f` is given the index of the attempt.
+#[cfg(feature = "cuda")]
+fn retry_with_backoff<T, E>(
+    retries: u32,
+    initial_delay: Duration,
+    mut f: impl FnMut(u32) -> Result<T, E>,
+) -> Result<T, E> {
+    let mut delay = initial_delay;
+    let mut attempt = 0;
+    loop {
+        match f(attempt) {
+            Ok(v) => return Ok(v),
+            Err(e) if attempt >= retries => return Err(e),
+            Err(_) => {
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Convert a size in bytes to `usize`, failing instead of truncating it where `usize` is narrower than 64 bits.
+#[cfg(feature = "cuda")]
+pub(crate) fn checked_bytes(bytes: u64) -> anyhow::Result<usize> {
+    usize::try_from(bytes).map_err(|_| {
+        anyhow::anyhow!(
+            "Cannot occupy {} MiB, which exceeds the address space of this platform",
+            bytes >> 20
+        )
+    })
+}
+
+/// The total and free memory of a GPU in bytes, as reported by `nvidia-smi`, for when CUDA cannot be initialized but
+/// the GPU should still be occupied, see `--nvidia-smi-fallback`.
+#[cfg(feature = "cuda")]
+pub(crate) fn nvidia_smi_memory(index: u32) -> anyhow::Result<(u64, u64)> {
+    let output = std::process::Command::new("nvidia-smi")
+        .args([
+            "--query-gpu=memory.total,memory.free",
+            "--format=csv",
+            "-i",
+            &index.to_string(),
+        ])
+        .output()
+        .context("Failed to run nvidia-smi")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "nvidia-smi failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    parse_nvidia_smi_memory(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Parse the output of `nvidia-smi --query-gpu=memory.total,memory.free --format=csv` for a single GPU, e.g.
+///
+/// ```text
+/// memory.total [MiB], memory.free [MiB]
+/// 81920 MiB, 81037 MiB
+/// ```
+#[cfg(feature = "cuda")]
+fn parse_nvidia_smi_memory(output: &str) -> anyhow::Result<(u64, u64)> {
+    let mib = |field: &str| -> anyhow::Result<u64> {
+        let value: u64 = field
+            .trim()
+            .strip_suffix("MiB")
+            .and_then(|value| value.trim().parse().ok())
+            .with_context(|| {
+                format!(
+                    "Unexpected memory size in the output of nvidia-smi: {:?}",
+                    field
+                )
+            })?;
+        Ok(value << 20)
+    };
+    let (total, free) = output
+        .lines()
+        .nth(1)
+        .and_then(|line| line.split_once(','))
+        .with_context(|| format!("Unexpected output of nvidia-smi: {:?}", output))?;
+    Ok((mib(total)?, mib(free)?))
+}
+
+/// Reserve `bytes` of host memory as a stand-in occupation, see [`Allocation::Host`].
+///
+/// The memory is only reserved and never touched, so it takes address space but hardly any physical memory.
+#[cfg(feature = "cuda")]
+pub(crate) fn occupy_host(bytes: usize) -> anyhow::Result<Allocation> {
+    let mut buffer = Vec::new();
+    buffer
+        .try_reserve_exact(bytes)
+        .with_context(|| format!("Failed to reserve {} MiB of host memory", bytes >> 20))?;
+    Ok(Allocation::Host(buffer))
+}
+
+/// The GPU whose next allocation fails, to exercise the error handling without a real failure.
+/// Only available in debug builds, see `--inject-alloc-failure`.
+#[cfg(all(feature = "cuda", debug_assertions))]
+pub(crate) static INJECT_ALLOC_FAILURE: parking_lot::Mutex<Option<u32>> =
+    parking_lot::const_mutex(None);
+
+/// Whether the next allocation on the given GPU should fail, see [`INJECT_ALLOC_FAILURE`]. Only the first one does.
+#[cfg(all(feature = "cuda", debug_assertions))]
+fn take_injected_failure(index: u32) -> bool {
+    let mut injected = INJECT_ALLOC_FAILURE.lock();
+    if *injected == Some(index) {
+        *injected = None;
+        true
+    } else {
+        false
+    }
+}
+
+/// Occupy `bytes` of memory on the given target. The memory is released when the returned allocation is dropped.
+///
+/// If `lazy` is set, the memory is allocated without being zeroed, which is much faster for large sizes.
+/// The driver still backs the whole allocation with device memory, so nobody else can allocate it.
+#[cfg(feature = "cuda")]
+pub(crate) fn occupy_gpu(
+    target: &OccupyTarget,
+    bytes: usize,
+    lazy: bool,
+) -> anyhow::Result<Allocation> {
+    occupy(target, bytes, lazy)
+}
+
+/// The driver calls behind [`occupy_gpu`].
+#[cfg(feature = "cuda")]
+trait Memory {
+    /// Allocate `bytes` of memory without initializing it.
+    fn alloc(&self, bytes: usize) -> anyhow::Result<Allocation>;
+    /// Zero an allocation made by [`Memory::alloc`].
+    fn zero(&self, allocation: &mut Allocation) -> anyhow::Result<()>;
+}
+
+#[cfg(feature = "cuda")]
+impl Memory for OccupyTarget {
+    fn alloc(&self, bytes: usize) -> anyhow::Result<Allocation> {
+        #[cfg(debug_assertions)]
+        {
+            let ordinal = match self {
+                OccupyTarget::Device(cuda_dev) => cuda_dev.ordinal(),
+                OccupyTarget::Stream(stream) => stream.cuda_dev.ordinal(),
+            };
+            if take_injected_failure(ordinal as u32) {
+                anyhow::bail!("Injected allocation failure on GPU {}", ordinal);
+            }
+        }
+        match self {
+            OccupyTarget::Device(cuda_dev) => {
+                // SAFETY: the memory is never read, so it does not matter that it is uninitialized.
+                Ok(Allocation::Default(unsafe { cuda_dev.alloc::<u8>(bytes)? }))
+            }
+            OccupyTarget::Stream(stream) => {
+                stream.cuda_dev.bind_to_thread()?;
+                // SAFETY: the memory is only used on this stream, and is never read.
+                let ptr = unsafe { result::malloc_async(stream.stream, bytes)? };
+                Ok(Allocation::Stream(StreamAllocation {
+                    stream: stream.clone(),
+                    ptr,
+                    len: bytes,
+                }))
+            }
+        }
+    }
+
+    fn zero(&self, allocation: &mut Allocation) -> anyhow::Result<()> {
+        match (self, allocation) {
+            (OccupyTarget::Device(cuda_dev), Allocation::Default(slice)) => {
+                cuda_dev.memset_zeros(slice)?
+            }
+            (OccupyTarget::Stream(stream), Allocation::Stream(allocation)) => {
+                // SAFETY: the memory is allocated with `len` bytes on the same stream.
+                unsafe {
+                    result::memset_d8_async(allocation.ptr, 0, allocation.len, stream.stream)?;
+                    result::stream::synchronize(stream.stream)?;
+                }
+            }
+            _ => unreachable!("the allocation is made on another target"),
+        }
+        Ok(())
+    }
+}
+
+/// Allocate `bytes`, zeroing them unless `lazy`. The size is the same either way.
+#[cfg(feature = "cuda")]
+fn occupy(memory: &impl Memory, bytes: usize, lazy: bool) -> anyhow::Result<Allocation> {
+    let mut allocation = memory.alloc(bytes)?;
+    if !lazy {
+        memory.zero(&mut allocation)?;
+    }
+    Ok(allocation)
+}
+
+/// Occupy GPUs from `candidates` in order until `num` of them are, and return them. `occupy` is given each GPU and the
+/// ones occupied so far, and returns whether it occupied the GPU. Otherwise, it has given the GPU up and the next
+/// candidate replaces it.
+///
+/// If the candidates run out or `occupy` fails, the GPUs occupied so far are released from `guard` again.
+pub(crate) fn occupy_enough(
+    candidates: impl IntoIterator<Item = u32>,
+    num: usize,
+    guard: &RwLock<GpuAllocationGuard>,
+    mut occupy: impl FnMut(u32, &[u32]) -> anyhow::Result<bool>,
+) -> anyhow::Result<Vec<u32>> {
+    let mut occupied = Vec::with_capacity(num);
+    let mut candidates = candidates.into_iter();
+    let result = loop {
+        if occupied.len() >= num {
+            break Ok(());
+        }
+        let Some(i) = candidates.next() else {
+            break Err(anyhow::anyhow!(
+                "Failed to occupy enough GPUs, and there are no more idle GPUs to try"
+            ));
+        };
+        match occupy(i, &occupied) {
+            Ok(true) => occupied.push(i),
+            Ok(false) => {}
+            Err(err) => break Err(err),
+        }
+    };
+    if let Err(err) = result {
+        let mut guard = guard.write();
+        for &i in &occupied {
+            guard.release(i);
+        }
+        return Err(err);
+    }
+    Ok(occupied)
+}
+
+/// The order in which the selected GPUs are occupied.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum OccupyOrder {
+    /// In the order the GPUs were selected, e.g. ascending indices, or as given by --select
+    Index,
+    /// The GPU with the least free memory first, so that the hardest one fails before the others are occupied
+    ScarceFirst,
+    /// The GPU with the most free memory first
+    AmpleFirst,
+}
+
+impl OccupyOrder {
+    /// Reorder the GPUs in place. GPUs with the same free memory keep their relative order.
+    pub fn sort(self, devices: &mut [u32]) -> Result<(), NvmlError> {
+        if self == OccupyOrder::Index {
+            return Ok(());
+        }
+        let nvml = crate::NVML.wait();
+        let mut free = HashMap::with_capacity(devices.len());
+        for &i in devices.iter() {
+            free.insert(i, nvml.device_by_index(i)?.memory_info()?.free);
+        }
+        self.sort_by_free(devices, &free);
+        Ok(())
+    }
+
+    fn sort_by_free(self, devices: &mut [u32], free: &HashMap<u32, u64>) {
+        match self {
+            OccupyOrder::Index => {}
+            OccupyOrder::ScarceFirst => devices.sort_by_key(|i| free[i]),
+            OccupyOrder::AmpleFirst => devices.sort_by_key(|i| Reverse(free[i])),
+        }
+    }
+}
+
+/// Parse a fraction in (0, 1].
+pub(crate) fn parse_fraction(s: &str) -> Result<f64, String> {
+    let f: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if f > 0.0 && f <= 1.0 {
+        Ok(f)
+    } else {
+        Err(format!("expected a fraction in (0, 1], got {}", f))
+    }
+}
+
+/// Parse a positive weight, see `--occupy-weights`.
+pub(crate) fn parse_weight(s: &str) -> Result<f64, String> {
+    let w: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if w > 0.0 && w.is_finite() {
+        Ok(w)
+    } else {
+        Err(format!("expected a positive weight, got {}", w))
+    }
+}
+
+/// The share of the occupation of GPU `i`, i.e. its weight divided by the largest one, see `--occupy-weights`.
+///
+/// `weights` belong to the `selected` GPUs in order. A spare GPU takes the weight of the first selected one it replaces,
+/// given the GPUs `occupied` so far.
+#[cfg(feature = "cuda")]
+pub(crate) fn weight_scale(weights: &[f64], selected: &[u32], i: u32, occupied: &[u32]) -> f64 {
+    let max = weights.iter().copied().fold(f64::MIN, f64::max);
+    let weight = match selected.iter().position(|&j| j == i) {
+        Some(k) => weights[k],
+        None => {
+            let spares_used = occupied.iter().filter(|j| !selected.contains(j)).count();
+            (0..selected.len())
+                .filter(|&k| !occupied.contains(&selected[k]))
+                .nth(spares_used)
+                .map_or(max, |k| weights[k])
+        }
+    };
+    weight / max
+}
+
+/// The fraction of the free memory the occupation starts with and is raised by each time, see [`RampUp`].
+#[cfg(feature = "cuda")]
+const RAMP_UP_STEP: f64 = 0.05;
+
+/// Gradually raises the occupation of the GPUs to the target fraction of their free memory,
+/// so that the command has time to claim its own memory before the occupation is maximized.
+#[cfg(feature = "cuda")]
+pub(crate) struct RampUp {
+    /// The target and the free memory before the occupation, of each GPU
+    devices: Vec<(u32, OccupyTarget, u64)>,
+    fraction: f64,
+    target: f64,
+    lazy: bool,
+}
+
+#[cfg(feature = "cuda")]
+impl RampUp {
+    pub fn new(target: f64, lazy: bool) -> Self {
+        Self {
+            devices: vec![],
+            fraction: RAMP_UP_STEP.min(target),
+            target,
+            lazy,
+        }
+    }
+
+    /// The fraction of the free memory currently occupied.
+    pub fn fraction(&self) -> f64 {
+        self.fraction
+    }
+
+    pub fn add(&mut self, index: u32, target: OccupyTarget, free_mem: u64) {
+        self.devices.push((index, target, free_mem));
+    }
+
+    /// Raise the occupation by one step on the GPUs that are still occupied. Returns whether the target is reached.
+    pub fn step(&mut self, guard: &RwLock<GpuAllocationGuard>) -> bool {
+        let next = (self.fraction + RAMP_UP_STEP).min(self.target);
+        for (i, target, free_mem) in &self.devices {
+            // keep the write lock, so that the GPU is not released by the monitor in the meantime
+            let mut guard = guard.write();
+            if !guard.contains(*i) {
+                continue;
+            }
+            let bytes = ((next - self.fraction) * *free_mem as f64) as u64;
+            match checked_bytes(bytes).and_then(|bytes| occupy_gpu(target, bytes, self.lazy)) {
+                Ok(out) => guard.push(*i, out),
+                Err(err) => warn!("Failed to raise the occupation of GPU {}: {}", i, err),
+            }
+        }
+        self.fraction = next;
+        info!("Occupying {:.0}% of the free memory", next * 100.0);
+        next >= self.target
+    }
+}
+
+/// The granularity of the occupation with `--dynamic-fraction`, so that it can be partially released.
+#[cfg(feature = "cuda")]
+const DYNAMIC_CHUNK: usize = 64 << 20;
+
+/// How much room is left for the memory used by others, relative to what they use, with `--dynamic-fraction`.
+#[cfg(feature = "cuda")]
+const DYNAMIC_HEADROOM: f64 = 1.1;
+
+#[cfg(feature = "cuda")]
+struct DynamicDevice {
+    index: u32,
+    target: OccupyTarget,
+    /// Memory info before the occupation
+    free_at_start: u64,
+    used_at_start: u64,
+    /// The initial occupation, which is never exceeded
+    max_bytes: usize,
+}
+
+/// Resizes the occupation of each GPU according to the memory used by the others (e.g. the command),
+/// instead of releasing the GPU entirely as soon as it is used.
+#[cfg(feature = "cuda")]
+pub(crate) struct DynamicOccupation {
+    devices: Vec<DynamicDevice>,
+    lazy: bool,
+}
+
+#[cfg(feature = "cuda")]
+impl DynamicOccupation {
+    pub fn new(lazy: bool) -> Self {
+        Self {
+            devices: vec![],
+            lazy,
+        }
+    }
+
+    /// Occupy `bytes` of memory on the GPU in chunks. `free` and `used` are measured before the occupation.
+    pub fn occupy(
+        &mut self,
+        index: u32,
+        target: OccupyTarget,
+        (free, used): (u64, u64),
+        bytes: usize,
+        guard: &RwLock<GpuAllocationGuard>,
+    ) -> anyhow::Result<()> {
+        let mut guard = guard.write();
+        let mut allocated = 0;
+        while allocated < bytes {
+            let chunk = DYNAMIC_CHUNK.min(bytes - allocated);
+            guard.push(index, occupy_gpu(&target, chunk, self.lazy)?);
+            allocated += chunk;
+        }
+        self.devices.push(DynamicDevice {
+            index,
+            target,
+            free_at_start: free,
+            used_at_start: used,
+            max_bytes: bytes,
+        });
+        Ok(())
+    }
+
+    /// Shrink or grow the occupation of each GPU, so that the memory used by others has room to grow.
+    pub fn adjust(&self, guard: &RwLock<GpuAllocationGuard>) -> anyhow::Result<()> {
+        for device in &self.devices {
+            let mut guard = guard.write();
+            if !guard.contains(device.index) {
+                continue;
+            }
+            let ours = guard.allocated(device.index);
+            let used = crate::NVML
+                .wait()
+                .device_by_index(device.index)?
+                .memory_info()?
+                .used;
+            let others = used.saturating_sub(device.used_at_start + ours as u64);
+            let target = ((device.free_at_start as f64 - others as f64 * DYNAMIC_HEADROOM).max(0.0)
+                as usize)
+                .min(device.max_bytes);
+            if target + DYNAMIC_CHUNK <= ours {
+                debug!(
+                    "Shrinking the occupation of GPU {} to {} MiB",
+                    device.index,
+                    target >> 20
+                );
+                guard.shrink(device.index, ours - target);
+            } else if target >= ours + DYNAMIC_CHUNK {
+                debug!(
+                    "Growing the occupation of GPU {} to {} MiB",
+                    device.index,
+                    target >> 20
+                );
+                let mut allocated = ours;
+                while allocated + DYNAMIC_CHUNK <= target {
+                    match occupy_gpu(&device.target, DYNAMIC_CHUNK, self.lazy) {
+                        Ok(out) => guard.push(device.index, out),
+                        // the memory may have been taken in the meantime, try again next time
+                        Err(_) => break,
+                    }
+                    allocated += DYNAMIC_CHUNK;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How many times to check whether an occupation is visible in NVML.
+#[cfg(feature = "cuda")]
+const OCCUPANCY_CHECKS: u32 = 5;
+
+#[cfg(feature = "cuda")]
+const OCCUPANCY_CHECK_DELAY: Duration = Duration::from_millis(100);
+
+/// Whether our process shows up among the `count` compute processes, given the `baseline` taken before occupying.
+///
+/// Other processes (e.g. allowed by --process-count-threshold) may already be there, so any count is not enough.
+#[cfg(feature = "cuda")]
+fn occupancy_visible(count: u32, baseline: u32) -> bool {
+    count > baseline
+}
+
+/// Check that our process shows up in NVML as running on the GPU, so that other instances see it as busy.
+///
+/// `baseline` is the number of compute processes before occupying it.
+/// Some drivers report the processes with a delay, so it is retried a few times before giving up with a warning.
+#[cfg(feature = "cuda")]
+pub(crate) fn check_occupancy(index: u32, baseline: u32) -> anyhow::Result<()> {
+    let device = crate::NVML.wait().device_by_index(index)?;
+    for _ in 0..OCCUPANCY_CHECKS {
+        if occupancy_visible(device.running_compute_processes_count()?, baseline) {
+            return Ok(());
+        }
+        thread::sleep(OCCUPANCY_CHECK_DELAY);
+    }
+    warn!(
+        "The occupation of GPU {} is still not visible in NVML after {:?}, other instances may see it as idle",
+        index,
+        OCCUPANCY_CHECK_DELAY * OCCUPANCY_CHECKS
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_fractions() {
+        assert_eq!(parse_fraction("0.5"), Ok(0.5));
+        assert_eq!(parse_fraction("1"), Ok(1.0));
+        assert!(parse_fraction("0").is_err());
+        assert!(parse_fraction("1.5").is_err());
+        assert!(parse_fraction("NaN").is_err());
+        assert!(parse_fraction("half").is_err());
+    }
+
+    #[cfg(feature = "cuda")]
+    #[test]
+    fn checked_bytes_do_not_truncate() {
+        assert_eq!(checked_bytes(1 << 20).unwrap(), 1 << 20);
+        // e.g. 80 GiB free on a 32-bit platform
+        let large = 80u64 << 30;
+        match usize::try_from(large) {
+            Ok(expected) => assert_eq!(checked_bytes(large).unwrap(), expected),
+            Err(_) => assert!(checked_bytes(large).is_err()),
+        }
+        if usize::BITS < u64::BITS {
+            assert_eq!(
+                checked_bytes(u64::MAX).unwrap_err().to_string(),
+                format!(
+                    "Cannot occupy {} MiB, which exceeds the address space of this platform",
+                    u64::MAX >> 20
+                )
+            );
+        } else {
+            assert_eq!(checked_bytes(u64::MAX).unwrap(), usize::MAX);
+        }
+    }
+
+    #[test]
+    fn parses_weights() {
+        assert_eq!(parse_weight("2"), Ok(2.0));
+        assert_eq!(parse_weight("0.5"), Ok(0.5));
+        assert!(parse_weight("0").is_err());
+        assert!(parse_weight("-1").is_err());
+        assert!(parse_weight("inf").is_err());
+        assert!(parse_weight("heavy").is_err());
+    }
+
+    #[cfg(feature = "cuda")]
+    #[test]
+    fn weights_scale_the_occupation() {
+        let weights = [2.0, 1.0, 4.0];
+        let selected = [3, 0, 5];
+        assert_eq!(weight_scale(&weights, &selected, 3, &[]), 0.5);
+        assert_eq!(weight_scale(&weights, &selected, 0, &[3]), 0.25);
+        assert_eq!(weight_scale(&weights, &selected, 5, &[3, 0]), 1.0);
+        // GPU 3 could not be occupied, so the spare GPU 7 replaces it, and then GPU 8 replaces GPU 0
+        assert_eq!(weight_scale(&weights, &selected, 7, &[]), 0.5);
+        assert_eq!(weight_scale(&weights, &selected, 8, &[7]), 0.25);
+        // with every selected GPU already replaced, a spare gets the full fraction
+        assert_eq!(weight_scale(&weights, &selected, 9, &[3, 0, 5]), 1.0);
+    }
+
+    #[test]
+    fn occupy_order() {
+        let free = HashMap::from([(0, 20), (1, 10), (2, 30), (3, 10)]);
+        let sorted = |order: OccupyOrder| {
+            let mut devices = [1, 2, 0, 3];
+            order.sort_by_free(&mut devices, &free);
+            devices
+        };
+        assert_eq!(sorted(OccupyOrder::Index), [1, 2, 0, 3]);
+        // ties keep their order
+        assert_eq!(sorted(OccupyOrder::ScarceFirst), [1, 3, 0, 2]);
+        assert_eq!(sorted(OccupyOrder::AmpleFirst), [2, 0, 1, 3]);
+    }
+
+    #[cfg(all(feature = "cuda", debug_assertions))]
+    #[test]
+    fn injected_failure_only_fails_once() {
+        *INJECT_ALLOC_FAILURE.lock() = Some(1);
+        assert!(!take_injected_failure(0));
+        assert!(take_injected_failure(1));
+        assert!(!take_injected_failure(1));
+    }
+
+    #[cfg(feature = "cuda")]
+    #[test]
+    fn parses_nvidia_smi_memory() {
+        let output = "memory.total [MiB], memory.free [MiB]\n81920 MiB, 81037 MiB\n";
+        assert_eq!(
+            parse_nvidia_smi_memory(output).unwrap(),
+            (81920 << 20, 81037 << 20)
+        );
+        assert!(parse_nvidia_smi_memory("memory.total [MiB], memory.free [MiB]\n").is_err());
+        assert!(parse_nvidia_smi_memory("header\n[N/A], [N/A]\n").is_err());
+    }
+
+    #[cfg(feature = "cuda")]
+    #[test]
+    fn retries_until_success() {
+        let mut attempts = vec![];
+        let result = retry_with_backoff(3, Duration::ZERO, |attempt| {
+            attempts.push(attempt);
+            if attempt < 2 {
+                Err(attempt)
+            } else {
+                Ok("ok")
+            }
+        });
+        assert_eq!(result, Ok("ok"));
+        assert_eq!(attempts, [0, 1, 2]);
+    }
+
+    #[cfg(feature = "cuda")]
+    #[test]
+    fn gives_up_after_the_retries() {
+        let mut calls = 0;
+        let result: Result<(), u32> = retry_with_backoff(2, Duration::ZERO, |attempt| {
+            calls += 1;
+            Err(attempt)
+        });
+        assert_eq!(result, Err(2));
+        assert_eq!(calls, 3);
+    }
+
+    #[cfg(feature = "cuda")]
+    #[test]
+    fn occupancy_is_compared_to_the_baseline() {
+        assert!(occupancy_visible(1, 0));
+        // another process was already there when we occupied it
+        assert!(!occupancy_visible(1, 1));
+        assert!(occupancy_visible(2, 1));
+    }
+
+    /// Host memory standing in for a GPU, recording the driver calls.
+    #[cfg(feature = "cuda")]
+    #[derive(Default)]
+    struct FakeMemory {
+        calls: parking_lot::Mutex<Vec<String>>,
+        /// Whether the allocations fail, like an injected failure
+        failing: bool,
+    }
+
+    #[cfg(feature = "cuda")]
+    impl Memory for FakeMemory {
+        fn alloc(&self, bytes: usize) -> anyhow::Result<Allocation> {
+            self.calls.lock().push(format!("alloc {}", bytes));
+            if self.failing {
+                anyhow::bail!("Injected allocation failure");
+            }
+            Ok(Allocation::Host(Vec::with_capacity(bytes)))
+        }
+
+        fn zero(&self, allocation: &mut Allocation) -> anyhow::Result<()> {
+            self.calls.lock().push(format!("zero {}", allocation.len()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "cuda")]
+    fn lazy_occupation_has_the_same_size() {
+        let eager = FakeMemory::default();
+        assert_eq!(occupy(&eager, 1 << 20, false).unwrap().len(), 1 << 20);
+        assert_eq!(*eager.calls.lock(), ["alloc 1048576", "zero 1048576"]);
+        let lazy = FakeMemory::default();
+        assert_eq!(occupy(&lazy, 1 << 20, true).unwrap().len(), 1 << 20);
+        assert_eq!(*lazy.calls.lock(), ["alloc 1048576"]);
+    }
+
+    /// Occupy GPUs 0 to 2 on fake memory, where the allocation on GPU 1 fails, until `num` are occupied.
+    #[cfg(feature = "cuda")]
+    fn occupy_with_failure(
+        num: usize,
+        candidates: &[u32],
+    ) -> (anyhow::Result<Vec<u32>>, Vec<u32>, GpuAllocationGuard) {
+        let guard = RwLock::new(GpuAllocationGuard::default());
+        let mut tried = vec![];
+        let result = occupy_enough(candidates.iter().copied(), num, &guard, |i, _| {
+            tried.push(i);
+            let memory = FakeMemory {
+                failing: i == 1,
+                ..Default::default()
+            };
+            match occupy(&memory, 1 << 20, false) {
+                Ok(allocation) => {
+                    guard.write().push(i, allocation);
+                    Ok(true)
+                }
+                Err(_) => Ok(false),
+            }
+        });
+        (result, tried, guard.into_inner())
+    }
+
+    #[test]
+    #[cfg(feature = "cuda")]
+    fn allocation_failure_is_replaced() {
+        let (result, tried, guard) = occupy_with_failure(2, &[0, 1, 2]);
+        assert_eq!(result.unwrap(), [0, 2]);
+        assert_eq!(tried, [0, 1, 2]);
+        assert_eq!(guard.indices(), [0, 2]);
+    }
+
+    #[test]
+    #[cfg(feature = "cuda")]
+    fn allocation_failure_without_replacement_releases_the_others() {
+        let (result, tried, guard) = occupy_with_failure(2, &[0, 1]);
+        assert!(result.is_err());
+        assert_eq!(tried, [0, 1]);
+        // the allocation on GPU 0 is dropped
+        assert!(guard.is_empty());
+    }
+}
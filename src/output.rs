@@ -0,0 +1,93 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// The longest line buffered before it is written out. The rest of a longer line follows without a prefix.
+const MAX_LINE: usize = 64 << 10;
+
+/// How long to wait for the output of an exited command to end, since the processes it started may keep it open.
+pub(crate) const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Wait for the threads returned by [`ChildOutput::forward`] to finish for at most `timeout`, leaving the rest running.
+/// Returns whether all of them finished.
+pub(crate) fn drain(forwarders: Vec<JoinHandle<()>>, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while forwarders.iter().any(|f| !f.is_finished()) {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
+
+/// How each line of the child's output is decorated when it is forwarded.
+#[derive(Clone)]
+pub(crate) struct ChildOutput {
+    /// Prepended as is, before the timestamp if any.
+    pub prefix: Option<String>,
+    /// Prepend `[HH:MM:SS.mmm STDOUT]` or `[HH:MM:SS.mmm STDERR]`.
+    pub timestamps: bool,
+}
+
+impl ChildOutput {
+    /// Whether the output has to be piped through gpu-waiter, instead of being inherited by the child.
+    pub fn is_piped(&self) -> bool {
+        self.prefix.is_some() || self.timestamps
+    }
+
+    fn prefix(&self, stream: &str) -> String {
+        let mut prefix = self.prefix.clone().unwrap_or_default();
+        if self.timestamps {
+            prefix += &format!(
+                "[{} {}] ",
+                chrono::Local::now().format("%H:%M:%S%.3f"),
+                stream
+            );
+        }
+        prefix
+    }
+
+    /// Forward the lines read from `reader` to `writer` in a new thread, until the end of `reader`.
+    pub fn forward(
+        &self,
+        reader: impl Read + Send + 'static,
+        mut writer: impl Write + Send + 'static,
+        stream: &'static str,
+    ) -> JoinHandle<()> {
+        let this = self.clone();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(reader);
+            let mut line = Vec::with_capacity(MAX_LINE);
+            let mut at_line_start = true;
+            loop {
+                line.clear();
+                match (&mut reader)
+                    .take(MAX_LINE as u64)
+                    .read_until(b'\n', &mut line)
+                {
+                    Ok(0) => break,
+                    Ok(_) => {}
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        log::warn!("Failed to read the {} of the command: {}", stream, e);
+                        break;
+                    }
+                }
+                let prefix = if at_line_start {
+                    this.prefix(stream)
+                } else {
+                    String::new()
+                };
+                at_line_start = line.ends_with(b"\n");
+                // the terminal may be gone, but the child still has to be drained
+                let _ = writer
+                    .write_all(prefix.as_bytes())
+                    .and_then(|_| writer.write_all(&line))
+                    .and_then(|_| writer.flush());
+            }
+        })
+    }
+}
@@ -0,0 +1,758 @@
+use std::{
+    collections::HashSet,
+    fs, io,
+    net::{Ipv4Addr, TcpListener},
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    sync::atomic::Ordering,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::Sender;
+use itertools::Itertools;
+use log::{info, warn};
+#[cfg(feature = "cuda")]
+use nvml_wrapper::{enums::device::UsedGpuMemory, struct_wrappers::device::ProcessInfo};
+
+use crate::{NVML, STOPPED};
+
+/// A file holding the PID of a process, removed when dropped.
+pub(crate) struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create(path: &Path, pid: u32) -> io::Result<Self> {
+        fs::write(path, format!("{}\n", pid))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            log::warn!(
+                "Failed to remove the pidfile {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// A file signalling that the GPUs are ready, holding their indices, removed when dropped.
+pub(crate) struct ReadyFile {
+    path: PathBuf,
+}
+
+impl ReadyFile {
+    /// Create the file atomically, so that it never appears empty to the scripts waiting for it.
+    pub fn create(path: &Path, gpus: &str) -> io::Result<Self> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, format!("{}\n", gpus))?;
+        fs::rename(&tmp, path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for ReadyFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            log::warn!(
+                "Failed to remove the GPU idle file {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Parse a `KEY=VALUE` pair of an environment variable.
+pub(crate) fn parse_env(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got `{}`", s)),
+    }
+}
+
+/// The rendezvous environment variables of PyTorch distributed, for `world_size` processes on the local machine.
+///
+/// `MASTER_PORT` is a port that is free at the time of the call.
+pub(crate) fn torch_env(world_size: usize) -> io::Result<Vec<(&'static str, String)>> {
+    let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?
+        .local_addr()?
+        .port();
+    Ok(vec![
+        ("MASTER_ADDR", Ipv4Addr::LOCALHOST.to_string()),
+        ("MASTER_PORT", port.to_string()),
+        ("WORLD_SIZE", world_size.to_string()),
+    ])
+}
+
+/// The host name of this machine, or an empty string if it cannot be determined.
+pub(crate) fn hostname() -> String {
+    #[cfg(unix)]
+    {
+        let mut buf = [0u8; 256];
+        // SAFETY: the buffer is valid for its whole length.
+        if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+            return String::new();
+        }
+        let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+    #[cfg(not(unix))]
+    {
+        std::env::var("COMPUTERNAME").unwrap_or_default()
+    }
+}
+
+/// Whether the current process runs as root. Always false on platforms other than Unix.
+pub(crate) fn is_root() -> bool {
+    #[cfg(unix)]
+    {
+        // SAFETY: `geteuid` is always successful.
+        unsafe { libc::geteuid() == 0 }
+    }
+    #[cfg(not(unix))]
+    {
+        false
+    }
+}
+
+/// The user ID owning a process, if it can be determined, i.e. on Linux.
+fn owner(pid: u32) -> Option<u32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        fs::metadata(format!("/proc/{}", pid)).ok().map(|m| m.uid())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = pid;
+        None
+    }
+}
+
+/// The real user ID of the current process, or one matching no user where it cannot be determined.
+fn current_uid() -> u32 {
+    #[cfg(unix)]
+    {
+        // SAFETY: `getuid` is always successful.
+        unsafe { libc::getuid() }
+    }
+    #[cfg(not(unix))]
+    {
+        u32::MAX
+    }
+}
+
+/// The GPU memory held on a GPU by the other processes of the current user, e.g. a previous run that is still exiting.
+///
+/// Zero outside the host PID namespace, where the PIDs reported by NVML cannot be looked up, or if NVML cannot tell.
+#[cfg(feature = "cuda")]
+pub(crate) fn own_residual_memory(index: u32) -> u64 {
+    if !in_host_pid_namespace() {
+        return 0;
+    }
+    let processes = NVML
+        .wait()
+        .device_by_index(index)
+        .and_then(|device| device.running_compute_processes());
+    match processes {
+        Ok(processes) => memory_of_user(&processes, current_uid()),
+        Err(err) => {
+            warn!(
+                "Cannot get the memory held by our own processes on GPU {}, assuming none: {}",
+                index, err
+            );
+            0
+        }
+    }
+}
+
+/// The GPU memory held by the processes of the given user, except the current one. The memory of processes whose owner
+/// cannot be determined is not counted.
+#[cfg(feature = "cuda")]
+fn memory_of_user(processes: &[ProcessInfo], uid: u32) -> u64 {
+    let pid = std::process::id();
+    processes
+        .iter()
+        .filter(|process| process.pid != pid && owner(process.pid) == Some(uid))
+        .map(|process| match process.used_gpu_memory {
+            UsedGpuMemory::Used(bytes) => bytes,
+            UsedGpuMemory::Unavailable => 0,
+        })
+        .sum()
+}
+
+/// The inode of the initial PID namespace, i.e. `PROC_PID_INIT_INO` of Linux.
+#[cfg(unix)]
+const INIT_PID_NAMESPACE: &str = "pid:[4026531836]";
+
+/// Whether the current process is in the PID namespace of the host, where the PIDs reported by NVML belong.
+/// False if it cannot be determined.
+fn in_host_pid_namespace() -> bool {
+    #[cfg(unix)]
+    {
+        fs::read_link("/proc/self/ns/pid").is_ok_and(|ns| ns.as_os_str() == INIT_PID_NAMESPACE)
+    }
+    #[cfg(not(unix))]
+    {
+        false
+    }
+}
+
+/// Whether a process is still owned by `uid`, i.e. it has neither exited nor been replaced by another one with its PID.
+fn still_owned_by(pid: u32, uid: u32) -> bool {
+    if owner(pid) == Some(uid) {
+        return true;
+    }
+    info!(
+        "Process {} has exited or changed its owner, leaving it alone",
+        pid
+    );
+    false
+}
+
+/// The compute processes on a GPU other than the current process and the `commands` it started, or their descendants,
+/// i.e. the ones that preempted it while the commands run.
+///
+/// Always empty outside of the PID namespace of the host, where the PIDs reported by NVML cannot be told apart.
+pub(crate) fn foreign_processes(index: u32, commands: &HashSet<u32>) -> anyhow::Result<Vec<u32>> {
+    if !in_host_pid_namespace() {
+        return Ok(vec![]);
+    }
+    let mut own = commands.clone();
+    own.insert(std::process::id());
+    #[cfg(unix)]
+    own.extend(commands.iter().flat_map(|pid| descendants(*pid)));
+    let processes = NVML
+        .wait()
+        .device_by_index(index)?
+        .running_compute_processes()?;
+    Ok(not_in(processes.iter().map(|process| process.pid), &own))
+}
+
+/// The PIDs that are not in `own`.
+fn not_in(pids: impl IntoIterator<Item = u32>, own: &HashSet<u32>) -> Vec<u32> {
+    pids.into_iter().filter(|pid| !own.contains(pid)).collect()
+}
+
+/// Terminate the compute processes of other users on the given GPUs, and kill the ones still running after `grace_period`.
+///
+/// Processes whose owner cannot be determined are left alone. Refuses to run outside of the PID namespace of the host,
+/// e.g. in a container, where the PIDs reported by NVML may belong to unrelated processes.
+pub(crate) fn kill_other_users(devices: &[u32], grace_period: Duration) -> anyhow::Result<()> {
+    if !in_host_pid_namespace() {
+        anyhow::bail!(
+            "--kill-other-users only works in the PID namespace of the host, where the PIDs reported by NVML belong"
+        );
+    }
+    let me = current_uid();
+    let mut victims = vec![];
+    for &index in devices {
+        for process in NVML
+            .wait()
+            .device_by_index(index)?
+            .running_compute_processes()?
+        {
+            match owner(process.pid) {
+                Some(uid) if uid != me => victims.push((index, process.pid, uid)),
+                Some(_) => {}
+                None => warn!(
+                    "Cannot tell the owner of process {} on GPU {}, leaving it alone",
+                    process.pid, index
+                ),
+            }
+        }
+    }
+    // a process may use several GPUs
+    let victims = victims
+        .into_iter()
+        .unique_by(|(_, pid, _)| *pid)
+        .collect::<Vec<_>>();
+    if victims.is_empty() {
+        return Ok(());
+    }
+
+    for (index, pid, uid) in &victims {
+        if !still_owned_by(*pid, *uid) {
+            continue;
+        }
+        warn!(
+            "Terminating process {} of user {} on GPU {}",
+            pid, uid, index
+        );
+        if let Err(e) = terminate(*pid) {
+            warn!("Failed to terminate process {}: {}", pid, e);
+        }
+    }
+    let deadline = Instant::now() + grace_period;
+    while Instant::now() < deadline && !STOPPED.load(Ordering::Relaxed) {
+        if victims.iter().all(|(_, pid, _)| !is_alive(*pid)) {
+            info!("All processes of other users have exited");
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    for (_, pid, uid) in victims.iter().filter(|(_, pid, _)| is_alive(*pid)) {
+        if !still_owned_by(*pid, *uid) {
+            continue;
+        }
+        warn!(
+            "Process {} is still running {:?} after being asked to terminate, killing it",
+            pid, grace_period
+        );
+        if let Err(e) = kill(*pid) {
+            warn!("Failed to kill process {}: {}", pid, e);
+        }
+    }
+    Ok(())
+}
+
+/// Ask a process to exit gracefully, i.e. send `SIGTERM` on Unix. A process that is already gone is not an error.
+pub(crate) fn terminate(pid: u32) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        ignore_gone(send_signal(pid, libc::SIGTERM))
+    }
+    #[cfg(not(unix))]
+    {
+        taskkill(pid, false)
+    }
+}
+
+/// Kill a process forcibly, i.e. send `SIGKILL` on Unix. A process that is already gone is not an error.
+pub(crate) fn kill(pid: u32) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        ignore_gone(send_signal(pid, libc::SIGKILL))
+    }
+    #[cfg(not(unix))]
+    {
+        taskkill(pid, true)
+    }
+}
+
+/// Block until a child process has exited, without reaping it, so that its PID is not reused in the meantime.
+///
+/// Returns immediately on platforms other than Unix.
+pub(crate) fn wait_exited(pid: u32) -> io::Result<()> {
+    #[cfg(unix)]
+    loop {
+        // SAFETY: `siginfo_t` is plain data, for which all zeros is a valid value.
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        // SAFETY: `info` is valid for writes.
+        let ret = unsafe {
+            libc::waitid(
+                libc::P_PID,
+                pid as libc::id_t,
+                &mut info,
+                libc::WEXITED | libc::WNOWAIT,
+            )
+        };
+        if ret == 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = pid;
+        Ok(())
+    }
+}
+
+/// Check whether a process is still running. On platforms other than Unix, it is always assumed to be.
+pub(crate) fn is_alive(pid: u32) -> bool {
+    #[cfg(unix)]
+    {
+        // signal 0 only checks for existence; EPERM means it exists but belongs to another user
+        match send_signal(pid, 0) {
+            Ok(()) => true,
+            Err(e) => e.raw_os_error() == Some(libc::EPERM),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = pid;
+        true
+    }
+}
+
+/// The PIDs of the processes descended from `pid`, found by scanning `/proc`. Empty where there is no `/proc`.
+#[cfg(unix)]
+fn descendants(pid: u32) -> Vec<u32> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return vec![];
+    };
+    let parents: Vec<(u32, u32)> = entries
+        .filter_map(|entry| {
+            let child = entry.ok()?.file_name().to_str()?.parse().ok()?;
+            let stat = fs::read_to_string(format!("/proc/{}/stat", child)).ok()?;
+            // the name in parentheses may contain spaces, and the parent PID is the second field after it
+            let parent = stat[stat.rfind(')')? + 1..]
+                .split_whitespace()
+                .nth(1)?
+                .parse()
+                .ok()?;
+            Some((child, parent))
+        })
+        .collect();
+    let mut result = vec![];
+    let mut queue = vec![pid];
+    while let Some(parent) = queue.pop() {
+        for &(child, _) in parents.iter().filter(|(_, p)| *p == parent) {
+            result.push(child);
+            queue.push(child);
+        }
+    }
+    result
+}
+
+/// Set the niceness of a process and its descendants, and thus of the processes they spawn later.
+///
+/// On Linux, where the niceness belongs to each thread, all their current threads are reniced. The descendants are
+/// only found on systems with `/proc`; elsewhere only the process itself is reniced.
+pub(crate) fn set_niceness(pid: u32, nice: i32) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        for pid in std::iter::once(pid).chain(descendants(pid)) {
+            let tids: Vec<u32> = match fs::read_dir(format!("/proc/{}/task", pid)) {
+                Ok(entries) => entries
+                    .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+                    .collect(),
+                Err(_) => vec![pid],
+            };
+            for tid in tids {
+                // SAFETY: `setpriority` has no memory safety requirements.
+                if unsafe { libc::setpriority(libc::PRIO_PROCESS, tid as libc::id_t, nice) } != 0 {
+                    let e = io::Error::last_os_error();
+                    // the thread or process has exited in the meantime
+                    if e.raw_os_error() != Some(libc::ESRCH) {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (pid, nice);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "setting the niceness is only supported on Unix",
+        ))
+    }
+}
+
+/// Treat the failure to signal a process that no longer exists as a success.
+#[cfg(unix)]
+fn ignore_gone(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.raw_os_error() == Some(libc::ESRCH) => Ok(()),
+        result => result,
+    }
+}
+
+/// Set the niceness of the calling thread only, and thus of the threads and processes it starts later.
+pub(crate) fn set_thread_niceness(nice: i32) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        // on Linux, `setpriority` with 0 only affects the calling thread
+        // SAFETY: `setpriority` has no memory safety requirements.
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = nice;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "setting the niceness is only supported on Unix",
+        ))
+    }
+}
+
+enum Launch {
+    Spawn(Command, Sender<io::Result<Child>>),
+    #[cfg(unix)]
+    Exec(Command, Sender<io::Error>),
+}
+
+/// Starts the commands, optionally from a thread of its own that keeps the niceness it is created with.
+///
+/// With --self-nice, gpu-waiter lowers its priority after creating one, so that the commands do not inherit it:
+/// an unprivileged process cannot raise its priority back, e.g. in `pre_exec`.
+pub(crate) struct Launcher {
+    thread: Option<Sender<Launch>>,
+}
+
+impl Launcher {
+    /// Start the commands from the calling thread.
+    pub fn direct() -> Self {
+        Self { thread: None }
+    }
+
+    /// Start the commands from a new thread, which keeps the current niceness of the calling thread.
+    pub fn start() -> Self {
+        let (s, r) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            for launch in r {
+                match launch {
+                    Launch::Spawn(mut cmd, result) => {
+                        let _ = result.send(cmd.spawn());
+                    }
+                    #[cfg(unix)]
+                    Launch::Exec(mut cmd, result) => {
+                        use std::os::unix::process::CommandExt;
+                        let _ = result.send(cmd.exec());
+                    }
+                }
+            }
+        });
+        Self { thread: Some(s) }
+    }
+
+    pub fn spawn(&self, mut cmd: Command) -> io::Result<Child> {
+        let Some(thread) = &self.thread else {
+            return cmd.spawn();
+        };
+        let (s, r) = crossbeam_channel::bounded(1);
+        thread
+            .send(Launch::Spawn(cmd, s))
+            .map_err(|_| io::Error::other("the launcher thread is gone"))?;
+        r.recv()
+            .map_err(|_| io::Error::other("the launcher thread is gone"))?
+    }
+
+    /// Replace gpu-waiter with the command, returning only on failure.
+    #[cfg(unix)]
+    pub fn exec(&self, mut cmd: Command) -> io::Error {
+        use std::os::unix::process::CommandExt;
+        let Some(thread) = &self.thread else {
+            return cmd.exec();
+        };
+        let (s, r) = crossbeam_channel::bounded(1);
+        if thread.send(Launch::Exec(cmd, s)).is_err() {
+            return io::Error::other("the launcher thread is gone");
+        }
+        r.recv()
+            .unwrap_or_else(|_| io::Error::other("the launcher thread is gone"))
+    }
+}
+
+#[cfg(unix)]
+fn send_signal(pid: u32, signal: libc::c_int) -> io::Result<()> {
+    // SAFETY: `kill` has no memory safety requirements.
+    if unsafe { libc::kill(pid as libc::pid_t, signal) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn taskkill(pid: u32, force: bool) -> io::Result<()> {
+    let mut cmd = std::process::Command::new("taskkill");
+    cmd.args(["/PID", &pid.to_string()]);
+    if force {
+        cmd.arg("/F");
+    }
+    let status = cmd.status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("taskkill exited with {}", status)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    /// The niceness of a `nice` command started by the launcher.
+    #[cfg(unix)]
+    fn niceness_of_command(launcher: &Launcher) -> i32 {
+        let mut cmd = Command::new("nice");
+        cmd.stdout(std::process::Stdio::piped());
+        let output = launcher.spawn(cmd).unwrap().wait_with_output().unwrap();
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn launcher_keeps_the_original_niceness() {
+        // SAFETY: `getpriority` has no memory safety requirements.
+        let original = unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+        let launcher = Launcher::start();
+        set_thread_niceness((original + 5).min(19)).unwrap();
+        assert_eq!(niceness_of_command(&launcher), original);
+        assert_eq!(
+            niceness_of_command(&Launcher::direct()),
+            (original + 5).min(19)
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn renices_the_calling_thread_only() {
+        // SAFETY: `getpriority` has no memory safety requirements.
+        let niceness = || unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+        let original = niceness();
+        let reniced = thread::spawn(move || {
+            set_thread_niceness((original + 3).min(19)).unwrap();
+            niceness()
+        })
+        .join()
+        .unwrap();
+        assert_eq!(reniced, (original + 3).min(19));
+        assert_eq!(niceness(), original);
+    }
+
+    #[test]
+    fn parses_env() {
+        assert_eq!(
+            parse_env("A=1").unwrap(),
+            ("A".to_string(), "1".to_string())
+        );
+        assert_eq!(
+            parse_env("A=b=c").unwrap(),
+            ("A".to_string(), "b=c".to_string())
+        );
+        assert_eq!(parse_env("A=").unwrap(), ("A".to_string(), String::new()));
+        assert!(parse_env("A").is_err());
+        assert!(parse_env("=1").is_err());
+    }
+
+    #[test]
+    fn torch_env_points_to_localhost() {
+        let env: HashMap<_, _> = torch_env(4).unwrap().into_iter().collect();
+        assert_eq!(env["MASTER_ADDR"], "127.0.0.1");
+        assert_eq!(env["WORLD_SIZE"], "4");
+        assert!(env["MASTER_PORT"].parse::<u16>().unwrap() > 0);
+    }
+
+    #[test]
+    fn pidfile_is_removed_when_dropped() {
+        let path = std::env::temp_dir().join(format!("gpu-waiter-test-{}.pid", std::process::id()));
+        let pidfile = PidFile::create(&path, 1234).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1234\n");
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn foreign_processes_exclude_our_own() {
+        let own = HashSet::from([10, 11, 12]);
+        assert_eq!(not_in([10, 12], &own), [] as [u32; 0]);
+        assert_eq!(not_in([10, 42, 12, 43], &own), [42, 43]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn terminates_a_process() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        terminate(child.id()).unwrap();
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGTERM));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn renices_the_whole_process_tree() {
+        let mut child = Command::new("sh")
+            .args(["-c", "sleep 10 & wait"])
+            .spawn()
+            .unwrap();
+        // wait for `sleep` to be spawned
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let grandchild = loop {
+            if let Some(&pid) = descendants(child.id()).first() {
+                break pid;
+            }
+            assert!(Instant::now() < deadline, "sleep was not spawned");
+            thread::sleep(Duration::from_millis(10));
+        };
+        set_niceness(child.id(), 19).unwrap();
+        // SAFETY: `getpriority` has no memory safety requirements.
+        let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, grandchild) };
+        // SAFETY: `kill` has no memory safety requirements.
+        unsafe { libc::kill(grandchild as libc::pid_t, libc::SIGKILL) };
+        child.wait().unwrap();
+        assert_eq!(nice, 19);
+    }
+
+    #[cfg(all(feature = "cuda", target_os = "linux"))]
+    #[test]
+    fn counts_the_memory_of_other_own_processes() {
+        let process = |pid, used_gpu_memory| ProcessInfo {
+            pid,
+            used_gpu_memory,
+            gpu_instance_id: None,
+            compute_instance_id: None,
+        };
+        let mut exited = Command::new("true").spawn().unwrap();
+        exited.wait().unwrap();
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let processes = [
+            process(child.id(), UsedGpuMemory::Used(1 << 30)),
+            process(child.id(), UsedGpuMemory::Unavailable),
+            // gpu-waiter itself, i.e. the occupation
+            process(std::process::id(), UsedGpuMemory::Used(1 << 20)),
+            // exited, so the owner is unknown
+            process(exited.id(), UsedGpuMemory::Used(1 << 20)),
+        ];
+        let uid = current_uid();
+        assert_eq!(memory_of_user(&processes, uid), 1 << 30);
+        assert_eq!(memory_of_user(&processes, uid.wrapping_add(1)), 0);
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn kills_a_process_ignoring_terminate() {
+        use std::{
+            io::{BufRead, BufReader},
+            os::unix::process::ExitStatusExt,
+            process::Stdio,
+            thread,
+            time::Duration,
+        };
+
+        // like a command that does not exit within the grace period of --max-runtime
+        let mut child = Command::new("sh")
+            .args(["-c", "trap '' TERM; echo ready; exec sleep 10"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut line = String::new();
+        BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        terminate(child.id()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(child.try_wait().unwrap().is_none());
+        kill(child.id()).unwrap();
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGKILL));
+    }
+}
@@ -0,0 +1,306 @@
+use std::{
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    path::Path,
+    sync::atomic::Ordering,
+    time::Duration,
+};
+
+use anyhow::Context;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::STOPPED;
+
+/// How long to wait for the allocation server to accept a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for each read from or write to the allocation server, so that a hung server does not hang us.
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A centralized allocation service, which assigns the GPUs instead of the local NVML discovery.
+///
+/// Only plain `http://` is supported.
+pub(crate) struct Server {
+    /// `host:port`
+    address: String,
+    host: String,
+    /// The path prefix of the API, without the trailing slash
+    base: String,
+}
+
+#[derive(Serialize)]
+struct AllocateRequest<'a> {
+    num: u32,
+    user: &'a str,
+    priority: i32,
+}
+
+#[derive(Deserialize)]
+struct AllocateResponse {
+    gpus: Vec<u32>,
+    token: String,
+}
+
+#[derive(Serialize)]
+struct ReleaseRequest<'a> {
+    token: &'a str,
+}
+
+impl Server {
+    pub fn new(url: &str) -> anyhow::Result<Self> {
+        let Some(rest) = url.strip_prefix("http://") else {
+            anyhow::bail!(
+                "Only http:// URLs are supported for the allocation server, got {}",
+                url
+            );
+        };
+        let (host, base) = match rest.find('/') {
+            Some(i) => (&rest[..i], rest[i..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        if host.is_empty() {
+            anyhow::bail!("No host in the allocation server URL {}", url);
+        }
+        let address = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:80", host)
+        };
+        Ok(Self {
+            address,
+            host: host.to_string(),
+            base: base.to_string(),
+        })
+    }
+
+    /// Connect to the first address of the server that accepts within [`CONNECT_TIMEOUT`].
+    fn connect(&self) -> anyhow::Result<TcpStream> {
+        let mut last_err = None;
+        for addr in self.address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(match last_err {
+            Some(e) => anyhow::Error::new(e),
+            None => anyhow::anyhow!("No address found"),
+        })
+    }
+
+    /// POST a JSON body to `path` and return the response body.
+    fn post(&self, path: &str, body: &impl Serialize) -> anyhow::Result<Vec<u8>> {
+        let body = serde_json::to_vec(body)?;
+        let mut stream = self.connect().with_context(|| {
+            format!(
+                "Failed to connect to the allocation server {}",
+                self.address
+            )
+        })?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        // HTTP/1.0, so that the response is neither chunked nor kept alive
+        write!(
+            stream,
+            "POST {}{} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+            self.base,
+            path,
+            self.host,
+            body.len()
+        )?;
+        stream.write_all(&body)?;
+        // the body may not be valid UTF-8, e.g. from a misbehaving proxy, which is reported by the caller
+        let mut response = vec![];
+        stream
+            .read_to_end(&mut response)
+            .context("Failed to read the response of the allocation server")?;
+
+        let split = response
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .context("Malformed response from the allocation server")?;
+        let head = String::from_utf8_lossy(&response[..split]);
+        let body = &response[split + 4..];
+        let status = head
+            .lines()
+            .next()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|code| code.parse::<u16>().ok())
+            .context("Malformed status line from the allocation server")?;
+        if !(200..300).contains(&status) {
+            anyhow::bail!(
+                "The allocation server responded {} to {}: {}",
+                status,
+                path,
+                String::from_utf8_lossy(body).trim()
+            );
+        }
+        Ok(body.to_vec())
+    }
+
+    /// Ask the server for `num` GPUs. They are released when the returned [`Allocation`] is dropped.
+    pub fn allocate(&self, num: u32, priority: i32) -> anyhow::Result<Allocation<'_>> {
+        let user = std::env::var("USER")
+            .or_else(|_| std::env::var("USERNAME"))
+            .unwrap_or_default();
+        let body = self.post(
+            "/api/allocate",
+            &AllocateRequest {
+                num,
+                user: &user,
+                priority,
+            },
+        )?;
+        let response: AllocateResponse = serde_json::from_slice(&body).with_context(|| {
+            format!(
+                "Malformed allocation from the server: {}",
+                String::from_utf8_lossy(&body).trim()
+            )
+        })?;
+        let allocation = Allocation {
+            server: self,
+            token: response.token,
+            gpus: response.gpus,
+        };
+        if allocation.gpus.len() < num as usize {
+            anyhow::bail!(
+                "Requested {} GPUs, but the allocation server assigned {:?}",
+                num,
+                allocation.gpus
+            );
+        }
+        info!("The allocation server assigned GPUs {:?}", allocation.gpus);
+        Ok(allocation)
+    }
+}
+
+/// GPUs assigned by a [`Server`], released when dropped.
+pub(crate) struct Allocation<'a> {
+    server: &'a Server,
+    token: String,
+    pub gpus: Vec<u32>,
+}
+
+impl Drop for Allocation<'_> {
+    fn drop(&mut self) {
+        match self
+            .server
+            .post("/api/release", &ReleaseRequest { token: &self.token })
+        {
+            Ok(_) => info!("Released GPUs {:?} to the allocation server", self.gpus),
+            Err(e) => warn!(
+                "Failed to release GPUs {:?} to the allocation server: {}",
+                self.gpus, e
+            ),
+        }
+    }
+}
+
+/// Connect to the Unix socket of an external coordinator and block until it sends a `go` line.
+///
+/// Returns `false` if interrupted by Ctrl+C first.
+#[cfg(unix)]
+pub(crate) fn wait_for_go(path: &Path) -> anyhow::Result<bool> {
+    use std::os::unix::net::UnixStream;
+
+    let stream = UnixStream::connect(path)
+        .with_context(|| format!("Failed to connect to the coordinator socket {:?}", path))?;
+    // wake up regularly to check for Ctrl+C
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    while !STOPPED.load(Ordering::Relaxed) {
+        match reader.read_line(&mut line) {
+            Ok(0) => anyhow::bail!(
+                "The coordinator closed the socket {:?} without sending go",
+                path
+            ),
+            Ok(_) if line.ends_with('\n') => {
+                if line.trim() == "go" {
+                    info!("Received go from the coordinator");
+                    return Ok(true);
+                }
+                warn!(
+                    "Ignoring unexpected message from the coordinator: {:?}",
+                    line.trim()
+                );
+                line.clear();
+            }
+            // a partial line; keep reading the rest
+            Ok(_) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                ) => {}
+            Err(e) => return Err(e).context("Failed to read from the coordinator socket"),
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn wait_for_go(_path: &Path) -> anyhow::Result<bool> {
+    anyhow::bail!("--wait-for-ipc is only supported on Unix")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        net::{Shutdown, TcpListener},
+        thread,
+    };
+
+    use super::*;
+
+    /// Serve a single request with the given raw response, returning the URL of the server.
+    fn serve_once(response: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/gpus/", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![0; 4096];
+            let _ = stream.read(&mut request);
+            stream.write_all(response).unwrap();
+            // read the rest of the request until the client closes, since closing with unread data resets the
+            // connection before the client reads the response
+            stream.shutdown(Shutdown::Write).unwrap();
+            let _ = io::copy(&mut stream, &mut io::sink());
+        });
+        url
+    }
+
+    #[test]
+    fn parse_url() {
+        let server = Server::new("http://example.com/api/").unwrap();
+        assert_eq!(server.address, "example.com:80");
+        assert_eq!(server.host, "example.com");
+        assert_eq!(server.base, "/api");
+        let server = Server::new("http://10.0.0.1:8080").unwrap();
+        assert_eq!(server.address, "10.0.0.1:8080");
+        assert_eq!(server.base, "");
+        assert!(Server::new("https://example.com").is_err());
+        assert!(Server::new("http:///api").is_err());
+    }
+
+    #[test]
+    fn allocate() {
+        let url = serve_once(b"HTTP/1.0 200 OK\r\n\r\n{\"gpus\":[1,3],\"token\":\"t\"}");
+        let server = Server::new(&url).unwrap();
+        let allocation = server.allocate(2, 0).unwrap();
+        assert_eq!(allocation.gpus, [1, 3]);
+        // there is no server left to release to, which is only logged
+        drop(allocation);
+    }
+
+    #[test]
+    fn error_with_binary_body() {
+        let url = serve_once(b"HTTP/1.0 503 Unavailable\r\n\r\nbusy \xff\xfe");
+        let server = Server::new(&url).unwrap();
+        let err = server.post("/api/allocate", &()).unwrap_err().to_string();
+        assert!(err.contains("503"), "{}", err);
+        assert!(err.contains("busy"), "{}", err);
+    }
+}
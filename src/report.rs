@@ -0,0 +1,206 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use clap::ValueEnum;
+use itertools::Itertools;
+use nvml_wrapper::{enum_wrappers::device::TemperatureSensor, error::NvmlError};
+use serde::Serialize;
+
+use crate::NVML;
+
+/// Whether a single GPU was found idle in a poll.
+#[derive(Serialize)]
+pub struct DeviceState {
+    pub index: u32,
+    pub idle: bool,
+}
+
+/// The result of a single poll, as emitted by `--json-poll-stream`.
+#[derive(Serialize)]
+pub struct PollRecord {
+    /// RFC 3339 timestamp of the poll.
+    pub timestamp: String,
+    pub available: usize,
+    pub requested: usize,
+    pub devices: Vec<DeviceState>,
+    /// The seed of the random decisions, as given by `--seed` or generated.
+    pub seed: u64,
+}
+
+impl PollRecord {
+    pub fn new(device_count: u32, idle: &[u32], requested: usize, seed: u64) -> Self {
+        Self {
+            timestamp: chrono::Local::now().to_rfc3339(),
+            available: idle.len(),
+            requested,
+            seed,
+            devices: (0..device_count)
+                .map(|index| DeviceState {
+                    index,
+                    idle: idle.contains(&index),
+                })
+                .collect(),
+        }
+    }
+
+    /// Write the record to stdout as a single line of JSON.
+    pub fn emit(&self) -> io::Result<()> {
+        let mut stdout = io::stdout().lock();
+        serde_json::to_writer(&mut stdout, self)?;
+        writeln!(stdout)?;
+        stdout.flush()
+    }
+}
+
+/// Memory sizes in GiB, rounded to one decimal place.
+fn gib(bytes: u64) -> f64 {
+    (bytes as f64 / (1u64 << 30) as f64 * 10.0).round() / 10.0
+}
+
+/// A snapshot of a GPU, as written by `--output-gpu-info-json`.
+#[derive(Serialize)]
+pub struct GpuInfo {
+    pub index: u32,
+    pub name: String,
+    pub free_mem_gb: f64,
+    pub total_mem_gb: f64,
+    pub temp_c: u32,
+    pub uuid: String,
+}
+
+impl GpuInfo {
+    pub fn query(index: u32) -> Result<Self, NvmlError> {
+        let device = NVML.wait().device_by_index(index)?;
+        let memory = device.memory_info()?;
+        Ok(Self {
+            index,
+            name: device.name()?,
+            free_mem_gb: gib(memory.free),
+            total_mem_gb: gib(memory.total),
+            temp_c: device.temperature(TemperatureSensor::Gpu)?,
+            uuid: device.uuid()?,
+        })
+    }
+}
+
+/// The GPUs selected in a run, as written by `--output-gpu-info-json`.
+#[derive(Serialize)]
+pub struct SelectionRecord {
+    pub selected_gpus: Vec<GpuInfo>,
+    /// RFC 3339 timestamp of the selection.
+    pub selection_time: String,
+    /// The NVIDIA driver version, if known.
+    pub driver_version: Option<String>,
+    /// The CUDA driver API version, e.g. "12.4", if known.
+    pub cuda_version: Option<String>,
+    /// The seed of the random decisions, as given by `--seed` or generated.
+    pub seed: u64,
+}
+
+impl SelectionRecord {
+    /// Write the record to `path` as pretty-printed JSON, replacing it atomically so that readers never see a partial file.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut file = io::BufWriter::new(fs::File::create(&tmp)?);
+        serde_json::to_writer_pretty(&mut file, self)?;
+        writeln!(file)?;
+        file.into_inner()?.sync_all()?;
+        fs::rename(&tmp, path)
+    }
+}
+
+/// The syntax of the files written by `--output-{bash,fish,csh}-export`.
+#[derive(Clone, Copy)]
+pub enum Shell {
+    Bash,
+    Fish,
+    Csh,
+}
+
+impl Shell {
+    fn export(self, name: &str, value: &str) -> String {
+        match self {
+            Shell::Bash => format!("export {}=\"{}\";", name, value),
+            Shell::Fish => format!("set -x {} \"{}\";", name, value),
+            Shell::Csh => format!("setenv {} \"{}\";", name, value),
+        }
+    }
+
+    /// Write the lines exporting the selected GPUs to `path`, to be sourced or `eval`ed by the shell.
+    ///
+    /// Besides CUDA_VISIBLE_DEVICES, GPU_COUNT holds their number, and GPU_WAITER_PID the current process, like `hold --export`.
+    pub fn write_exports(self, path: &Path, gpu_list: &str, count: usize) -> io::Result<()> {
+        let mut file = io::BufWriter::new(fs::File::create(path)?);
+        for (name, value) in [
+            ("CUDA_VISIBLE_DEVICES", gpu_list.to_string()),
+            ("GPU_COUNT", count.to_string()),
+            ("GPU_WAITER_PID", std::process::id().to_string()),
+        ] {
+            writeln!(file, "{}", self.export(name, &value))?;
+        }
+        file.flush()
+    }
+}
+
+/// How `wait-for-gpus` prints the indices of the selected GPUs.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum IndexFormat {
+    /// Comma-separated, as in CUDA_VISIBLE_DEVICES
+    Csv,
+    /// Space-separated, e.g. to loop over in a shell
+    Space,
+    /// A JSON array
+    Json,
+}
+
+impl IndexFormat {
+    pub fn format(self, indices: &[u32]) -> String {
+        match self {
+            IndexFormat::Csv => indices.iter().join(","),
+            IndexFormat::Space => indices.iter().join(" "),
+            IndexFormat::Json => serde_json::to_string(indices).expect("indices are serializable"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn poll_record() {
+        let record = PollRecord::new(3, &[0, 2], 2, 42);
+        let json = serde_json::to_value(&record).unwrap();
+        assert_eq!(json["available"], 2);
+        assert_eq!(json["requested"], 2);
+        assert_eq!(json["seed"], 42);
+        assert_eq!(
+            json["devices"],
+            serde_json::json!([
+                {"index": 0, "idle": true},
+                {"index": 1, "idle": false},
+                {"index": 2, "idle": true},
+            ])
+        );
+        assert!(chrono::DateTime::parse_from_rfc3339(&record.timestamp).is_ok());
+    }
+
+    #[test]
+    fn selection_record() {
+        let record = SelectionRecord {
+            selected_gpus: vec![],
+            selection_time: chrono::Local::now().to_rfc3339(),
+            driver_version: Some("550.54.15".to_string()),
+            cuda_version: None,
+            seed: 42,
+        };
+        let json = serde_json::to_value(&record).unwrap();
+        assert_eq!(json["driver_version"], "550.54.15");
+        assert!(json["cuda_version"].is_null());
+        assert_eq!(json["seed"], 42);
+    }
+}
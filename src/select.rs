@@ -0,0 +1,358 @@
+use std::ops::RangeInclusive;
+
+use anyhow::Context;
+use clap::ValueEnum;
+use itertools::Itertools;
+use log::warn;
+use rand::{seq::SliceRandom, Rng};
+
+use crate::NVML;
+
+/// A single item of a device selection spec.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Selector {
+    /// A device index, e.g. `1`
+    Index(u32),
+    /// An inclusive range of device indices, e.g. `0-3`
+    Range(RangeInclusive<u32>),
+    /// A GPU UUID, e.g. `GPU-5f8e...`
+    Uuid(String),
+}
+
+/// Parse a comma-separated device selection spec, in the style of `nvidia-smi -i`.
+///
+/// Indices, ranges and UUIDs can be mixed, e.g. `0,2-3,GPU-5f8e...`.
+pub(crate) fn parse_spec(spec: &str) -> anyhow::Result<Vec<Selector>> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(parse_item)
+        .collect()
+}
+
+fn parse_item(item: &str) -> anyhow::Result<Selector> {
+    // GPUs are waited for and occupied as a whole, so a MIG instance cannot be selected on its own
+    if item.starts_with("MIG-") {
+        anyhow::bail!(
+            "MIG UUIDs are not supported in device selection, select the parent GPU instead: {}",
+            item
+        );
+    }
+    if item.starts_with("GPU-") {
+        return Ok(Selector::Uuid(item.to_string()));
+    }
+    if let Some((start, end)) = item.split_once('-') {
+        let start: u32 = start
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid range start in device selection: {}", item))?;
+        let end: u32 = end
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid range end in device selection: {}", item))?;
+        if start > end {
+            anyhow::bail!("Empty range in device selection: {}", item);
+        }
+        return Ok(Selector::Range(start..=end));
+    }
+    let index = item
+        .parse()
+        .with_context(|| format!("Invalid device selection: {}", item))?;
+    Ok(Selector::Index(index))
+}
+
+/// Resolve the parsed selectors to device indices, in the given order.
+///
+/// Devices selected more than once (e.g. by overlapping ranges, or by both index and UUID) are only kept at their first occurrence.
+pub(crate) fn resolve(selectors: &[Selector]) -> anyhow::Result<Vec<u32>> {
+    let nvml = NVML.wait();
+    let device_count = nvml.device_count()?;
+    let mut result = vec![];
+    for selector in selectors {
+        match selector {
+            Selector::Index(i) => result.push(*i),
+            Selector::Range(range) => result.extend(range.clone()),
+            Selector::Uuid(uuid) => {
+                let index = nvml
+                    .device_by_uuid(uuid.as_str())
+                    .and_then(|device| device.index())
+                    .with_context(|| format!("Failed to resolve device {} to an index", uuid))?;
+                result.push(index);
+            }
+        }
+    }
+    let result = dedup(result);
+    if let Some(i) = result.iter().find(|i| **i >= device_count) {
+        anyhow::bail!(
+            "Device {} is selected, but there are only {} devices in total",
+            i,
+            device_count
+        );
+    }
+    Ok(result)
+}
+
+/// Keep each device only at its first occurrence, warning about the duplicates.
+fn dedup(devices: Vec<u32>) -> Vec<u32> {
+    let mut deduped = Vec::with_capacity(devices.len());
+    for i in devices {
+        if deduped.contains(&i) {
+            warn!(
+                "Device {} is selected more than once, ignoring the duplicate",
+                i
+            );
+        } else {
+            deduped.push(i);
+        }
+    }
+    deduped
+}
+
+/// Randomly choose `num` of the candidates and move them to the front, keeping the rest as spares.
+pub(crate) fn randomize(candidates: Vec<u32>, num: usize, rng: &mut impl Rng) -> Vec<u32> {
+    let mut result: Vec<u32> = candidates.choose_multiple(rng, num).copied().collect();
+    let spares: Vec<u32> = candidates
+        .into_iter()
+        .filter(|i| !result.contains(i))
+        .collect();
+    result.extend(spares);
+    result
+}
+
+/// Choose `num` of the candidates spaced as far apart by index as possible, and move them to the front,
+/// keeping the rest as spares.
+///
+/// It maximizes the smallest index gap between the chosen GPUs, e.g. picking 0 and 7 on an 8-GPU node.
+pub(crate) fn spread(mut candidates: Vec<u32>, num: usize) -> Vec<u32> {
+    candidates.sort_unstable();
+    if num < 2 || candidates.len() <= num {
+        return candidates;
+    }
+
+    // greedily pick GPUs at least `gap` apart
+    let pick = |gap: u32| {
+        let mut picked = vec![candidates[0]];
+        for &i in &candidates[1..] {
+            if picked.len() < num && i - picked[picked.len() - 1] >= gap {
+                picked.push(i);
+            }
+        }
+        picked
+    };
+    // binary search the largest feasible gap
+    let (mut low, mut high) = (1, candidates[candidates.len() - 1] - candidates[0]);
+    while low < high {
+        let mid = low + (high - low).div_ceil(2);
+        if pick(mid).len() >= num {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+
+    let mut result = pick(low);
+    let spares: Vec<u32> = candidates
+        .into_iter()
+        .filter(|i| !result.contains(i))
+        .collect();
+    result.extend(spares);
+    result
+}
+
+/// The order of the selected GPUs given to the command, in `CUDA_VISIBLE_DEVICES` and the template.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum IdOrder {
+    Ascending,
+    Descending,
+    /// In the order they were selected, e.g. by --select or --randomize-selection
+    AsSelected,
+}
+
+impl IdOrder {
+    pub fn sort(self, devices: &mut [u32]) {
+        match self {
+            IdOrder::Ascending => devices.sort_unstable(),
+            IdOrder::Descending => devices.sort_unstable_by(|a, b| b.cmp(a)),
+            IdOrder::AsSelected => {}
+        }
+    }
+}
+
+/// Short GPU type names, and the NVML device names of each, see [`matches_gpu_type`].
+const GPU_TYPES: &[(&str, &[&str])] = &[
+    ("P100", &["P100"]),
+    ("V100", &["V100", "V100S"]),
+    ("T4", &["T4"]),
+    ("A10", &["A10"]),
+    ("A30", &["A30"]),
+    ("A40", &["A40"]),
+    ("A100", &["A100"]),
+    ("A800", &["A800"]),
+    ("A6000", &["RTX A6000"]),
+    ("L4", &["L4"]),
+    ("L40", &["L40"]),
+    ("L40S", &["L40S"]),
+    ("H100", &["H100"]),
+    ("H800", &["H800"]),
+    ("H200", &["H200"]),
+    ("GH200", &["GH200"]),
+    ("RTX3090", &["RTX 3090"]),
+    ("RTX4090", &["RTX 4090"]),
+];
+
+/// Parse a short GPU type name, e.g. `a100`, see `--gpu-type-filter`.
+pub(crate) fn parse_gpu_type(s: &str) -> Result<&'static str, String> {
+    GPU_TYPES
+        .iter()
+        .map(|(short, _)| *short)
+        .find(|short| short.eq_ignore_ascii_case(s.trim()))
+        .ok_or_else(|| {
+            format!(
+                "unknown GPU type `{}`, expected one of {}",
+                s,
+                GPU_TYPES.iter().map(|(short, _)| short).join(", ")
+            )
+        })
+}
+
+/// Split a device name into upper-case words, e.g. `NVIDIA A100-SXM4-80GB` into `NVIDIA`, `A100`, `SXM4` and `80GB`.
+fn name_words(name: &str) -> Vec<String> {
+    name.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_ascii_uppercase)
+        .collect()
+}
+
+/// Whether an NVML device name is of one of the given short types.
+///
+/// The names vary, e.g. `NVIDIA A100-SXM4-80GB` and `NVIDIA A100 80GB PCIe`, so they are compared word by word,
+/// which also keeps `A10` from matching `A100`, or `L40` from matching `L40S`.
+pub(crate) fn matches_gpu_type(name: &str, types: &[&str]) -> bool {
+    let words = name_words(name);
+    GPU_TYPES
+        .iter()
+        .filter(|(short, _)| types.contains(short))
+        .flat_map(|(_, names)| names.iter())
+        .any(|pattern| {
+            let pattern = name_words(pattern);
+            words.windows(pattern.len()).any(|window| window == pattern)
+        })
+}
+
+/// The indices of all GPUs of the given short types.
+pub(crate) fn devices_of_types(types: &[&str]) -> anyhow::Result<Vec<u32>> {
+    let nvml = NVML.wait();
+    let mut result = vec![];
+    for i in 0..nvml.device_count()? {
+        if matches_gpu_type(&nvml.device_by_index(i)?.name()?, types) {
+            result.push(i);
+        }
+    }
+    Ok(result)
+}
+
+/// A class of GPUs by total memory, see `--gpu-vram-tier`.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum VramTier {
+    /// Any
+    Sm,
+    /// At least 8 GB
+    Md,
+    /// At least 24 GB
+    Lg,
+    /// At least 48 GB
+    Xl,
+}
+
+impl VramTier {
+    /// The minimum total memory of the tier, in bytes.
+    ///
+    /// The sizes are in GB (10^9 bytes) as marketed, since NVML reports a little less than the nominal size in GiB, e.g.
+    /// 23028 MiB for a 24 GB A10.
+    pub fn min_bytes(self) -> u64 {
+        match self {
+            VramTier::Sm => 0,
+            VramTier::Md => 8_000_000_000,
+            VramTier::Lg => 24_000_000_000,
+            VramTier::Xl => 48_000_000_000,
+        }
+    }
+}
+
+/// The indices of all GPUs with at least `min_bytes` of total memory.
+pub(crate) fn devices_with_memory(min_bytes: u64) -> anyhow::Result<Vec<u32>> {
+    let nvml = NVML.wait();
+    let mut result = vec![];
+    for i in 0..nvml.device_count()? {
+        if nvml.device_by_index(i)?.memory_info()?.total >= min_bytes {
+            result.push(i);
+        }
+    }
+    Ok(result)
+}
+
+/// Restrict the selected devices, if any, to the allowed ones. Without a selection, all the allowed ones are selected.
+pub(crate) fn restrict(devices: Option<Vec<u32>>, allowed: Vec<u32>) -> Option<Vec<u32>> {
+    Some(match devices {
+        Some(selected) => selected
+            .into_iter()
+            .filter(|i| allowed.contains(i))
+            .collect(),
+        None => allowed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_specs() {
+        assert_eq!(
+            parse_spec(" 0, 2-3 ,GPU-5f8e,,").unwrap(),
+            [
+                Selector::Index(0),
+                Selector::Range(2..=3),
+                Selector::Uuid("GPU-5f8e".to_string()),
+            ]
+        );
+        assert!(parse_spec("3-1").is_err());
+        assert!(parse_spec("1-x").is_err());
+        assert!(parse_spec("gpu0").is_err());
+    }
+
+    #[test]
+    fn id_order() {
+        let sorted = |order: IdOrder| {
+            let mut devices = [3, 1, 2];
+            order.sort(&mut devices);
+            devices
+        };
+        assert_eq!(sorted(IdOrder::Ascending), [1, 2, 3]);
+        assert_eq!(sorted(IdOrder::Descending), [3, 2, 1]);
+        assert_eq!(sorted(IdOrder::AsSelected), [3, 1, 2]);
+    }
+
+    #[test]
+    fn rejects_mig_uuids() {
+        let err = parse_spec("0,MIG-1a2b").unwrap_err().to_string();
+        assert!(err.contains("MIG-1a2b"), "{}", err);
+    }
+
+    #[test]
+    fn keeps_the_first_occurrence() {
+        assert_eq!(dedup(vec![3, 0, 1, 0, 3, 2]), [3, 0, 1, 2]);
+        assert_eq!(dedup(vec![]), [0; 0]);
+    }
+
+    #[test]
+    fn spreads_across_the_node() {
+        assert_eq!(spread((0..8).collect(), 2)[..2], [0, 7]);
+        assert_eq!(spread((0..8).collect(), 3)[..3], [0, 3, 6]);
+        // the rest are kept as spares
+        assert_eq!(spread(vec![5, 1, 2, 3], 2), [1, 5, 2, 3]);
+        // nothing to choose from
+        assert_eq!(spread(vec![3, 1], 2), [1, 3]);
+        assert_eq!(spread(vec![3, 1, 2], 1), [1, 2, 3]);
+    }
+}
@@ -0,0 +1,241 @@
+// without CUDA there is no load to run, but the select loop of main still names the report
+#![cfg_attr(not(feature = "cuda"), allow(dead_code))]
+
+use std::{
+    sync::atomic::Ordering,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::Receiver;
+
+use crate::STOPPED;
+#[cfg(feature = "cuda")]
+use crate::{occupy, NVML};
+
+/// How often the utilization is sampled during the stress.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The load achieved on a single device.
+pub struct DeviceLoad {
+    pub index: u32,
+    /// Launches of the stress kernel.
+    pub launches: u64,
+    /// GPU utilization in percent, sampled every [`SAMPLE_INTERVAL`].
+    pub utilization: Vec<u32>,
+}
+
+impl DeviceLoad {
+    pub fn mean_utilization(&self) -> f64 {
+        if self.utilization.is_empty() {
+            return 0.0;
+        }
+        self.utilization.iter().sum::<u32>() as f64 / self.utilization.len() as f64
+    }
+}
+
+pub struct SelftestReport {
+    pub duration: Duration,
+    pub devices: Vec<DeviceLoad>,
+}
+
+impl SelftestReport {
+    pub fn print(&self) {
+        println!(
+            "Stressed {} GPUs for {:?}",
+            self.devices.len(),
+            self.duration
+        );
+        for dev in &self.devices {
+            println!(
+                "GPU {}: {} kernel launches, utilization mean {:.0}%, min {}%, max {}%",
+                dev.index,
+                dev.launches,
+                dev.mean_utilization(),
+                dev.utilization.iter().min().copied().unwrap_or_default(),
+                dev.utilization.iter().max().copied().unwrap_or_default()
+            );
+        }
+    }
+}
+
+/// What the selftest puts on each GPU.
+pub(crate) trait Load: Sync {
+    /// Keep the GPU busy until `deadline` (or Ctrl+C), returning how many times the work was launched.
+    fn stress(&self, index: u32, deadline: Instant) -> anyhow::Result<u64>;
+
+    /// The current utilization of the GPU, in percent.
+    fn utilization(&self, index: u32) -> anyhow::Result<u32>;
+}
+
+/// The stress kernel, in a CUDA context of its own thread.
+#[cfg(feature = "cuda")]
+pub(crate) struct CudaLoad {
+    pub init_retries: u32,
+}
+
+#[cfg(feature = "cuda")]
+impl Load for CudaLoad {
+    fn stress(&self, index: u32, deadline: Instant) -> anyhow::Result<u64> {
+        let cuda_dev = occupy::init_cuda_device(index, self.init_retries)?;
+        occupy::stress(&cuda_dev, deadline)
+    }
+
+    fn utilization(&self, index: u32) -> anyhow::Result<u32> {
+        Ok(NVML.wait().device_by_index(index)?.utilization_rates()?.gpu)
+    }
+}
+
+/// Run [`run`] in a new thread, receiving its report from the returned channel, so that the caller keeps serving
+/// e.g. the heartbeat in the meantime.
+pub(crate) fn spawn(
+    devices: Vec<u32>,
+    duration: Duration,
+    load: impl Load + Send + 'static,
+) -> Receiver<anyhow::Result<SelftestReport>> {
+    let (s, r) = crossbeam_channel::bounded(1);
+    thread::spawn(move || {
+        let _ = s.send(run(&devices, duration, &load));
+    });
+    r
+}
+
+/// Run `load` on every given GPU for `duration` (or until Ctrl+C), sampling their utilization meanwhile.
+pub(crate) fn run(
+    devices: &[u32],
+    duration: Duration,
+    load: &impl Load,
+) -> anyhow::Result<SelftestReport> {
+    let start = Instant::now();
+    let deadline = start + duration;
+    let mut utilization = vec![vec![]; devices.len()];
+    let launches = thread::scope(|scope| {
+        let workers: Vec<_> = devices
+            .iter()
+            .map(|&i| scope.spawn(move || load.stress(i, deadline)))
+            .collect();
+        while Instant::now() < deadline && !STOPPED.load(Ordering::Relaxed) {
+            thread::sleep(SAMPLE_INTERVAL.min(deadline.saturating_duration_since(Instant::now())));
+            for (&i, samples) in devices.iter().zip(&mut utilization) {
+                samples.push(load.utilization(i)?);
+            }
+        }
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("stress thread panicked"))
+            .collect::<anyhow::Result<Vec<_>>>()
+    })?;
+    Ok(SelftestReport {
+        duration: start.elapsed(),
+        devices: devices
+            .iter()
+            .zip(launches)
+            .zip(utilization)
+            .map(|((&index, launches), utilization)| DeviceLoad {
+                index,
+                launches,
+                utilization,
+            })
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::AtomicBool, Arc};
+
+    use crossbeam_channel::{select, tick};
+
+    use super::*;
+
+    /// Counts the launches, failing if the GPUs are stressed while not held.
+    struct MockLoad {
+        held: Arc<AtomicBool>,
+    }
+
+    impl Load for MockLoad {
+        fn stress(&self, _index: u32, deadline: Instant) -> anyhow::Result<u64> {
+            let mut launches = 0;
+            while Instant::now() < deadline {
+                anyhow::ensure!(self.held.load(Ordering::Relaxed), "stressed a released GPU");
+                thread::sleep(Duration::from_millis(10));
+                launches += 1;
+            }
+            Ok(launches)
+        }
+
+        fn utilization(&self, index: u32) -> anyhow::Result<u32> {
+            Ok(50 + index)
+        }
+    }
+
+    #[test]
+    fn acquire_stress_report_release() {
+        let held = Arc::new(AtomicBool::new(true));
+        let load = MockLoad { held: held.clone() };
+        let report_r = spawn(vec![0, 1], Duration::from_millis(1500), load);
+        // the caller keeps serving e.g. the heartbeat while the stress runs
+        let heartbeat = tick(Duration::from_millis(100));
+        let mut beats = 0;
+        let report = loop {
+            select! {
+                recv(report_r) -> res => break res.unwrap().unwrap(),
+                recv(heartbeat) -> _ => beats += 1,
+            }
+        };
+        held.store(false, Ordering::Relaxed);
+
+        assert!(beats >= 5, "only {} heartbeats during the stress", beats);
+        assert!(report.duration >= Duration::from_millis(1500));
+        assert_eq!(
+            report.devices.iter().map(|d| d.index).collect::<Vec<_>>(),
+            [0, 1]
+        );
+        for dev in &report.devices {
+            assert!(dev.launches > 0);
+            // sampled every second, and once more at the deadline
+            assert_eq!(dev.utilization.len(), 2);
+            assert_eq!(dev.mean_utilization(), 50.0 + dev.index as f64);
+        }
+    }
+
+    /// Fails to stress the GPU 1, e.g. without a CUDA context.
+    struct FailingLoad;
+
+    impl Load for FailingLoad {
+        fn stress(&self, index: u32, deadline: Instant) -> anyhow::Result<u64> {
+            anyhow::ensure!(index != 1, "no CUDA context on GPU {}", index);
+            thread::sleep(deadline.saturating_duration_since(Instant::now()));
+            Ok(1)
+        }
+
+        fn utilization(&self, _index: u32) -> anyhow::Result<u32> {
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn a_failing_gpu_fails_the_selftest() {
+        let err = run(&[0, 1], Duration::from_millis(100), &FailingLoad)
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "no CUDA context on GPU 1");
+        assert_eq!(
+            run(&[0, 2], Duration::from_millis(100), &FailingLoad)
+                .unwrap()
+                .devices
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn mean_utilization_without_samples() {
+        let load = DeviceLoad {
+            index: 0,
+            launches: 0,
+            utilization: vec![],
+        };
+        assert_eq!(load.mean_utilization(), 0.0);
+    }
+}